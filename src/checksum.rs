@@ -1,100 +1,462 @@
-use bevy::prelude::*;
-use bevy_ggrs::Rollback;
+//! A hash over the whole rollback state, which GGRS compares between peers (and between
+//! resimulations in a sync test) to detect desyncs.
 
-use crate::{
-    physics::{components::Vel, prelude::Pos},
-    round::prelude::{Attacker, Cake, Crosshair, Splat},
+use std::hash::{Hash, Hasher};
+
+use bevy::{
+    ecs::query::QueryState,
+    prelude::*,
+    reflect::{FromType, GetTypeRegistration, ReflectRef, TypeRegistry},
 };
+use bevy_ggrs::{GGRSPlugin, Rollback};
+use ggrs::Config;
+
+use crate::physics::RollbackRegistry;
 
+/// The hash of every rollback type, updated once per frame by [`checksum_world`]
 #[derive(Default, Reflect, Hash, Component)]
-#[reflect(Hash)]
+#[reflect(Hash, SkipChecksum)]
 pub struct Checksum {
-    value: u16,
+    pub value: u64,
 }
 
-pub fn checksum_attackers(
-    mut query: Query<(&Transform, &Vel, &Pos, &mut Checksum), (With<Attacker>, With<Rollback>)>,
-) {
-    for (t, v, p, mut checksum) in query.iter_mut() {
-        let translation = t.translation;
-        let mut bytes = Vec::with_capacity(28);
-        bytes.extend_from_slice(&translation.x.to_le_bytes());
-        bytes.extend_from_slice(&translation.y.to_le_bytes());
-        bytes.extend_from_slice(&translation.z.to_le_bytes()); // this z will probably never matter, but removing it probably also will not matter...
+/// Leaves a rollback type out of the [`Checksum`], e.g. with `#[reflect(Component, SkipChecksum)]`.
+/// Use it for state that is rolled back but allowed to differ between peers.
+#[derive(Clone)]
+pub struct ReflectSkipChecksum;
 
-        // TODO: checksum more physics types here
-        bytes.extend_from_slice(&v.0.x.to_le_bytes());
-        bytes.extend_from_slice(&v.0.y.to_le_bytes());
+impl<T> FromType<T> for ReflectSkipChecksum {
+    fn from_type() -> Self {
+        Self
+    }
+}
 
-        bytes.extend_from_slice(&p.0.x.to_le_bytes());
-        bytes.extend_from_slice(&p.0.y.to_le_bytes());
+/// Hashes a type that is opaque to reflection, like an enum, with its `Hash` impl,
+/// e.g. with `#[reflect(Component, Checksum)]`.
+/// Unlike `#[reflect(Hash)]`, this gives the same hash on every platform.
+#[derive(Clone)]
+pub struct ReflectChecksum {
+    hash: fn(&dyn Reflect, &mut Fnv64),
+}
 
-        // naive checksum implementation
-        checksum.value = fletcher16(&bytes);
+impl<T: Reflect + Hash> FromType<T> for ReflectChecksum {
+    fn from_type() -> Self {
+        Self {
+            hash: |value, hasher| value.downcast_ref::<T>().unwrap().hash(hasher),
+        }
     }
 }
 
-pub fn checksum_cakes(
-    mut query: Query<(&Transform, &Vel, &Pos, &mut Checksum), (With<Cake>, With<Rollback>)>,
-) {
-    for (t, v, p, mut checksum) in query.iter_mut() {
-        let translation = t.translation;
-        let mut bytes = Vec::with_capacity(28);
-        bytes.extend_from_slice(&translation.x.to_le_bytes());
-        bytes.extend_from_slice(&translation.y.to_le_bytes());
-        bytes.extend_from_slice(&translation.z.to_le_bytes()); // this z will probably never matter, but removing it probably also will not matter...
+/// Registers types for rollback with GGRS, and remembers them so the [`Checksum`] covers them too
+pub struct RollbackTypes<C: Config> {
+    plugin: GGRSPlugin<C>,
+    types: Vec<ChecksumType>,
+    registry: TypeRegistry,
+}
+
+impl<C: Config> RollbackTypes<C> {
+    pub fn new(plugin: GGRSPlugin<C>) -> Self {
+        Self {
+            plugin,
+            types: Vec::new(),
+            registry: TypeRegistry::default(),
+        }
+    }
+
+    pub fn register_rollback_type<T>(mut self) -> Self
+    where
+        T: GetTypeRegistration + Reflect + Default + Component,
+    {
+        self.plugin = self.plugin.register_rollback_type::<T>();
+        if T::get_type_registration()
+            .data::<ReflectSkipChecksum>()
+            .is_none()
+        {
+            self.registry.register::<T>();
+            self.types.push(ChecksumType {
+                name: std::any::type_name::<T>(),
+                component: |world, entity| world.get::<T>(entity).map(|c| c as &dyn Reflect),
+                resource: |world| world.get_resource::<T>().map(|r| r as &dyn Reflect),
+            });
+        }
+        self
+    }
+
+    /// Registers a type that only shows up inside rollback types, so its type data is found
+    pub fn register_type<T: GetTypeRegistration>(mut self) -> Self {
+        self.registry.register::<T>();
+        self
+    }
+
+    pub fn build(self, app: &mut App) {
+        app.init_resource::<Checksum>()
+            .insert_resource(ChecksumTypes {
+                types: self.types,
+                registry: self.registry,
+                rollback_entities: None,
+            });
+        self.plugin.build(app);
+    }
+}
+
+impl<C: Config> RollbackRegistry for RollbackTypes<C> {
+    fn register_rollback_type<T>(self) -> Self
+    where
+        T: GetTypeRegistration + Reflect + Default + Component,
+    {
+        RollbackTypes::register_rollback_type::<T>(self)
+    }
+
+    fn register_type<T: GetTypeRegistration>(self) -> Self {
+        RollbackTypes::register_type::<T>(self)
+    }
+}
+
+/// A rollback type can be both a component and a resource, so we look for both
+pub(crate) struct ChecksumType {
+    pub(crate) name: &'static str,
+    pub(crate) component: fn(&World, Entity) -> Option<&dyn Reflect>,
+    pub(crate) resource: fn(&World) -> Option<&dyn Reflect>,
+}
+
+/// The rollback types the checksum covers, also used by the [desync diagnostics](crate::desync)
+pub(crate) struct ChecksumTypes {
+    pub(crate) types: Vec<ChecksumType>,
+    pub(crate) registry: TypeRegistry,
+    pub(crate) rollback_entities: Option<QueryState<(Entity, &'static Rollback)>>,
+}
+
+/// Hashes every rollback entity and resource into the [`Checksum`].
+/// Entity ids differ between peers, so entities are summed up in any order, keyed by their rollback id.
+pub fn checksum_world(world: &mut World) {
+    world.resource_scope(|world, mut checksum_types: Mut<ChecksumTypes>| {
+        let ChecksumTypes {
+            types,
+            registry,
+            rollback_entities,
+        } = &mut *checksum_types;
+        let rollback_entities = rollback_entities.get_or_insert_with(|| world.query());
+
+        let mut value = 0u64;
+        for (entity, rollback) in rollback_entities.iter(world) {
+            let mut hasher = Fnv64::default();
+            hasher.write(&(rollback.id() as u64).to_le_bytes());
+            for (index, checksum_type) in types.iter().enumerate() {
+                if let Some(component) = (checksum_type.component)(world, entity) {
+                    hasher.write(&(index as u64).to_le_bytes());
+                    hash_reflect(component, world, registry, &mut hasher);
+                }
+            }
+            value = value.wrapping_add(hasher.finish());
+        }
+
+        let mut hasher = Fnv64::default();
+        for (index, checksum_type) in types.iter().enumerate() {
+            if let Some(resource) = (checksum_type.resource)(world) {
+                hasher.write(&(index as u64).to_le_bytes());
+                hash_reflect(resource, world, registry, &mut hasher);
+            }
+        }
+        value = value.wrapping_add(hasher.finish());
+
+        world.get_resource_mut::<Checksum>().unwrap().value = value;
+    });
+}
+
+/// Walks a reflected value down to its primitives, so floats can be canonicalized on the way
+fn hash_reflect(value: &dyn Reflect, world: &World, registry: &TypeRegistry, hasher: &mut Fnv64) {
+    match value.reflect_ref() {
+        ReflectRef::Struct(value) => {
+            for field in value.iter_fields() {
+                hash_reflect(field, world, registry, hasher);
+            }
+        }
+        ReflectRef::TupleStruct(value) => {
+            for field in value.iter_fields() {
+                hash_reflect(field, world, registry, hasher);
+            }
+        }
+        ReflectRef::Tuple(value) => {
+            for field in value.iter_fields() {
+                hash_reflect(field, world, registry, hasher);
+            }
+        }
+        ReflectRef::List(list) => {
+            hasher.write(&(list.len() as u64).to_le_bytes());
+            for item in list.iter() {
+                hash_reflect(item, world, registry, hasher);
+            }
+        }
+        ReflectRef::Map(map) => {
+            // maps don't iterate in the same order everywhere, so sum up the entries instead
+            let mut entries = 0u64;
+            for (key, value) in map.iter() {
+                let mut entry = Fnv64::default();
+                hash_reflect(key, world, registry, &mut entry);
+                hash_reflect(value, world, registry, &mut entry);
+                entries = entries.wrapping_add(entry.finish());
+            }
+            hasher.write(&(map.len() as u64).to_le_bytes());
+            hasher.write(&entries.to_le_bytes());
+        }
+        ReflectRef::Value(value) => hash_value(value, world, registry, hasher),
+    }
+}
+
+macro_rules! hash_ints {
+    ($value:ident, $hasher:ident, $($int:ty),*) => {
+        $(
+            if let Some(int) = $value.downcast_ref::<$int>() {
+                // widened, so usize hashes the same on wasm
+                $hasher.write(&(*int as u64).to_le_bytes());
+                return;
+            }
+        )*
+    };
+}
+
+fn hash_value(value: &dyn Reflect, world: &World, registry: &TypeRegistry, hasher: &mut Fnv64) {
+    hash_ints!(value, hasher, u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+    if let Some(float) = value.downcast_ref::<f32>() {
+        hash_f32(*float, hasher);
+    } else if let Some(float) = value.downcast_ref::<f64>() {
+        hasher.write(&canonical_f64(*float).to_le_bytes());
+    } else if let Some(vec) = value.downcast_ref::<Vec2>() {
+        vec.to_array().iter().for_each(|x| hash_f32(*x, hasher));
+    } else if let Some(vec) = value.downcast_ref::<Vec3>() {
+        vec.to_array().iter().for_each(|x| hash_f32(*x, hasher));
+    } else if let Some(quat) = value.downcast_ref::<Quat>() {
+        quat.to_array().iter().for_each(|x| hash_f32(*x, hasher));
+    } else if let Some(boolean) = value.downcast_ref::<bool>() {
+        hasher.write(&[*boolean as u8]);
+    } else if let Some(string) = value.downcast_ref::<String>() {
+        hasher.write(string.as_bytes());
+        hasher.write(&[0xff]);
+    } else if let Some(entity) = value.downcast_ref::<Entity>() {
+        // the rollback id is the only thing peers agree on
+        match world.get::<Rollback>(*entity) {
+            Some(rollback) => hasher.write(&(rollback.id() as u64).to_le_bytes()),
+            None => hasher.write(&u64::MAX.to_le_bytes()),
+        }
+    } else if let Some(checksum) = registry.get_type_data::<ReflectChecksum>(value.any().type_id())
+    {
+        // e.g. enums, which are reflected as opaque values
+        (checksum.hash)(value, hasher);
+    } else {
+        // not `reflect_hash`, that differs between platforms
+        warn!(
+            "{} can't be hashed, add #[reflect(Checksum)] or #[reflect(SkipChecksum)]",
+            value.type_name()
+        );
+    }
+}
 
-        // TODO: checksum more physics types here
-        bytes.extend_from_slice(&v.0.x.to_le_bytes());
-        bytes.extend_from_slice(&v.0.y.to_le_bytes());
+fn hash_f32(float: f32, hasher: &mut Fnv64) {
+    hasher.write(&canonical_f32(float).to_le_bytes());
+}
 
-        bytes.extend_from_slice(&p.0.x.to_le_bytes());
-        bytes.extend_from_slice(&p.0.y.to_le_bytes());
+/// The bits of a float, with -0.0 turned into 0.0 and all NaNs into the same NaN
+fn canonical_f32(float: f32) -> u32 {
+    if float.is_nan() {
+        f32::NAN.to_bits()
+    } else if float == 0. {
+        0
+    } else {
+        float.to_bits()
+    }
+}
 
-        // naive checksum implementation
-        checksum.value = fletcher16(&bytes);
+fn canonical_f64(float: f64) -> u64 {
+    if float.is_nan() {
+        f64::NAN.to_bits()
+    } else if float == 0. {
+        0
+    } else {
+        float.to_bits()
     }
 }
 
-pub fn checksum_crosshair(
-    mut query: Query<(&Transform, &mut Checksum), (With<Crosshair>, With<Rollback>)>,
-) {
-    for (t, mut checksum) in query.iter_mut() {
-        let translation = t.translation;
-        let mut bytes = Vec::with_capacity(12);
-        bytes.extend_from_slice(&translation.x.to_le_bytes());
-        bytes.extend_from_slice(&translation.y.to_le_bytes());
-        bytes.extend_from_slice(&translation.z.to_le_bytes()); // this z will probably never matter, but removing it probably also will not matter...
+/// The 64 bit FNV-1a hash, see <https://en.wikipedia.org/wiki/Fowler%E2%80%93Noll%E2%80%93Vo_hash_function>.
+/// Unlike the std and bevy hashers, it is the same on every platform and in every version.
+/// Integers are written little endian and `usize`s widened to 64 bits, so derived `Hash` impls agree as well.
+struct Fnv64(u64);
 
-        // naive checksum implementation
-        checksum.value = fletcher16(&bytes);
+impl Default for Fnv64 {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
     }
 }
 
-pub fn checksum_splat(
-    mut query: Query<(&Transform, &mut Checksum), (With<Splat>, With<Rollback>)>,
-) {
-    for (t, mut checksum) in query.iter_mut() {
-        let translation = t.translation;
-        let mut bytes = Vec::with_capacity(12);
-        bytes.extend_from_slice(&translation.x.to_le_bytes());
-        bytes.extend_from_slice(&translation.y.to_le_bytes());
-        bytes.extend_from_slice(&translation.z.to_le_bytes()); // this z will probably never matter, but removing it probably also will not matter...
+impl Hasher for Fnv64 {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
 
-        // naive checksum implementation
-        checksum.value = fletcher16(&bytes);
+    // the signed ones forward to these by default
+    fn write_u16(&mut self, int: u16) {
+        self.write(&int.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, int: u32) {
+        self.write(&int.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, int: u64) {
+        self.write(&int.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, int: u128) {
+        self.write(&int.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, int: usize) {
+        self.write_u64(int as u64);
+    }
+
+    // sign extended, unlike the default that goes through `write_usize`
+    fn write_isize(&mut self, int: isize) {
+        self.write_u64(int as i64 as u64);
     }
 }
 
-/// Computes the fletcher16 checksum, copied from wikipedia: <https://en.wikipedia.org/wiki/Fletcher%27s_checksum>
-fn fletcher16(data: &[u8]) -> u16 {
-    let mut sum1: u16 = 0;
-    let mut sum2: u16 = 0;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GGRSConfig;
+
+    #[derive(Component, Reflect, Default)]
+    #[reflect(Component)]
+    struct Body {
+        pos: Vec2,
+        frames: usize,
+    }
 
-    for byte in data {
-        sum1 = (sum1 + *byte as u16) % 255;
-        sum2 = (sum2 + sum1) % 255;
+    #[derive(Component, Reflect, Clone, Hash)]
+    #[reflect(Component, Checksum)]
+    enum Mood {
+        Calm,
+        Hungry(usize),
     }
 
-    (sum2 << 8) | sum1
+    impl Default for Mood {
+        fn default() -> Self {
+            Self::Calm
+        }
+    }
+
+    #[derive(Component, Reflect, Default)]
+    #[reflect(Component, SkipChecksum)]
+    struct Unsynced {
+        frames: usize,
+    }
+
+    fn world() -> World {
+        let rollback_types = RollbackTypes::new(GGRSPlugin::<GGRSConfig>::new())
+            .register_rollback_type::<Body>()
+            .register_rollback_type::<Mood>()
+            .register_rollback_type::<Unsynced>();
+        let mut world = World::default();
+        world.init_resource::<Checksum>();
+        world.insert_resource(ChecksumTypes {
+            types: rollback_types.types,
+            registry: rollback_types.registry,
+            rollback_entities: None,
+        });
+        world
+    }
+
+    fn checksum(world: &mut World) -> u64 {
+        checksum_world(world);
+        world.get_resource::<Checksum>().unwrap().value
+    }
+
+    #[test]
+    fn checksum_is_pinned() {
+        let mut world = world();
+        world.spawn().insert(Rollback::new(5)).insert(Body {
+            pos: Vec2::new(1.5, -2.),
+            frames: 7,
+        });
+        // if this changes, peers on different versions can't play each other anymore
+        assert_eq!(checksum(&mut world), 0x75b821582c63b41f);
+    }
+
+    #[test]
+    fn floats_are_canonical() {
+        let mut world = world();
+        let entity = world
+            .spawn()
+            .insert(Rollback::new(0))
+            .insert(Body {
+                pos: Vec2::new(0., f32::NAN),
+                frames: 0,
+            })
+            .id();
+        let before = checksum(&mut world);
+        world.get_mut::<Body>(entity).unwrap().pos = Vec2::new(-0., f32::from_bits(0x7fc0_0001));
+        assert_eq!(checksum(&mut world), before);
+
+        world.get_mut::<Body>(entity).unwrap().pos = Vec2::new(0., 1.);
+        assert_ne!(checksum(&mut world), before);
+    }
+
+    #[test]
+    fn skip_checksum() {
+        let mut world = world();
+        let entity = world
+            .spawn()
+            .insert(Rollback::new(0))
+            .insert(Unsynced { frames: 1 })
+            .id();
+        let before = checksum(&mut world);
+        world.get_mut::<Unsynced>(entity).unwrap().frames = 2;
+        assert_eq!(checksum(&mut world), before);
+    }
+
+    #[test]
+    fn enums_are_hashed() {
+        let mut world = world();
+        let entity = world
+            .spawn()
+            .insert(Rollback::new(0))
+            .insert(Mood::Hungry(1))
+            .id();
+        let hungry = checksum(&mut world);
+        *world.get_mut::<Mood>(entity).unwrap() = Mood::Hungry(2);
+        let hungrier = checksum(&mut world);
+        *world.get_mut::<Mood>(entity).unwrap() = Mood::Calm;
+        let calm = checksum(&mut world);
+        assert_ne!(hungry, hungrier);
+        assert_ne!(hungry, calm);
+        assert_ne!(hungrier, calm);
+    }
+
+    #[test]
+    fn integers_hash_the_same_on_every_platform() {
+        let hash = |value: &dyn Fn(&mut Fnv64)| {
+            let mut hasher = Fnv64::default();
+            value(&mut hasher);
+            hasher.finish()
+        };
+        assert_eq!(
+            hash(&|hasher| 3usize.hash(hasher)),
+            hash(&|hasher| 3u64.hash(hasher))
+        );
+        assert_eq!(
+            hash(&|hasher| (-1isize).hash(hasher)),
+            hash(&|hasher| (-1i64).hash(hasher))
+        );
+        assert_eq!(
+            hash(&|hasher| 0x0102u16.hash(hasher)),
+            hash(&|hasher| hasher.write(&[2, 1]))
+        );
+    }
 }
@@ -0,0 +1,583 @@
+//! Desync diagnostics: remembers the rollback state of the last frames, and when a frame is
+//! simulated again with a different checksum, dumps both versions and logs every field that differs.
+//! Enabled with the `desync-diagnostics` feature.
+//!
+//! In P2P sessions, [`PeerChecksums`] compares the checksums of confirmed frames between peers,
+//! which GGRS doesn't do on its own. Before that, it makes sure the peers use the same physics settings.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::{Debug, Write},
+};
+
+use bevy::{
+    prelude::*,
+    reflect::{FromType, ReflectRef, TypeRegistry},
+};
+use bevy_ggrs::Rollback;
+use ggrs::P2PSession;
+use matchbox_socket::WebRtcSocket;
+
+use crate::{
+    checksum::{Checksum, ChecksumTypes},
+    physics::prelude::PhysicsConfig,
+    round::prelude::{ConnectionInfo, ConnectionStatus},
+    GGRSConfig, FPS,
+};
+
+/// Long enough for the checksum of a confirmed frame to arrive from the other peer
+const HISTORY_LENGTH: usize = 2 * FPS;
+
+/// Counts the frames simulated since the round started. Unlike `FrameCount` it never resets,
+/// and it is rolled back, so a resimulated frame gets the same number as the first time around.
+#[derive(Default, Reflect, Hash, Component)]
+#[reflect(Hash)]
+pub struct RollbackFrame {
+    pub frame: u32,
+}
+
+pub fn advance_rollback_frame(mut frame: ResMut<RollbackFrame>) {
+    frame.frame += 1;
+}
+
+/// Lets the dumps show values that are opaque to reflection, like enums,
+/// e.g. with `#[reflect(Component, Checksum, Debug)]`
+#[derive(Clone)]
+pub struct ReflectDebug {
+    fmt: fn(&dyn Reflect) -> String,
+}
+
+impl<T: Reflect + Debug> FromType<T> for ReflectDebug {
+    fn from_type() -> Self {
+        Self {
+            fmt: |value| format!("{:?}", value.downcast_ref::<T>().unwrap()),
+        }
+    }
+}
+
+/// Records the rollback state of every frame while present, see [`record_frame_state`]
+pub struct DesyncDiagnostics {
+    history: VecDeque<FrameState>,
+    /// Only the first desync is reported, the frames after it will mismatch as well
+    reported: bool,
+    compare_resimulations: bool,
+}
+
+impl DesyncDiagnostics {
+    /// For sync tests, where every resimulated frame has to match its first simulation
+    pub fn sync_test() -> Self {
+        Self {
+            history: VecDeque::new(),
+            reported: false,
+            compare_resimulations: true,
+        }
+    }
+
+    /// For P2P sessions, where a resimulation replaces a mispredicted frame,
+    /// and [`PeerChecksums`] asks for a dump when the peers disagree
+    pub fn p2p() -> Self {
+        Self {
+            compare_resimulations: false,
+            ..Self::sync_test()
+        }
+    }
+
+    /// Whether a desync was found and reported
+    pub fn found_desync(&self) -> bool {
+        self.reported
+    }
+
+    fn record(&mut self, state: FrameState) {
+        match self
+            .history
+            .iter_mut()
+            .find(|recorded| recorded.frame == state.frame)
+        {
+            Some(recorded) if self.compare_resimulations => {
+                if recorded.checksum != state.checksum && !self.reported {
+                    self.reported = true;
+                    report_desync(recorded, &state);
+                }
+            }
+            Some(recorded) => *recorded = state,
+            None => {
+                self.history.push_back(state);
+                if self.history.len() > HISTORY_LENGTH {
+                    self.history.pop_front();
+                }
+            }
+        }
+    }
+
+    /// Writes the state of a frame another peer disagrees with
+    fn dump_frame(&mut self, frame: u32) {
+        if self.reported {
+            return;
+        }
+        self.reported = true;
+        match self.history.iter().find(|state| state.frame == frame) {
+            Some(state) => write_dump(state, "local"),
+            None => warn!("Frame {} is no longer in the desync history", frame),
+        }
+    }
+}
+
+/// Runs after the [`Checksum`] was updated. Hands the checksum to [`PeerChecksums`], captures the
+/// rollback state and compares it to the state recorded when the same frame was simulated before.
+pub fn record_frame_state(world: &mut World) {
+    let frame = world.get_resource::<RollbackFrame>().map_or(0, |f| f.frame);
+    let checksum = world.get_resource::<Checksum>().map_or(0, |c| c.value);
+    if let Some(mut peer_checksums) = world.get_resource_mut::<PeerChecksums>() {
+        peer_checksums.record(frame, checksum);
+    }
+
+    if !world.contains_resource::<DesyncDiagnostics>() {
+        return;
+    }
+
+    let state = world.resource_scope(|world, mut checksum_types: Mut<ChecksumTypes>| {
+        FrameState::capture(world, &mut checksum_types)
+    });
+    world
+        .get_resource_mut::<DesyncDiagnostics>()
+        .unwrap()
+        .record(state);
+}
+
+/// The rollback state of a frame, with type names as keys
+struct FrameState {
+    frame: u32,
+    checksum: u64,
+    resources: Vec<(&'static str, Dump)>,
+    /// Sorted by rollback id, as entity ids may differ
+    entities: Vec<(u32, Vec<(&'static str, Dump)>)>,
+}
+
+impl FrameState {
+    fn capture(world: &mut World, checksum_types: &mut ChecksumTypes) -> Self {
+        let ChecksumTypes {
+            types,
+            registry,
+            rollback_entities,
+        } = checksum_types;
+        let rollback_entities = rollback_entities.get_or_insert_with(|| world.query());
+        let (world, types, registry): (&World, &Vec<_>, &TypeRegistry) = (world, types, registry);
+
+        let resources = types
+            .iter()
+            .filter_map(|ty| Some((ty.name, Dump::new((ty.resource)(world)?, world, registry))))
+            .collect();
+        let mut entities: Vec<_> = rollback_entities
+            .iter(world)
+            .map(|(entity, rollback)| {
+                let components = types
+                    .iter()
+                    .filter_map(|ty| {
+                        let component = (ty.component)(world, entity)?;
+                        Some((ty.name, Dump::new(component, world, registry)))
+                    })
+                    .collect();
+                (rollback.id(), components)
+            })
+            .collect();
+        entities.sort_by_key(|(id, _)| *id);
+
+        Self {
+            frame: world.get_resource::<RollbackFrame>().map_or(0, |f| f.frame),
+            checksum: world.get_resource::<Checksum>().map_or(0, |c| c.value),
+            resources,
+            entities,
+        }
+    }
+
+    fn to_ron(&self) -> String {
+        let mut ron = String::new();
+        writeln!(ron, "(").unwrap();
+        writeln!(ron, "    frame: {},", self.frame).unwrap();
+        writeln!(ron, "    checksum: {},", self.checksum).unwrap();
+        writeln!(ron, "    resources: {{").unwrap();
+        for (name, dump) in &self.resources {
+            writeln!(ron, "        {:?}: {},", name, dump).unwrap();
+        }
+        writeln!(ron, "    }},").unwrap();
+        writeln!(ron, "    entities: {{").unwrap();
+        for (id, components) in &self.entities {
+            writeln!(ron, "        {}: {{", id).unwrap();
+            for (name, dump) in components {
+                writeln!(ron, "            {:?}: {},", name, dump).unwrap();
+            }
+            writeln!(ron, "        }},").unwrap();
+        }
+        writeln!(ron, "    }},").unwrap();
+        writeln!(ron, ")").unwrap();
+        ron
+    }
+
+    /// Every primitive in the state, keyed by its path
+    fn fields(&self) -> BTreeMap<String, String> {
+        let mut fields = BTreeMap::new();
+        for (name, dump) in &self.resources {
+            dump.flatten(name.to_string(), &mut fields);
+        }
+        for (id, components) in &self.entities {
+            for (name, dump) in components {
+                dump.flatten(format!("rollback {} / {}", id, name), &mut fields);
+            }
+        }
+        fields
+    }
+}
+
+fn report_desync(recorded: &FrameState, resimulated: &FrameState) {
+    error!(
+        "Desync in frame {}: checksum {} became {} when resimulated",
+        recorded.frame, recorded.checksum, resimulated.checksum
+    );
+
+    write_dump(recorded, "recorded");
+    write_dump(resimulated, "resimulated");
+
+    let before = recorded.fields();
+    let after = resimulated.fields();
+    for (path, value) in &before {
+        match after.get(path) {
+            Some(new_value) if new_value != value => {
+                error!("  {}: {} != {}", path, value, new_value)
+            }
+            None => error!("  {}: {} is gone", path, value),
+            _ => {}
+        }
+    }
+    for (path, value) in &after {
+        if !before.contains_key(path) {
+            error!("  {}: {} is new", path, value);
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn write_dump(state: &FrameState, suffix: &str) {
+    let path = format!("desync_{}_{}.ron", state.frame, suffix);
+    match std::fs::write(&path, state.to_ron()) {
+        Ok(()) => info!("Wrote {}", path),
+        Err(e) => error!("Could not write {}: {}", path, e),
+    }
+}
+
+/// There's no file system in the browser, so the dump goes to the console instead
+#[cfg(target_arch = "wasm32")]
+fn write_dump(state: &FrameState, suffix: &str) {
+    info!("desync_{}_{}.ron:\n{}", state.frame, suffix, state.to_ron());
+}
+
+/// A reflected value, copied into something we can keep around, print and compare
+enum Dump {
+    Struct(Vec<(String, Dump)>),
+    Tuple(Vec<Dump>),
+    List(Vec<Dump>),
+    Map(Vec<(Dump, Dump)>),
+    Value(String),
+}
+
+impl Dump {
+    fn new(value: &dyn Reflect, world: &World, registry: &TypeRegistry) -> Self {
+        let dump = |value: &dyn Reflect| Dump::new(value, world, registry);
+        match value.reflect_ref() {
+            ReflectRef::Struct(value) => Dump::Struct(
+                (0..value.field_len())
+                    .map(|i| {
+                        let name = value.name_at(i).unwrap_or_default().to_owned();
+                        (name, dump(value.field_at(i).unwrap()))
+                    })
+                    .collect(),
+            ),
+            ReflectRef::TupleStruct(value) => Dump::Tuple(value.iter_fields().map(dump).collect()),
+            ReflectRef::Tuple(value) => Dump::Tuple(value.iter_fields().map(dump).collect()),
+            ReflectRef::List(list) => Dump::List(list.iter().map(dump).collect()),
+            ReflectRef::Map(map) => Dump::Map(
+                map.iter()
+                    .map(|(key, value)| (dump(key), dump(value)))
+                    .collect(),
+            ),
+            ReflectRef::Value(value) => Dump::Value(value_to_string(value, world, registry)),
+        }
+    }
+
+    fn flatten(&self, path: String, out: &mut BTreeMap<String, String>) {
+        match self {
+            Dump::Struct(fields) => {
+                for (name, dump) in fields {
+                    dump.flatten(format!("{}.{}", path, name), out);
+                }
+            }
+            Dump::Tuple(items) => {
+                for (i, dump) in items.iter().enumerate() {
+                    dump.flatten(format!("{}.{}", path, i), out);
+                }
+            }
+            Dump::List(items) => {
+                for (i, dump) in items.iter().enumerate() {
+                    dump.flatten(format!("{}[{}]", path, i), out);
+                }
+            }
+            Dump::Map(entries) => {
+                for (key, dump) in entries {
+                    dump.flatten(format!("{}[{}]", path, key), out);
+                }
+            }
+            Dump::Value(value) => {
+                out.insert(path, value.clone());
+            }
+        }
+    }
+}
+
+/// Writes the dump as RON
+impl std::fmt::Display for Dump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Dump::Struct(fields) => {
+                write!(f, "(")?;
+                for (i, (name, dump)) in fields.iter().enumerate() {
+                    let separator = if i == 0 { "" } else { ", " };
+                    write!(f, "{}{}: {}", separator, name, dump)?;
+                }
+                write!(f, ")")
+            }
+            Dump::Tuple(items) => {
+                write!(f, "(")?;
+                write_items(f, items)?;
+                write!(f, ")")
+            }
+            Dump::List(items) => {
+                write!(f, "[")?;
+                write_items(f, items)?;
+                write!(f, "]")
+            }
+            Dump::Map(entries) => {
+                write!(f, "{{")?;
+                for (i, (key, dump)) in entries.iter().enumerate() {
+                    let separator = if i == 0 { "" } else { ", " };
+                    write!(f, "{}{}: {}", separator, key, dump)?;
+                }
+                write!(f, "}}")
+            }
+            Dump::Value(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+fn write_items(f: &mut std::fmt::Formatter<'_>, items: &[Dump]) -> std::fmt::Result {
+    for (i, dump) in items.iter().enumerate() {
+        let separator = if i == 0 { "" } else { ", " };
+        write!(f, "{}{}", separator, dump)?;
+    }
+    Ok(())
+}
+
+fn value_to_string(value: &dyn Reflect, world: &World, registry: &TypeRegistry) -> String {
+    if let Some(entity) = value.downcast_ref::<Entity>() {
+        // same as in the checksum, the rollback id is what peers agree on
+        return match world.get::<Rollback>(*entity) {
+            Some(rollback) => format!("Rollback({})", rollback.id()),
+            None => format!("Entity({})", entity.id()),
+        };
+    }
+
+    macro_rules! debug_values {
+        ($($ty:ty),*) => {
+            $(
+                if let Some(value) = value.downcast_ref::<$ty>() {
+                    return format!("{:?}", value);
+                }
+            )*
+        };
+    }
+    debug_values!(
+        u8, u16, u32, u64, usize, i8, i16, i32, i64, isize, f32, f64, bool, String, Vec2, Vec3,
+        Quat
+    );
+
+    match registry.get_type_data::<ReflectDebug>(value.any().type_id()) {
+        Some(debug) => (debug.fmt)(value),
+        // quoted, so the dump is still valid RON
+        None => format!(
+            "{:?}",
+            format!(
+                "{} #{:x}",
+                value.type_name(),
+                value.reflect_hash().unwrap_or(0)
+            )
+        ),
+    }
+}
+
+/// A checksum packet is followed by the frame (u32) and its checksum (u64)
+const CHECKSUM_PACKET: u8 = 0;
+/// A settings packet is followed by the sender's [`PhysicsConfig::to_bytes`], and asks for an answer
+const SETTINGS_PACKET: u8 = 1;
+/// Same as a settings packet, but doesn't ask for an answer
+const SETTINGS_ANSWER_PACKET: u8 = 2;
+
+/// Exchanges the checksums of confirmed frames with the other peer, over a matchbox socket of its own
+/// so GGRS doesn't have to know about it.
+/// Before the match starts, the peers compare their physics settings over it as well.
+pub struct PeerChecksums {
+    socket: WebRtcSocket,
+    /// The peer GGRS plays against, the only one whose checksums count
+    opponent: String,
+    /// Our [`PhysicsConfig::to_bytes`], and the opponent's once they arrived
+    settings: Vec<u8>,
+    remote_settings: Option<Vec<u8>>,
+    /// Checksums of frames that may still be resimulated
+    pending: VecDeque<(u32, u64)>,
+    /// Confirmed checksums that haven't been compared yet, by frame
+    local: BTreeMap<u32, u64>,
+    remote: BTreeMap<u32, u64>,
+    desynced: bool,
+}
+
+impl PeerChecksums {
+    pub fn new(socket: WebRtcSocket, opponent: String, settings: &PhysicsConfig) -> Self {
+        Self {
+            socket,
+            opponent,
+            settings: settings.to_bytes(),
+            remote_settings: None,
+            pending: VecDeque::new(),
+            local: BTreeMap::new(),
+            remote: BTreeMap::new(),
+            desynced: false,
+        }
+    }
+
+    /// Sends our settings until the opponent's arrive, then tells whether they're the same.
+    /// Whoever got the other's settings first keeps answering, so the other side finds out too.
+    pub fn agree_on_settings(&mut self) -> Option<bool> {
+        self.socket.accept_new_connections();
+        if self.remote_settings.is_none() {
+            self.send_settings(SETTINGS_PACKET);
+        }
+        self.receive();
+        self.remote_settings
+            .as_ref()
+            .map(|remote| *remote == self.settings)
+    }
+
+    fn record(&mut self, frame: u32, checksum: u64) {
+        // a resimulation starts over from this frame, so the later ones will be recorded again too
+        while matches!(self.pending.back(), Some((f, _)) if *f >= frame) {
+            self.pending.pop_back();
+        }
+        self.pending.push_back((frame, checksum));
+        if self.pending.len() > HISTORY_LENGTH {
+            self.pending.pop_front();
+        }
+    }
+
+    fn send(&mut self, packet: Vec<u8>) {
+        // lost if the side channel isn't connected yet, like any other packet could be
+        if self.socket.connected_peers().contains(&self.opponent) {
+            self.socket
+                .send(packet.into_boxed_slice(), self.opponent.clone());
+        }
+    }
+
+    fn send_settings(&mut self, kind: u8) {
+        let mut packet = vec![kind];
+        packet.extend_from_slice(&self.settings);
+        self.send(packet);
+    }
+
+    fn receive(&mut self) {
+        for (peer, packet) in self.socket.receive() {
+            if peer != self.opponent {
+                warn!(
+                    "Ignoring a checksum packet from {}, who isn't in this match",
+                    peer
+                );
+                continue;
+            }
+            match (packet.first().copied(), packet.len()) {
+                (Some(CHECKSUM_PACKET), 13) => {
+                    let frame = u32::from_le_bytes(packet[1..5].try_into().unwrap());
+                    let checksum = u64::from_le_bytes(packet[5..].try_into().unwrap());
+                    self.remote.insert(frame, checksum);
+                }
+                (Some(kind @ (SETTINGS_PACKET | SETTINGS_ANSWER_PACKET)), _) => {
+                    if kind == SETTINGS_PACKET {
+                        self.send_settings(SETTINGS_ANSWER_PACKET);
+                    }
+                    self.remote_settings = Some(packet[1..].to_vec());
+                }
+                _ => warn!("Ignoring a checksum packet of {} bytes", packet.len()),
+            }
+        }
+    }
+}
+
+/// Sends the checksums that can't change anymore, and compares them to what the other peer sent
+pub fn exchange_checksums(
+    session: Res<P2PSession<GGRSConfig>>,
+    mut peer_checksums: ResMut<PeerChecksums>,
+    mut con_info: ResMut<ConnectionInfo>,
+    diagnostics: Option<ResMut<DesyncDiagnostics>>,
+) {
+    let peer_checksums = &mut *peer_checksums;
+    peer_checksums.socket.accept_new_connections();
+
+    // frame n is the state after simulating frame n - 1, which is final once its inputs are confirmed
+    let confirmed_frame = session.confirmed_frame();
+    while let Some(&(frame, checksum)) = peer_checksums.pending.front() {
+        if frame as i64 > confirmed_frame as i64 + 1 {
+            break;
+        }
+        peer_checksums.pending.pop_front();
+        let mut packet = vec![CHECKSUM_PACKET];
+        packet.extend_from_slice(&frame.to_le_bytes());
+        packet.extend_from_slice(&checksum.to_le_bytes());
+        peer_checksums.send(packet);
+        peer_checksums.local.insert(frame, checksum);
+    }
+
+    // also answers the settings of an opponent that started waiting for them before we did
+    peer_checksums.receive();
+
+    let PeerChecksums {
+        local,
+        remote,
+        desynced,
+        ..
+    } = peer_checksums;
+    let mut mismatch = None;
+    remote.retain(|frame, checksum| match local.remove(frame) {
+        Some(local_checksum) => {
+            if local_checksum != *checksum && mismatch.is_none() {
+                mismatch = Some((*frame, local_checksum, *checksum));
+            }
+            false
+        }
+        None => true,
+    });
+    // packets get lost, so don't wait forever for the other side of a checksum
+    for checksums in [local, remote] {
+        while checksums.len() > HISTORY_LENGTH {
+            let oldest = *checksums.keys().next().unwrap();
+            checksums.remove(&oldest);
+        }
+    }
+
+    if let Some((frame, local_checksum, remote_checksum)) = mismatch {
+        if !*desynced {
+            *desynced = true;
+            error!(
+                "Desync in frame {}: local checksum {}, remote checksum {}",
+                frame, local_checksum, remote_checksum
+            );
+            con_info.status = ConnectionStatus::Desynced;
+            if let Some(mut diagnostics) = diagnostics {
+                diagnostics.dump_frame(frame);
+            }
+        }
+    }
+}
@@ -1,23 +1,22 @@
 mod checksum;
+mod desync;
 mod menu;
 mod physics;
 mod round;
+mod schedule;
 
 use bevy::prelude::*;
 use bevy_asset_loader::{AssetCollection, AssetLoader};
 use bevy_ggrs::GGRSPlugin;
-use checksum::{checksum_attackers, checksum_cakes, checksum_crosshair, checksum_splat, Checksum};
+use desync::exchange_checksums;
 use ggrs::Config;
 use menu::{
     connect::{create_matchbox_socket, update_matchbox_socket},
     online::{update_lobby_btn, update_lobby_id, update_lobby_id_display},
 };
-use physics::{components::*, create_physics_stage, prelude::*};
+use physics::prelude::*;
 use round::prelude::*;
-
-const ROLLBACK_SYSTEMS: &str = "rollback_systems";
-const CHECKSUM_UPDATE: &str = "checksum_update";
-const PHYSICS_UPDATE: &str = "physics_update";
+use schedule::{rollback_schedule, rollback_types};
 
 const NUM_PLAYERS: usize = 2;
 const FPS: usize = 60;
@@ -44,14 +43,6 @@ pub enum AppState {
     Win,
 }
 
-#[derive(SystemLabel, Debug, Clone, Hash, Eq, PartialEq)]
-enum SystemLabel {
-    UpdateState,
-    Input,
-    Move,
-    End,
-}
-
 #[derive(AssetCollection)]
 pub struct MiscAssets {
     #[asset(path = "sprites/misc/title.png")]
@@ -127,126 +118,12 @@ fn main() {
         .with_collection::<DefenderAssets>()
         .build(&mut app);
 
-    GGRSPlugin::<GGRSConfig>::new()
+    let ggrs_plugin = GGRSPlugin::<GGRSConfig>::new()
         .with_update_frequency(FPS)
         .with_input_system(input)
-        .register_rollback_type::<Attacker>()
-        .register_rollback_type::<Defender>()
-        .register_rollback_type::<RoundEntity>()
-        .register_rollback_type::<AttackerState>()
-        .register_rollback_type::<DefenderState>()
-        .register_rollback_type::<AttackerControls>()
-        .register_rollback_type::<DefenderControls>()
-        .register_rollback_type::<FrameCount>()
-        .register_rollback_type::<Checksum>()
-        .register_rollback_type::<RoundState>()
-        .register_rollback_type::<RoundData>()
-        .register_rollback_type::<Transform>()
-        .register_rollback_type::<FacingDirection>()
-        .register_rollback_type::<Cake>()
-        .register_rollback_type::<Splat>()
-        .register_rollback_type::<Crosshair>()
-        .register_rollback_type::<ScreenTimer>()
-        // physics types
-        .register_rollback_type::<Pos>()
-        .register_rollback_type::<Vel>()
-        .register_rollback_type::<PrevPos>()
-        .register_rollback_type::<PreSolveVel>()
-        .register_rollback_type::<Restitution>()
-        .register_rollback_type::<BoxCollider>()
-        .register_rollback_type::<Mass>()
-        .register_rollback_type::<Aabb>()
-        .register_rollback_type::<StaticContacts>()
-        .register_rollback_type::<Contacts>()
-        .with_rollback_schedule(
-            Schedule::default()
-                // adding physics in a separate stage for now,
-                // could perhaps merge with the stage below for increased parallelism...
-                // but this is a web jam game, so we don't *really* care about that now...
-                .with_stage(PHYSICS_UPDATE, create_physics_stage())
-                .with_stage_after(
-                    PHYSICS_UPDATE,
-                    ROLLBACK_SYSTEMS,
-                    SystemStage::parallel()
-                        // interlude start
-                        .with_system_set(
-                            SystemSet::new()
-                                .with_run_criteria(on_interlude_start)
-                                .with_system(setup_interlude),
-                        )
-                        // interlude
-                        .with_system_set(
-                            SystemSet::new()
-                                .with_run_criteria(on_interlude)
-                                .with_system(run_interlude),
-                        )
-                        // interlude end
-                        .with_system_set(
-                            SystemSet::new()
-                                .with_run_criteria(on_interlude_end)
-                                .with_system(cleanup_interlude),
-                        )
-                        // round start
-                        .with_system_set(
-                            SystemSet::new()
-                                .with_run_criteria(on_round_start)
-                                .with_system(spawn_attackers)
-                                .with_system(spawn_defender)
-                                .with_system(spawn_world)
-                                .with_system(start_round),
-                        )
-                        // round
-                        .with_system_set(
-                            SystemSet::new()
-                                .with_run_criteria(on_round)
-                                .with_system(update_attacker_state)
-                                .with_system(update_defender_state)
-                                .label(SystemLabel::UpdateState),
-                        )
-                        .with_system_set(
-                            SystemSet::new()
-                                .with_run_criteria(on_round)
-                                .with_system(apply_attacker_inputs)
-                                .with_system(apply_defender_inputs)
-                                .label(SystemLabel::Input)
-                                .after(SystemLabel::UpdateState),
-                        )
-                        .with_system_set(
-                            SystemSet::new()
-                                .with_run_criteria(on_round)
-                                .with_system(move_attackers)
-                                .with_system(move_crosshair)
-                                .with_system(cake_collision)
-                                .with_system(splat_cleaning)
-                                .label(SystemLabel::Move)
-                                .after(SystemLabel::Input),
-                        )
-                        .with_system_set(
-                            SystemSet::new()
-                                .with_run_criteria(on_round)
-                                .with_system(check_round_end)
-                                .label(SystemLabel::End)
-                                .after(SystemLabel::Move),
-                        )
-                        // round end
-                        .with_system_set(
-                            SystemSet::new()
-                                .after(SystemLabel::End)
-                                .with_run_criteria(on_round_end)
-                                .with_system(cleanup_round),
-                        ),
-                )
-                .with_stage_after(
-                    ROLLBACK_SYSTEMS,
-                    CHECKSUM_UPDATE,
-                    SystemStage::parallel()
-                        .with_system(checksum_attackers)
-                        .with_system(checksum_cakes)
-                        .with_system(checksum_crosshair)
-                        .with_system(checksum_splat),
-                ),
-        )
-        .build(&mut app);
+        .with_rollback_schedule(rollback_schedule());
+
+    rollback_types(ggrs_plugin).build(&mut app);
 
     app.insert_resource(WindowDescriptor {
         width: SCREEN_X,
@@ -259,7 +136,7 @@ fn main() {
     .add_state(AppState::AssetLoading)
     .insert_resource(ClearColor(Color::BLACK))
     // physics
-    .add_plugin(PhysicsPlugin)
+    .add_plugin(PhysicsPlugin::default())
     // main menu
     .add_system_set(SystemSet::on_enter(AppState::MenuMain).with_system(menu::main::setup_ui))
     .add_system_set(
@@ -325,6 +202,7 @@ fn main() {
             .with_system(update_defender_sprite)
             .with_system(update_screen_timer)
             .with_system(handle_p2p_events)
+            .with_system(exchange_checksums)
             .with_system(update_connection_info)
             .with_system(update_connection_display),
     )
@@ -1,9 +1,11 @@
 use bevy::{prelude::*, tasks::IoTaskPool};
 use bevy_ggrs::SessionType;
-use ggrs::{PlayerHandle, PlayerType, SessionBuilder};
+use ggrs::{P2PSession, PlayerHandle, PlayerType, SessionBuilder};
 use matchbox_socket::WebRtcSocket;
 
 use crate::{
+    desync::{DesyncDiagnostics, PeerChecksums},
+    round::prelude::physics_config,
     AppState, FontAssets, GGRSConfig, BUTTON_TEXT, FPS, HOVERED_BUTTON, INPUT_DELAY,
     MAX_PREDICTION, NORMAL_BUTTON, NUM_PLAYERS, PRESSED_BUTTON,
 };
@@ -14,6 +16,10 @@ const MATCHBOX_ADDR: &str = "wss://match.gschup.dev";
 #[derive(Component)]
 pub struct MenuConnectUI;
 
+/// The text telling how far the connection got
+#[derive(Component)]
+pub struct MenuConnectStatus;
+
 #[derive(Component)]
 pub enum MenuConnectBtn {
     Back,
@@ -40,26 +46,78 @@ pub fn create_matchbox_socket(
     commands.remove_resource::<ConnectData>();
 }
 
+/// The url of the room for the checksums of a match. It is named after both peers,
+/// so they meet each other there and nobody else, however the lobby paired them up.
+fn checksum_room_url(own_id: &str, opponent_id: &str) -> String {
+    let (a, b) = if own_id < opponent_id {
+        (own_id, opponent_id)
+    } else {
+        (opponent_id, own_id)
+    };
+    format!("{MATCHBOX_ADDR}/checksums_{a}_{b}")
+}
+
 pub fn update_matchbox_socket(
-    commands: Commands,
+    mut commands: Commands,
     mut state: ResMut<State<AppState>>,
     mut socket_res: ResMut<Option<WebRtcSocket>>,
+    peer_checksums: Option<ResMut<PeerChecksums>>,
+    mut status_query: Query<&mut Text, With<MenuConnectStatus>>,
+    task_pool: Res<IoTaskPool>,
 ) {
     if let Some(socket) = socket_res.as_mut() {
         socket.accept_new_connections();
-        if socket.players().len() >= NUM_PLAYERS {
-            // take the socket
-            let socket = socket_res.as_mut().take().unwrap();
+    }
+
+    if let Some(mut peer_checksums) = peer_checksums {
+        // matched, but the round only starts if both peers simulate the same world
+        let agreed = match peer_checksums.agree_on_settings() {
+            Some(agreed) if socket_res.is_some() => agreed,
+            _ => return,
+        };
+        // take the socket
+        let socket = socket_res.as_mut().take().unwrap();
+        if agreed {
             create_ggrs_session(commands, socket);
             state
                 .set(AppState::RoundOnline)
                 .expect("Could not change state.");
+        } else {
+            // the side channel stays open, so the other peer gets our settings and refuses as well
+            error!("The other peer uses different physics settings, not starting the match");
+            for mut text in status_query.iter_mut() {
+                text.sections[0].value = "The other player runs a different version.".to_owned();
+            }
+        }
+    } else if let Some(socket) = socket_res.as_ref() {
+        if socket.players().len() >= NUM_PLAYERS {
+            // a side channel, so GGRS keeps the game socket to itself
+            let opponent = socket
+                .connected_peers()
+                .into_iter()
+                .next()
+                .expect("No remote peer.");
+            let checksum_url = checksum_room_url(socket.id(), &opponent);
+            let (checksum_socket, message_loop) = WebRtcSocket::new(checksum_url);
+            task_pool.spawn(message_loop).detach();
+            commands.insert_resource(PeerChecksums::new(
+                checksum_socket,
+                opponent,
+                &physics_config(),
+            ));
+            for mut text in status_query.iter_mut() {
+                text.sections[0].value = "Comparing settings...".to_owned();
+            }
         }
     }
 }
 
-pub fn cleanup(mut commands: Commands) {
+pub fn cleanup(mut commands: Commands, session: Option<Res<P2PSession<GGRSConfig>>>) {
     commands.remove_resource::<Option<WebRtcSocket>>();
+    // the round takes the side channel over along with the session, unless there's none
+    if session.is_none() {
+        commands.remove_resource::<PeerChecksums>();
+    }
 }
 
 pub fn setup_ui(mut commands: Commands, font_assets: Res<FontAssets>) {
@@ -85,24 +143,26 @@ pub fn setup_ui(mut commands: Commands, font_assets: Res<FontAssets>) {
             ..Default::default()
         })
         .with_children(|parent| {
-            // lobby id display
-            parent.spawn_bundle(TextBundle {
-                style: Style {
-                    align_self: AlignSelf::Center,
-                    justify_content: JustifyContent::Center,
-                    ..Default::default()
-                },
-                text: Text::with_section(
-                    "Searching a match...",
-                    TextStyle {
-                        font: font_assets.default_font.clone(),
-                        font_size: 32.,
-                        color: BUTTON_TEXT,
+            // connection status display
+            parent
+                .spawn_bundle(TextBundle {
+                    style: Style {
+                        align_self: AlignSelf::Center,
+                        justify_content: JustifyContent::Center,
+                        ..Default::default()
                     },
-                    Default::default(),
-                ),
-                ..Default::default()
-            });
+                    text: Text::with_section(
+                        "Searching a match...",
+                        TextStyle {
+                            font: font_assets.default_font.clone(),
+                            font_size: 32.,
+                            color: BUTTON_TEXT,
+                        },
+                        Default::default(),
+                    ),
+                    ..Default::default()
+                })
+                .insert(MenuConnectStatus);
 
             // back button
             parent
@@ -209,4 +269,26 @@ fn create_ggrs_session(mut commands: Commands, socket: WebRtcSocket) {
     commands.insert_resource(sess);
     commands.insert_resource(LocalHandles { handles });
     commands.insert_resource(SessionType::P2PSession);
+    if cfg!(feature = "desync-diagnostics") {
+        commands.insert_resource(DesyncDiagnostics::p2p());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_room_is_the_same_for_both_peers() {
+        assert_eq!(checksum_room_url("a1", "b2"), checksum_room_url("b2", "a1"));
+        assert_eq!(
+            checksum_room_url("a1", "b2"),
+            format!("{MATCHBOX_ADDR}/checksums_a1_b2")
+        );
+    }
+
+    #[test]
+    fn checksum_room_differs_between_matches() {
+        assert_ne!(checksum_room_url("a1", "b2"), checksum_room_url("a1", "c3"));
+    }
 }
@@ -1,10 +1,11 @@
 use bevy::{app::AppExit, prelude::*};
 use bevy_ggrs::SessionType;
-use ggrs::{PlayerType, SessionBuilder};
+use ggrs::{PlayerType, SessionBuilder, SyncTestSession};
 
 use crate::{
-    AppState, FontAssets, GGRSConfig, MiscAssets, BUTTON_TEXT, CHECK_DISTANCE, FPS, HOVERED_BUTTON,
-    INPUT_DELAY, MAX_PREDICTION, NORMAL_BUTTON, NUM_PLAYERS, PRESSED_BUTTON,
+    desync::DesyncDiagnostics, AppState, FontAssets, GGRSConfig, MiscAssets, BUTTON_TEXT,
+    CHECK_DISTANCE, FPS, HOVERED_BUTTON, INPUT_DELAY, MAX_PREDICTION, NORMAL_BUTTON, NUM_PLAYERS,
+    PRESSED_BUTTON,
 };
 
 use super::connect::LocalHandles;
@@ -206,14 +207,17 @@ pub fn cleanup_ui(query: Query<Entity, With<MenuMainUI>>, mut commands: Commands
     }
 }
 
-fn create_synctest_session(commands: &mut Commands) {
+/// A session with all players on this machine, which resimulates the last `check_distance` frames
+/// every frame and compares the checksums
+pub fn synctest_session(check_distance: usize) -> SyncTestSession<GGRSConfig> {
     let mut sess_build = SessionBuilder::<GGRSConfig>::new()
         .with_num_players(NUM_PLAYERS)
-        .with_max_prediction_window(MAX_PREDICTION)
+        // GGRS wants the check distance to fit in the prediction window
+        .with_max_prediction_window(MAX_PREDICTION.max(check_distance + 1))
         .with_fps(FPS)
         .expect("Invalid FPS")
         .with_input_delay(INPUT_DELAY)
-        .with_check_distance(CHECK_DISTANCE);
+        .with_check_distance(check_distance);
 
     for i in 0..NUM_PLAYERS {
         sess_build = sess_build
@@ -221,10 +225,15 @@ fn create_synctest_session(commands: &mut Commands) {
             .expect("Could not add local player");
     }
 
-    let sess = sess_build.start_synctest_session().expect("");
+    sess_build.start_synctest_session().expect("")
+}
 
-    commands.insert_resource(sess);
+fn create_synctest_session(commands: &mut Commands) {
+    commands.insert_resource(synctest_session(CHECK_DISTANCE));
     commands.insert_resource(SessionType::SyncTestSession);
+    if cfg!(feature = "desync-diagnostics") {
+        commands.insert_resource(DesyncDiagnostics::sync_test());
+    }
     commands.insert_resource(LocalHandles {
         handles: (0..NUM_PLAYERS).collect(),
     });
@@ -0,0 +1,246 @@
+//! A fluent alternative to the bundles, loosely based on the body builder of bevy_xpbd
+
+use bevy::{ecs::system::EntityCommands, prelude::*};
+use bevy_ggrs::{Rollback, RollbackIdProvider};
+
+use super::{
+    components::*,
+    math::{scalar, Scalar, Vector},
+};
+
+/// How a body built by a [`RigidBodyBuilder`] moves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RigidBodyType {
+    /// Never moves
+    Static,
+    /// Moved by gravity, forces and collisions
+    Dynamic,
+    /// Moves with its velocity, see [`Kinematic`]
+    Kinematic,
+}
+
+/// The collider of a body built by a [`RigidBodyBuilder`]
+#[derive(Debug, Clone)]
+pub enum ColliderShape {
+    Circle(CircleCollider),
+    Box(BoxCollider),
+    Capsule(CapsuleCollider),
+    Polygon(PolygonCollider),
+}
+
+impl ColliderShape {
+    fn inertia_inv_from_mass_inv(&self, mass_inv: Scalar) -> Scalar {
+        match self {
+            ColliderShape::Circle(circle) => circle.inertia_inv_from_mass_inv(mass_inv),
+            ColliderShape::Box(r#box) => r#box.inertia_inv_from_mass_inv(mass_inv),
+            ColliderShape::Capsule(capsule) => capsule.inertia_inv_from_mass_inv(mass_inv),
+            ColliderShape::Polygon(polygon) => polygon.inertia_inv_from_mass_inv(mass_inv),
+        }
+    }
+}
+
+/// Puts together the components of a rigid body, e.g.
+/// `commands.spawn_body(RigidBodyBuilder::new_dynamic().with_pos(pos).with_box(size), &mut rip)`.
+/// Bodies have a unit box collider, unless another shape is given.
+#[derive(Debug, Clone)]
+pub struct RigidBodyBuilder {
+    body_type: RigidBodyType,
+    pos: Vector,
+    rot: Scalar,
+    vel: Vector,
+    ang_vel: Scalar,
+    shape: ColliderShape,
+    mass: Mass,
+    rotates: bool,
+    material: PhysicsMaterial,
+    layers: CollisionLayers,
+}
+
+impl RigidBodyBuilder {
+    pub fn new(body_type: RigidBodyType) -> Self {
+        Self {
+            body_type,
+            pos: Vector::ZERO,
+            rot: scalar(0.),
+            vel: Vector::ZERO,
+            ang_vel: scalar(0.),
+            shape: ColliderShape::Box(BoxCollider::default()),
+            mass: Mass::default(),
+            rotates: false,
+            material: PhysicsMaterial::default(),
+            layers: CollisionLayers::default(),
+        }
+    }
+
+    pub fn new_static() -> Self {
+        Self::new(RigidBodyType::Static)
+    }
+
+    pub fn new_dynamic() -> Self {
+        Self::new(RigidBodyType::Dynamic)
+    }
+
+    pub fn new_kinematic() -> Self {
+        Self::new(RigidBodyType::Kinematic)
+    }
+
+    pub fn with_pos(mut self, pos: Vector) -> Self {
+        self.pos = pos;
+        self
+    }
+
+    /// Rotation in radians, counter-clockwise
+    pub fn with_rot(mut self, rot: Scalar) -> Self {
+        self.rot = rot;
+        self
+    }
+
+    /// Initial velocity, ignored for static bodies
+    pub fn with_vel(mut self, vel: Vector) -> Self {
+        self.vel = vel;
+        self
+    }
+
+    /// Initial angular velocity, only matters for dynamic bodies that rotate
+    pub fn with_ang_vel(mut self, ang_vel: Scalar) -> Self {
+        self.ang_vel = ang_vel;
+        self
+    }
+
+    pub fn with_shape(mut self, shape: ColliderShape) -> Self {
+        self.shape = shape;
+        self
+    }
+
+    pub fn with_circle(self, radius: Scalar) -> Self {
+        self.with_shape(ColliderShape::Circle(CircleCollider { radius }))
+    }
+
+    pub fn with_box(self, size: Vector) -> Self {
+        self.with_shape(ColliderShape::Box(BoxCollider { size }))
+    }
+
+    pub fn with_capsule(self, half_height: Scalar, radius: Scalar) -> Self {
+        self.with_shape(ColliderShape::Capsule(CapsuleCollider {
+            half_height,
+            radius,
+        }))
+    }
+
+    /// Vertices in counter-clockwise order, see [`PolygonCollider`]
+    pub fn with_polygon(self, vertices: Vec<Vector>) -> Self {
+        self.with_shape(ColliderShape::Polygon(PolygonCollider { vertices }))
+    }
+
+    /// Only matters for dynamic bodies, the others behave as if their mass was infinite
+    pub fn with_mass(mut self, mass: Scalar) -> Self {
+        self.mass = Mass(mass);
+        self
+    }
+
+    /// Lets a dynamic body rotate, with the moment of inertia of its shape and mass
+    pub fn rotating(mut self) -> Self {
+        self.rotates = true;
+        self
+    }
+
+    pub fn with_material(mut self, material: PhysicsMaterial) -> Self {
+        self.material = material;
+        self
+    }
+
+    pub fn with_layers(mut self, layers: CollisionLayers) -> Self {
+        self.layers = layers;
+        self
+    }
+
+    /// Adds the components of the body to `entity`, along with a new rollback id
+    pub fn insert_into(self, entity: &mut EntityCommands, rip: &mut RollbackIdProvider) {
+        // integration overwrites this before anything reads it
+        let prev_pos = PrevPos(self.pos);
+
+        entity
+            .insert(Pos(self.pos))
+            .insert(Rot(self.rot))
+            .insert(Aabb::default())
+            .insert(self.material)
+            .insert(self.layers);
+
+        match self.body_type {
+            RigidBodyType::Static => {}
+            RigidBodyType::Dynamic => {
+                let inv_inertia = if self.rotates {
+                    self.shape
+                        .inertia_inv_from_mass_inv(scalar(1.) / self.mass.0)
+                } else {
+                    scalar(0.)
+                };
+                entity
+                    .insert(prev_pos)
+                    .insert(PrevRot(self.rot))
+                    .insert(self.mass)
+                    .insert(InvInertia(inv_inertia))
+                    .insert(Vel(self.vel))
+                    .insert(PreSolveVel::default())
+                    .insert(AngVel(self.ang_vel))
+                    .insert(PreSolveAngVel::default())
+                    .insert(SleepState::default());
+            }
+            RigidBodyType::Kinematic => {
+                entity
+                    .insert(prev_pos)
+                    .insert(Vel(self.vel))
+                    .insert(Kinematic);
+            }
+        }
+
+        match self.shape {
+            ColliderShape::Circle(circle) => entity.insert(circle),
+            ColliderShape::Box(r#box) => entity.insert(r#box),
+            ColliderShape::Capsule(capsule) => entity.insert(capsule),
+            ColliderShape::Polygon(polygon) => entity.insert(polygon),
+        };
+
+        entity.insert(Rollback::new(rip.next_id()));
+    }
+}
+
+/// Spawns rigid bodies through [`Commands`]
+pub trait SpawnBodyExt<'w, 's> {
+    /// Spawns the body, with a new rollback id
+    fn spawn_body<'a>(
+        &'a mut self,
+        builder: RigidBodyBuilder,
+        rip: &mut RollbackIdProvider,
+    ) -> EntityCommands<'w, 's, 'a>;
+}
+
+impl<'w, 's> SpawnBodyExt<'w, 's> for Commands<'w, 's> {
+    fn spawn_body<'a>(
+        &'a mut self,
+        builder: RigidBodyBuilder,
+        rip: &mut RollbackIdProvider,
+    ) -> EntityCommands<'w, 's, 'a> {
+        let mut entity = self.spawn();
+        builder.insert_into(&mut entity, rip);
+        entity
+    }
+}
+
+/// Turns an entity that is being spawned, e.g. a sprite, into a rigid body
+pub trait InsertBodyExt {
+    /// Adds the components of the body, with a new rollback id
+    fn insert_body(&mut self, builder: RigidBodyBuilder, rip: &mut RollbackIdProvider)
+        -> &mut Self;
+}
+
+impl InsertBodyExt for EntityCommands<'_, '_, '_> {
+    fn insert_body(
+        &mut self,
+        builder: RigidBodyBuilder,
+        rip: &mut RollbackIdProvider,
+    ) -> &mut Self {
+        builder.insert_into(self, rip);
+        self
+    }
+}
@@ -6,12 +6,19 @@ use super::components::*;
 pub struct ParticleBundle {
     pub pos: Pos,
     pub prev_pos: PrevPos,
+    pub rot: Rot,
+    pub prev_rot: PrevRot,
     pub mass: Mass,
+    pub inv_inertia: InvInertia,
     pub collider: CircleCollider,
     pub vel: Vel,
     pub pre_solve_vel: PreSolveVel,
-    pub restitution: Restitution,
+    pub ang_vel: AngVel,
+    pub pre_solve_ang_vel: PreSolveAngVel,
+    pub material: PhysicsMaterial,
     pub aabb: Aabb,
+    pub layers: CollisionLayers,
+    pub sleep: SleepState,
 }
 
 impl ParticleBundle {
@@ -29,12 +36,19 @@ impl ParticleBundle {
 pub struct DynamicBoxBundle {
     pub pos: Pos,
     pub prev_pos: PrevPos,
+    pub rot: Rot,
+    pub prev_rot: PrevRot,
     pub mass: Mass,
+    pub inv_inertia: InvInertia,
     pub collider: BoxCollider,
     pub vel: Vel,
     pub pre_solve_vel: PreSolveVel,
-    pub restitution: Restitution,
+    pub ang_vel: AngVel,
+    pub pre_solve_ang_vel: PreSolveAngVel,
+    pub material: PhysicsMaterial,
     pub aabb: Aabb,
+    pub layers: CollisionLayers,
+    pub sleep: SleepState,
 }
 
 impl DynamicBoxBundle {
@@ -48,16 +62,66 @@ impl DynamicBoxBundle {
     // }
 }
 
+#[derive(Bundle, Default)]
+pub struct DynamicCapsuleBundle {
+    pub pos: Pos,
+    pub prev_pos: PrevPos,
+    pub rot: Rot,
+    pub prev_rot: PrevRot,
+    pub mass: Mass,
+    pub inv_inertia: InvInertia,
+    pub collider: CapsuleCollider,
+    pub vel: Vel,
+    pub pre_solve_vel: PreSolveVel,
+    pub ang_vel: AngVel,
+    pub pre_solve_ang_vel: PreSolveAngVel,
+    pub material: PhysicsMaterial,
+    pub aabb: Aabb,
+    pub layers: CollisionLayers,
+    pub sleep: SleepState,
+}
+
 #[derive(Bundle, Default)]
 pub struct StaticCircleBundle {
     pub pos: Pos,
+    pub rot: Rot,
     pub collider: CircleCollider,
-    pub restitution: Restitution,
+    pub material: PhysicsMaterial,
+    pub aabb: Aabb,
+    pub layers: CollisionLayers,
 }
 
 #[derive(Bundle, Default)]
 pub struct StaticBoxBundle {
     pub pos: Pos,
+    pub rot: Rot,
+    pub collider: BoxCollider,
+    pub material: PhysicsMaterial,
+    pub aabb: Aabb,
+    pub layers: CollisionLayers,
+}
+
+/// For ramps and slopes
+#[derive(Bundle, Default)]
+pub struct StaticPolygonBundle {
+    pub pos: Pos,
+    pub rot: Rot,
+    pub collider: PolygonCollider,
+    pub material: PhysicsMaterial,
+    pub aabb: Aabb,
+    pub layers: CollisionLayers,
+}
+
+/// A body that moves along its velocity, or a [`KinematicPath`] if one is added
+#[derive(Bundle, Default)]
+pub struct KinematicBoxBundle {
+    pub pos: Pos,
+    pub prev_pos: PrevPos,
+    pub rot: Rot,
+    pub vel: Vel,
     pub collider: BoxCollider,
-    pub restitution: Restitution,
+    pub material: PhysicsMaterial,
+    pub aabb: Aabb,
+    pub layers: CollisionLayers,
+    pub kinematic: Kinematic,
 }
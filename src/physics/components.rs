@@ -1,13 +1,14 @@
 use bevy::prelude::*;
 use derive_more::From;
 
-// todo: register all of these as rollback components
+use super::math::{scalar, Scalar, Vector};
+use crate::checksum::ReflectChecksum;
 
 #[derive(Component, Reflect, Debug, Default, Clone, Copy)]
 #[reflect(Component)]
 pub struct Aabb {
-    pub(crate) min: Vec2,
-    pub(crate) max: Vec2,
+    pub(crate) min: Vector,
+    pub(crate) max: Vector,
 }
 
 impl Aabb {
@@ -22,65 +23,369 @@ impl Aabb {
 #[derive(Component, Reflect, Debug, Clone, Copy, From)]
 #[reflect(Component)]
 pub struct CircleCollider {
-    pub radius: f32,
+    pub radius: Scalar,
+}
+
+impl CircleCollider {
+    pub fn inertia_inv_from_mass_inv(&self, mass_inv: Scalar) -> Scalar {
+        scalar(2.) * mass_inv / (self.radius * self.radius)
+    }
 }
 
 impl Default for CircleCollider {
     fn default() -> Self {
-        Self { radius: 0.5 }
+        Self {
+            radius: scalar(0.5),
+        }
     }
 }
 
 #[derive(Component, Reflect, Debug, Clone, Copy, From)]
 #[reflect(Component)]
 pub struct BoxCollider {
-    pub size: Vec2,
+    pub size: Vector,
 }
 
 impl BoxCollider {
-    // pub fn inertia_inv_from_mass_inv(&self, mass_inv: f32) -> f32 {
-    //     12. * mass_inv / self.size.length_squared()
-    // }
+    pub fn inertia_inv_from_mass_inv(&self, mass_inv: Scalar) -> Scalar {
+        scalar(12.) * mass_inv / self.size.length_squared()
+    }
 }
 
 impl Default for BoxCollider {
     fn default() -> Self {
-        Self { size: Vec2::ONE }
+        Self { size: Vector::ONE }
+    }
+}
+
+/// A rectangle with two half circles on its ends, standing upright when not rotated.
+/// Unlike boxes, capsules slide over the corners of whatever they stand on.
+#[derive(Component, Reflect, Debug, Clone, Copy)]
+#[reflect(Component)]
+pub struct CapsuleCollider {
+    /// Half the distance between the centers of the two half circles
+    pub half_height: Scalar,
+    pub radius: Scalar,
+}
+
+impl CapsuleCollider {
+    /// Approximated by the inertia of the bounding box
+    pub fn inertia_inv_from_mass_inv(&self, mass_inv: Scalar) -> Scalar {
+        let size = scalar(2.) * Vector::new(self.radius, self.half_height + self.radius);
+        scalar(12.) * mass_inv / size.length_squared()
+    }
+}
+
+impl Default for CapsuleCollider {
+    fn default() -> Self {
+        Self {
+            half_height: scalar(0.25),
+            radius: scalar(0.25),
+        }
+    }
+}
+
+/// A convex polygon, with its vertices in counter-clockwise order, relative to the position of the body.
+/// The position has to be inside the polygon.
+#[derive(Component, Reflect, Debug, Clone)]
+#[reflect(Component)]
+pub struct PolygonCollider {
+    pub vertices: Vec<Vector>,
+}
+
+impl PolygonCollider {
+    pub fn inertia_inv_from_mass_inv(&self, mass_inv: Scalar) -> Scalar {
+        // sum over the triangles between the origin and every edge
+        let mut numerator = scalar(0.);
+        let mut denominator = scalar(0.);
+        for (i, a) in self.vertices.iter().enumerate() {
+            let b = self.vertices[(i + 1) % self.vertices.len()];
+            let cross = a.perp_dot(b).abs();
+            numerator += cross * (a.dot(*a) + a.dot(b) + b.dot(b));
+            denominator += cross;
+        }
+        scalar(6.) * mass_inv * denominator / numerator
+    }
+}
+
+impl Default for PolygonCollider {
+    fn default() -> Self {
+        let half = scalar(0.5);
+        Self {
+            vertices: vec![
+                Vector::new(-half, -half),
+                Vector::new(half, -half),
+                Vector::new(half, half),
+                Vector::new(-half, half),
+            ],
+        }
     }
 }
 
 #[derive(Component, Reflect, Debug, Default, Clone, Copy, From)]
 #[reflect(Component)]
-pub struct Pos(pub Vec2);
+pub struct Pos(pub Vector);
+
+#[derive(Component, Reflect, Debug, Default, Clone, Copy, From)]
+#[reflect(Component)]
+pub struct PrevPos(pub Vector);
+
+/// Rotation in radians, counter-clockwise
+#[derive(Component, Reflect, Debug, Default, Clone, Copy, From)]
+#[reflect(Component)]
+pub struct Rot(pub Scalar);
 
 #[derive(Component, Reflect, Debug, Default, Clone, Copy, From)]
 #[reflect(Component)]
-pub struct PrevPos(pub Vec2);
+pub struct PrevRot(pub Scalar);
 
 #[derive(Component, Reflect, Clone, Copy, Debug, Default)]
 #[reflect(Component)]
-pub struct Vel(pub(crate) Vec2);
+pub struct Vel(pub(crate) Vector);
+
+#[derive(Component, Reflect, Clone, Copy, Debug, Default, From)]
+#[reflect(Component)]
+pub struct AngVel(pub Scalar);
+
+#[derive(Component, Reflect, Debug, Default)]
+#[reflect(Component)]
+pub struct PreSolveVel(pub(crate) Vector);
 
 #[derive(Component, Reflect, Debug, Default)]
 #[reflect(Component)]
-pub struct PreSolveVel(pub(crate) Vec2);
+pub struct PreSolveAngVel(pub(crate) Scalar);
 
 #[derive(Component, Reflect, Debug, Clone, Copy, From)]
 #[reflect(Component)]
-pub struct Mass(pub f32);
+pub struct Mass(pub Scalar);
 
 impl Default for Mass {
     fn default() -> Self {
-        Self(1.)
+        Self(scalar(1.))
+    }
+}
+
+/// Multiplies the global [`gravity`](super::resources::PhysicsConfig::gravity) for a single body
+#[derive(Component, Reflect, Debug, Clone, Copy, From)]
+#[reflect(Component)]
+pub struct GravityScale(pub Scalar);
+
+impl Default for GravityScale {
+    fn default() -> Self {
+        Self(scalar(1.))
+    }
+}
+
+/// Force that keeps being applied every substep, until it is changed
+#[derive(Component, Reflect, Debug, Default, Clone, Copy, From)]
+#[reflect(Component)]
+pub struct ExternalForce(pub Vector);
+
+/// Impulse that is applied once at the start of the next physics step, and then reset to zero.
+/// Add to it rather than overwriting it, so impulses from different systems stack.
+#[derive(Component, Reflect, Debug, Default, Clone, Copy, From)]
+#[reflect(Component)]
+pub struct ExternalImpulse(pub Vector);
+
+/// How quickly a body slows down on its own, zero means not at all
+#[derive(Component, Reflect, Debug, Default, Clone, Copy, From)]
+#[reflect(Component)]
+pub struct LinearDamping(pub Scalar);
+
+/// Upper limit for the speed of a body
+#[derive(Component, Reflect, Debug, Clone, Copy, From)]
+#[reflect(Component)]
+pub struct MaxSpeed(pub Scalar);
+
+impl Default for MaxSpeed {
+    fn default() -> Self {
+        Self(Scalar::MAX) // no limit
+    }
+}
+
+/// Inverse of the moment of inertia, the default of zero means the body can't rotate
+#[derive(Component, Reflect, Debug, Default, Clone, Copy, From)]
+#[reflect(Component)]
+pub struct InvInertia(pub Scalar);
+
+/// How the coefficients of two touching materials are combined.
+/// If the two materials disagree, the rule further down the list wins.
+#[derive(Reflect, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[reflect(Hash, Checksum)]
+pub enum CombineRule {
+    Average,
+    Min,
+    Multiply,
+    Max,
+}
+
+impl Default for CombineRule {
+    fn default() -> Self {
+        Self::Average
+    }
+}
+
+impl CombineRule {
+    pub fn combine(self, a: Scalar, b: Scalar) -> Scalar {
+        match self {
+            CombineRule::Average => (a + b) / scalar(2.),
+            CombineRule::Min => a.min(b),
+            CombineRule::Multiply => a * b,
+            CombineRule::Max => a.max(b),
+        }
+    }
+}
+
+/// Surface properties of a body, the default is frictionless and doesn't bounce
+#[derive(Component, Reflect, Debug, Default, Clone, Copy)]
+#[reflect(Component)]
+pub struct PhysicsMaterial {
+    /// How hard it is to get a resting body sliding
+    pub static_friction: Scalar,
+    /// How much a sliding body is slowed down
+    pub dynamic_friction: Scalar,
+    /// How bouncy the body is, 0 means no bounce, 1 means a perfectly elastic bounce
+    pub restitution: Scalar,
+    pub friction_combine: CombineRule,
+    pub restitution_combine: CombineRule,
+}
+
+impl PhysicsMaterial {
+    /// The material of a contact between bodies made of `self` and `other`
+    pub fn combine(&self, other: &Self) -> Self {
+        let friction_combine = self.friction_combine.max(other.friction_combine);
+        let restitution_combine = self.restitution_combine.max(other.restitution_combine);
+        Self {
+            static_friction: friction_combine.combine(self.static_friction, other.static_friction),
+            dynamic_friction: friction_combine
+                .combine(self.dynamic_friction, other.dynamic_friction),
+            restitution: restitution_combine.combine(self.restitution, other.restitution),
+            friction_combine,
+            restitution_combine,
+        }
+    }
+}
+
+/// Which collision layers a body is on (`memberships`) and which layers it collides with (`filters`).
+/// Two bodies only collide if each of them is on a layer the other one collides with.
+/// Bodies without this component are on all layers and collide with everything.
+#[derive(Component, Reflect, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[reflect(Component, Hash)]
+pub struct CollisionLayers {
+    pub memberships: u32,
+    pub filters: u32,
+}
+
+impl CollisionLayers {
+    pub const ALL: u32 = u32::MAX;
+    pub const NONE: u32 = 0;
+
+    pub fn new(memberships: u32, filters: u32) -> Self {
+        Self {
+            memberships,
+            filters,
+        }
+    }
+
+    pub fn interacts_with(&self, other: &Self) -> bool {
+        self.memberships & other.filters != 0 && other.memberships & self.filters != 0
+    }
+}
+
+impl Default for CollisionLayers {
+    fn default() -> Self {
+        Self::new(Self::ALL, Self::ALL)
+    }
+}
+
+/// Marks a body that only detects overlaps, other bodies pass right through it.
+/// Overlaps show up in [`Collisions`](super::resources::Collisions) and the collision events.
+#[derive(Component, Reflect, Debug, Default, Clone, Copy)]
+#[reflect(Component)]
+pub struct Sensor;
+
+/// Opts a fast body into continuous collision detection.
+/// Its motion is swept against the bodies it may collide with, and clamped at the first hit,
+/// so it can't tunnel through thin colliders. Only the inscribed circle of a box is swept.
+#[derive(Component, Reflect, Debug, Default, Clone, Copy)]
+#[reflect(Component)]
+pub struct Ccd;
+
+/// Makes a static box a platform that bodies can pass through from one side.
+/// Bodies are only pushed out along `normal`, and only if they were completely on that side
+/// of the platform before the substep, judging by their [`PrevPos`].
+#[derive(Component, Reflect, Debug, Clone, Copy)]
+#[reflect(Component)]
+pub struct OneWay {
+    /// Points out of the solid side of the platform, needs to be normalized
+    pub normal: Vector,
+}
+
+impl Default for OneWay {
+    fn default() -> Self {
+        Self { normal: Vector::Y }
     }
 }
 
-#[derive(Component, Reflect, Debug)]
+/// While a body has this, it falls through [`OneWay`] platforms
+#[derive(Component, Reflect, Debug, Default, Clone, Copy)]
 #[reflect(Component)]
-pub struct Restitution(pub f32);
+pub struct DropThrough;
+
+/// Lets a dynamic body fall asleep after it has been at rest for [`SLEEP_FRAMES`](super::SLEEP_FRAMES) frames.
+/// Sleeping bodies are skipped by integration and the AABB updates, and the broadphase only pairs them
+/// with awake bodies, which wake them up. Setting the velocity of a sleeping body or giving it an
+/// [`ExternalImpulse`] wakes it up as well.
+// the usize counts the number of frames the body has been at rest
+#[derive(Component, Reflect, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[reflect(Component, Hash, Checksum)]
+pub enum SleepState {
+    Awake(usize),
+    Sleeping,
+}
 
-impl Default for Restitution {
+impl Default for SleepState {
     fn default() -> Self {
-        Self(0.) // no bounce, could just derive...
+        Self::Awake(0)
+    }
+}
+
+impl SleepState {
+    pub fn is_sleeping(&self) -> bool {
+        matches!(self, Self::Sleeping)
+    }
+
+    pub fn wake_up(&mut self) {
+        *self = Self::Awake(0);
+    }
+}
+
+/// Marks a body that moves on its own, with its [`Vel`] or a [`KinematicPath`].
+/// It has no [`Mass`], so like a static body it pushes dynamic bodies without being pushed back.
+/// Bodies resting on it are carried along by friction.
+#[derive(Component, Reflect, Debug, Default, Clone, Copy)]
+#[reflect(Component)]
+pub struct Kinematic;
+
+/// Moves a [`Kinematic`] body back and forth between `start` and `end` at `speed`,
+/// by overriding its velocity every substep
+#[derive(Component, Reflect, Debug, Default, Clone, Copy)]
+#[reflect(Component)]
+pub struct KinematicPath {
+    pub start: Vector,
+    pub end: Vector,
+    pub speed: Scalar,
+    /// Whether the body is currently headed to `end`
+    pub forward: bool,
+}
+
+impl KinematicPath {
+    pub fn new(start: Vector, end: Vector, speed: Scalar) -> Self {
+        Self {
+            start,
+            end,
+            speed,
+            forward: true,
+        }
     }
 }
@@ -1,11 +1,36 @@
-use bevy::prelude::*;
+use super::{
+    components::{BoxCollider, CapsuleCollider, CircleCollider, PolygonCollider},
+    math::{scalar, Scalar, Vector},
+};
+
+/// Vertices closer than this to the deepest vertex are averaged into a single contact point,
+/// so resting boxes don't get pushed by a single corner
+const CONTACT_POINT_TOLERANCE: f32 = 0.05;
 
 pub struct Contact {
-    pub penetration: f32,
-    pub normal: Vec2,
+    pub penetration: Scalar,
+    /// Points from body a to body b
+    pub normal: Vector,
+    /// World space point the contact is applied at, halfway between the two surfaces
+    pub point: Vector,
+}
+
+impl Contact {
+    /// The same contact, as seen from the other body
+    pub fn flipped(self) -> Self {
+        Self {
+            normal: -self.normal,
+            ..self
+        }
+    }
 }
 
-pub fn ball_ball(pos_a: Vec2, radius_a: f32, pos_b: Vec2, radius_b: f32) -> Option<Contact> {
+pub fn ball_ball(
+    pos_a: Vector,
+    radius_a: Scalar,
+    pos_b: Vector,
+    radius_b: Scalar,
+) -> Option<Contact> {
     let ab = pos_b - pos_a;
     let combined_radius = radius_a + radius_b;
     let ab_sqr_len = ab.length_squared();
@@ -16,16 +41,17 @@ pub fn ball_ball(pos_a: Vec2, radius_a: f32, pos_b: Vec2, radius_b: f32) -> Opti
         Some(Contact {
             normal,
             penetration,
+            point: pos_a + normal * (radius_a - penetration / scalar(2.)),
         })
     } else {
         None
     }
 }
 
-pub fn ball_box(pos_a: Vec2, radius_a: f32, pos_b: Vec2, size_b: Vec2) -> Option<Contact> {
+pub fn ball_box(pos_a: Vector, radius_a: Scalar, pos_b: Vector, size_b: Vector) -> Option<Contact> {
     let box_to_circle = pos_a - pos_b;
     let box_to_circle_abs = box_to_circle.abs();
-    let half_extents = size_b / 2.;
+    let half_extents = size_b / scalar(2.);
     let corner_to_center = box_to_circle_abs - half_extents;
     let r = radius_a;
     if corner_to_center.x > r || corner_to_center.y > r {
@@ -34,7 +60,7 @@ pub fn ball_box(pos_a: Vec2, radius_a: f32, pos_b: Vec2, size_b: Vec2) -> Option
 
     let s = box_to_circle.signum();
 
-    let (n, penetration) = if corner_to_center.x > 0. && corner_to_center.y > 0. {
+    let (n, penetration) = if corner_to_center.x > scalar(0.) && corner_to_center.y > scalar(0.) {
         // Corner case
         let corner_to_center_sqr = corner_to_center.length_squared();
         if corner_to_center_sqr > r * r {
@@ -46,56 +72,600 @@ pub fn ball_box(pos_a: Vec2, radius_a: f32, pos_b: Vec2, size_b: Vec2) -> Option
         (n, penetration)
     } else if corner_to_center.x > corner_to_center.y {
         // Closer to vertical edge
-        (Vec2::X * -s.x, -corner_to_center.x + r)
+        (Vector::X * -s.x, -corner_to_center.x + r)
     } else {
-        (Vec2::Y * -s.y, -corner_to_center.y + r)
+        (Vector::Y * -s.y, -corner_to_center.y + r)
     };
 
     Some(Contact {
         normal: n,
         penetration,
+        point: pos_a + n * (r - penetration / scalar(2.)),
+    })
+}
+
+/// Same as [`ball_box`], but the box is rotated by `rot_b` radians
+pub fn ball_obb(
+    pos_a: Vector,
+    radius_a: Scalar,
+    pos_b: Vector,
+    rot_b: Scalar,
+    size_b: Vector,
+) -> Option<Contact> {
+    // solve in the local space of the box
+    let local_a = rotate(pos_a - pos_b, -rot_b);
+    ball_box(local_a, radius_a, Vector::ZERO, size_b).map(|contact| Contact {
+        penetration: contact.penetration,
+        normal: rotate(contact.normal, rot_b),
+        point: pos_b + rotate(contact.point, rot_b),
     })
 }
 
-pub fn box_box(pos_a: Vec2, size_a: Vec2, pos_b: Vec2, size_b: Vec2) -> Option<Contact> {
-    let half_a = size_a / 2.;
-    let half_b = size_b / 2.;
+pub fn box_box(pos_a: Vector, size_a: Vector, pos_b: Vector, size_b: Vector) -> Option<Contact> {
+    let half_a = size_a / scalar(2.);
+    let half_b = size_b / scalar(2.);
     let ab = pos_b - pos_a;
     let overlap = (half_a + half_b) - ab.abs(); // exploit symmetry
-    if overlap.x < 0. || overlap.y < 0. {
+                                                // center of the intersection of both boxes
+    let intersection_min = (pos_a - half_a).max(pos_b - half_b);
+    let intersection_max = (pos_a + half_a).min(pos_b + half_b);
+    let point = (intersection_min + intersection_max) / scalar(2.);
+    if overlap.x < scalar(0.) || overlap.y < scalar(0.) {
         None
     } else if overlap.x < overlap.y {
         // closer to vertical edge
         Some(Contact {
             penetration: overlap.x,
-            normal: Vec2::X * ab.x.signum(),
+            normal: Vector::X * ab.x.signum(),
+            point,
         })
     } else {
         // closer to horizontal edge
         Some(Contact {
             penetration: overlap.y,
-            normal: Vec2::Y * ab.y.signum(),
+            normal: Vector::Y * ab.y.signum(),
+            point,
         })
     }
 }
 
+/// Oriented box vs oriented box, using the separating axis theorem.
+/// Falls back to [`box_box`] if neither box is rotated.
+pub fn obb_obb(
+    pos_a: Vector,
+    rot_a: Scalar,
+    size_a: Vector,
+    pos_b: Vector,
+    rot_b: Scalar,
+    size_b: Vector,
+) -> Option<Contact> {
+    if rot_a == scalar(0.) && rot_b == scalar(0.) {
+        return box_box(pos_a, size_a, pos_b, size_b);
+    }
+
+    let axes_a = box_axes(rot_a);
+    let axes_b = box_axes(rot_b);
+    let half_a = size_a / scalar(2.);
+    let half_b = size_b / scalar(2.);
+    let ab = pos_b - pos_a;
+
+    // find the axis of least penetration, remembering whether it belongs to box a
+    let mut best: Option<(Scalar, Vector, bool)> = None;
+    for (i, axis) in axes_a.iter().chain(axes_b.iter()).enumerate() {
+        let dist = ab.dot(*axis);
+        let overlap =
+            project_box(axes_a, half_a, *axis) + project_box(axes_b, half_b, *axis) - dist.abs();
+        if overlap < scalar(0.) {
+            return None;
+        }
+        if best.map_or(true, |(best_overlap, ..)| overlap < best_overlap) {
+            best = Some((overlap, *axis * dist.signum(), i < 2));
+        }
+    }
+
+    let (penetration, normal, axis_of_a) = best?;
+    // the contact point lies on the deepest vertices of the other box
+    let point = if axis_of_a {
+        deepest_point(&box_vertices(pos_b, axes_b, half_b), -normal)
+            + normal * penetration / scalar(2.)
+    } else {
+        deepest_point(&box_vertices(pos_a, axes_a, half_a), normal)
+            - normal * penetration / scalar(2.)
+    };
+
+    Some(Contact {
+        penetration,
+        normal,
+        point,
+    })
+}
+
+/// Capsules and polygons are all handled as rounded convex hulls, with the separating axis theorem
+pub fn capsule_ball(
+    pos_a: Vector,
+    rot_a: Scalar,
+    capsule_a: &CapsuleCollider,
+    pos_b: Vector,
+    radius_b: Scalar,
+) -> Option<Contact> {
+    hull_hull(
+        Hull {
+            vertices: &capsule_segment(pos_a, rot_a, capsule_a),
+            radius: capsule_a.radius,
+        },
+        Hull {
+            vertices: &[pos_b],
+            radius: radius_b,
+        },
+    )
+}
+
+pub fn capsule_obb(
+    pos_a: Vector,
+    rot_a: Scalar,
+    capsule_a: &CapsuleCollider,
+    pos_b: Vector,
+    rot_b: Scalar,
+    size_b: Vector,
+) -> Option<Contact> {
+    hull_hull(
+        Hull {
+            vertices: &capsule_segment(pos_a, rot_a, capsule_a),
+            radius: capsule_a.radius,
+        },
+        Hull {
+            vertices: &box_vertices(pos_b, box_axes(rot_b), size_b / scalar(2.)),
+            radius: scalar(0.),
+        },
+    )
+}
+
+pub fn capsule_capsule(
+    pos_a: Vector,
+    rot_a: Scalar,
+    capsule_a: &CapsuleCollider,
+    pos_b: Vector,
+    rot_b: Scalar,
+    capsule_b: &CapsuleCollider,
+) -> Option<Contact> {
+    hull_hull(
+        Hull {
+            vertices: &capsule_segment(pos_a, rot_a, capsule_a),
+            radius: capsule_a.radius,
+        },
+        Hull {
+            vertices: &capsule_segment(pos_b, rot_b, capsule_b),
+            radius: capsule_b.radius,
+        },
+    )
+}
+
+pub fn polygon_ball(
+    pos_a: Vector,
+    rot_a: Scalar,
+    polygon_a: &PolygonCollider,
+    pos_b: Vector,
+    radius_b: Scalar,
+) -> Option<Contact> {
+    hull_hull(
+        Hull {
+            vertices: &polygon_vertices(pos_a, rot_a, polygon_a),
+            radius: scalar(0.),
+        },
+        Hull {
+            vertices: &[pos_b],
+            radius: radius_b,
+        },
+    )
+}
+
+pub fn polygon_obb(
+    pos_a: Vector,
+    rot_a: Scalar,
+    polygon_a: &PolygonCollider,
+    pos_b: Vector,
+    rot_b: Scalar,
+    size_b: Vector,
+) -> Option<Contact> {
+    hull_hull(
+        Hull {
+            vertices: &polygon_vertices(pos_a, rot_a, polygon_a),
+            radius: scalar(0.),
+        },
+        Hull {
+            vertices: &box_vertices(pos_b, box_axes(rot_b), size_b / scalar(2.)),
+            radius: scalar(0.),
+        },
+    )
+}
+
+pub fn polygon_capsule(
+    pos_a: Vector,
+    rot_a: Scalar,
+    polygon_a: &PolygonCollider,
+    pos_b: Vector,
+    rot_b: Scalar,
+    capsule_b: &CapsuleCollider,
+) -> Option<Contact> {
+    hull_hull(
+        Hull {
+            vertices: &polygon_vertices(pos_a, rot_a, polygon_a),
+            radius: scalar(0.),
+        },
+        Hull {
+            vertices: &capsule_segment(pos_b, rot_b, capsule_b),
+            radius: capsule_b.radius,
+        },
+    )
+}
+
+pub fn polygon_polygon(
+    pos_a: Vector,
+    rot_a: Scalar,
+    polygon_a: &PolygonCollider,
+    pos_b: Vector,
+    rot_b: Scalar,
+    polygon_b: &PolygonCollider,
+) -> Option<Contact> {
+    hull_hull(
+        Hull {
+            vertices: &polygon_vertices(pos_a, rot_a, polygon_a),
+            radius: scalar(0.),
+        },
+        Hull {
+            vertices: &polygon_vertices(pos_b, rot_b, polygon_b),
+            radius: scalar(0.),
+        },
+    )
+}
+
+/// Any of the collider shapes, for code that has to handle all of them
+#[derive(Debug, Clone, Copy)]
+pub enum Shape<'a> {
+    Ball(Scalar),
+    Box(Vector),
+    Capsule(&'a CapsuleCollider),
+    Polygon(&'a PolygonCollider),
+}
+
+impl<'a> Shape<'a> {
+    /// The shape of a body, from whichever collider component it has
+    pub fn of(
+        (circle, r#box, capsule, polygon): (
+            Option<&'a CircleCollider>,
+            Option<&'a BoxCollider>,
+            Option<&'a CapsuleCollider>,
+            Option<&'a PolygonCollider>,
+        ),
+    ) -> Option<Self> {
+        match (circle, r#box, capsule, polygon) {
+            (Some(circle), ..) => Some(Shape::Ball(circle.radius)),
+            (_, Some(r#box), ..) => Some(Shape::Box(r#box.size)),
+            (_, _, Some(capsule), _) => Some(Shape::Capsule(capsule)),
+            (.., Some(polygon)) => Some(Shape::Polygon(polygon)),
+            _ => None,
+        }
+    }
+
+    /// Pairs of balls and boxes have dedicated solvers, every other pair goes through the generic ones
+    pub fn is_ball_or_box(&self) -> bool {
+        matches!(self, Shape::Ball(_) | Shape::Box(_))
+    }
+
+    /// How far the shape reaches from its center along the normalized `dir`
+    pub fn reach(&self, rot: Scalar, dir: Vector) -> Scalar {
+        match self {
+            Shape::Ball(radius) => *radius,
+            Shape::Box(size) => project_box(box_axes(rot), *size / scalar(2.), dir),
+            Shape::Capsule(capsule) => {
+                capsule.half_height * rotate(Vector::Y, rot).dot(dir).abs() + capsule.radius
+            }
+            Shape::Polygon(polygon) => {
+                let local_dir = rotate(dir, -rot);
+                polygon
+                    .vertices
+                    .iter()
+                    .map(|v| v.dot(local_dir))
+                    .fold(Scalar::MIN, Scalar::max)
+            }
+        }
+    }
+
+    /// Radius of the largest circle around the center that fits inside the shape
+    pub fn inscribed_radius(&self) -> Scalar {
+        match self {
+            Shape::Ball(radius) => *radius,
+            Shape::Box(size) => size.x.min(size.y) / scalar(2.),
+            Shape::Capsule(capsule) => capsule.radius,
+            Shape::Polygon(polygon) => hull_edges(&polygon.vertices)
+                .filter(|(start, end)| start != end)
+                .map(|(start, end)| {
+                    let edge = end - start;
+                    start.dot(Vector::new(edge.y, -edge.x).normalize())
+                })
+                .fold(Scalar::MAX, Scalar::min),
+        }
+    }
+
+    /// World space vertices of the shape, and the radius they are rounded off by
+    pub fn hull(&self, pos: Vector, rot: Scalar) -> (Vec<Vector>, Scalar) {
+        match self {
+            Shape::Ball(radius) => (vec![pos], *radius),
+            Shape::Box(size) => (
+                box_vertices(pos, box_axes(rot), *size / scalar(2.)).to_vec(),
+                scalar(0.),
+            ),
+            Shape::Capsule(capsule) => {
+                (capsule_segment(pos, rot, capsule).to_vec(), capsule.radius)
+            }
+            Shape::Polygon(polygon) => (polygon_vertices(pos, rot, polygon), scalar(0.)),
+        }
+    }
+}
+
+/// Contact between any two shapes
+pub fn shape_shape(
+    pos_a: Vector,
+    rot_a: Scalar,
+    a: Shape,
+    pos_b: Vector,
+    rot_b: Scalar,
+    b: Shape,
+) -> Option<Contact> {
+    use Shape::*;
+    match (a, b) {
+        (Ball(radius_a), Ball(radius_b)) => ball_ball(pos_a, radius_a, pos_b, radius_b),
+        (Ball(radius_a), Box(size_b)) => ball_obb(pos_a, radius_a, pos_b, rot_b, size_b),
+        (Ball(radius_a), Capsule(capsule_b)) => {
+            capsule_ball(pos_b, rot_b, capsule_b, pos_a, radius_a).map(Contact::flipped)
+        }
+        (Ball(radius_a), Polygon(polygon_b)) => {
+            polygon_ball(pos_b, rot_b, polygon_b, pos_a, radius_a).map(Contact::flipped)
+        }
+        (Box(size_a), Ball(radius_b)) => {
+            ball_obb(pos_b, radius_b, pos_a, rot_a, size_a).map(Contact::flipped)
+        }
+        (Box(size_a), Box(size_b)) => obb_obb(pos_a, rot_a, size_a, pos_b, rot_b, size_b),
+        (Box(size_a), Capsule(capsule_b)) => {
+            capsule_obb(pos_b, rot_b, capsule_b, pos_a, rot_a, size_a).map(Contact::flipped)
+        }
+        (Box(size_a), Polygon(polygon_b)) => {
+            polygon_obb(pos_b, rot_b, polygon_b, pos_a, rot_a, size_a).map(Contact::flipped)
+        }
+        (Capsule(capsule_a), Ball(radius_b)) => {
+            capsule_ball(pos_a, rot_a, capsule_a, pos_b, radius_b)
+        }
+        (Capsule(capsule_a), Box(size_b)) => {
+            capsule_obb(pos_a, rot_a, capsule_a, pos_b, rot_b, size_b)
+        }
+        (Capsule(capsule_a), Capsule(capsule_b)) => {
+            capsule_capsule(pos_a, rot_a, capsule_a, pos_b, rot_b, capsule_b)
+        }
+        (Capsule(capsule_a), Polygon(polygon_b)) => {
+            polygon_capsule(pos_b, rot_b, polygon_b, pos_a, rot_a, capsule_a).map(Contact::flipped)
+        }
+        (Polygon(polygon_a), Ball(radius_b)) => {
+            polygon_ball(pos_a, rot_a, polygon_a, pos_b, radius_b)
+        }
+        (Polygon(polygon_a), Box(size_b)) => {
+            polygon_obb(pos_a, rot_a, polygon_a, pos_b, rot_b, size_b)
+        }
+        (Polygon(polygon_a), Capsule(capsule_b)) => {
+            polygon_capsule(pos_a, rot_a, polygon_a, pos_b, rot_b, capsule_b)
+        }
+        (Polygon(polygon_a), Polygon(polygon_b)) => {
+            polygon_polygon(pos_a, rot_a, polygon_a, pos_b, rot_b, polygon_b)
+        }
+    }
+}
+
+// Helpers
+
+pub(super) fn rotate(v: Vector, angle: Scalar) -> Vector {
+    let (sin, cos) = angle.sin_cos();
+    Vector::new(v.x * cos - v.y * sin, v.x * sin + v.y * cos)
+}
+
+fn box_axes(angle: Scalar) -> [Vector; 2] {
+    [rotate(Vector::X, angle), rotate(Vector::Y, angle)]
+}
+
+/// Half the length of the projection of a box onto `axis`
+fn project_box(axes: [Vector; 2], half_extents: Vector, axis: Vector) -> Scalar {
+    half_extents.x * axes[0].dot(axis).abs() + half_extents.y * axes[1].dot(axis).abs()
+}
+
+fn box_vertices(pos: Vector, axes: [Vector; 2], half_extents: Vector) -> [Vector; 4] {
+    let x = axes[0] * half_extents.x;
+    let y = axes[1] * half_extents.y;
+    [pos - x - y, pos + x - y, pos + x + y, pos - x + y]
+}
+
+/// Average of the vertices furthest along `dir`
+fn deepest_point(vertices: &[Vector], dir: Vector) -> Vector {
+    let max_depth = vertices
+        .iter()
+        .map(|v| v.dot(dir))
+        .fold(Scalar::MIN, Scalar::max);
+    let mut sum = Vector::ZERO;
+    let mut count = scalar(0.);
+    for v in vertices {
+        if v.dot(dir) >= max_depth - scalar(CONTACT_POINT_TOLERANCE) {
+            sum += *v;
+            count += scalar(1.);
+        }
+    }
+    sum / count
+}
+
+/// Convex vertices in world space, in counter-clockwise order, rounded off by `radius`.
+/// A ball is a single vertex, a capsule two.
+struct Hull<'a> {
+    vertices: &'a [Vector],
+    radius: Scalar,
+}
+
+fn capsule_segment(pos: Vector, rot: Scalar, capsule: &CapsuleCollider) -> [Vector; 2] {
+    let axis = rotate(Vector::Y, rot) * capsule.half_height;
+    [pos - axis, pos + axis]
+}
+
+fn polygon_vertices(pos: Vector, rot: Scalar, polygon: &PolygonCollider) -> Vec<Vector> {
+    polygon
+        .vertices
+        .iter()
+        .map(|v| pos + rotate(*v, rot))
+        .collect()
+}
+
+/// Every edge of a hull, a single vertex is a zero length edge
+fn hull_edges(vertices: &[Vector]) -> impl Iterator<Item = (Vector, Vector)> + '_ {
+    let n = vertices.len();
+    (0..n).map(move |i| (vertices[i], vertices[(i + 1) % n]))
+}
+
+/// The edge normal of `reference` along which `incident` is the furthest away, and that distance.
+/// Negative distances are overlaps. Hulls without edges have no normals to test.
+fn max_separation(reference: &[Vector], incident: &[Vector]) -> Option<(Scalar, Vector)> {
+    let mut best: Option<(Scalar, Vector)> = None;
+    for (start, end) in hull_edges(reference) {
+        let edge = end - start;
+        if edge == Vector::ZERO {
+            continue;
+        }
+        // outward, since the vertices are counter-clockwise
+        let normal = Vector::new(edge.y, -edge.x).normalize();
+        let separation = incident
+            .iter()
+            .map(|v| (*v - start).dot(normal))
+            .fold(Scalar::MAX, Scalar::min);
+        if best.map_or(true, |(best_separation, _)| separation > best_separation) {
+            best = Some((separation, normal));
+        }
+    }
+    best
+}
+
+pub(super) fn closest_on_segment(point: Vector, start: Vector, end: Vector) -> Vector {
+    let edge = end - start;
+    let length_squared = edge.length_squared();
+    if length_squared == scalar(0.) {
+        return start;
+    }
+    let t = ((point - start).dot(edge) / length_squared)
+        .max(scalar(0.))
+        .min(scalar(1.));
+    start + edge * t
+}
+
+/// Closest points of two hulls that don't overlap, on a and on b
+fn closest_points(a: &[Vector], b: &[Vector]) -> (Vector, Vector) {
+    let mut best = (Scalar::MAX, Vector::ZERO, Vector::ZERO);
+    for &v in a {
+        for (start, end) in hull_edges(b) {
+            let closest = closest_on_segment(v, start, end);
+            let distance_squared = (closest - v).length_squared();
+            if distance_squared < best.0 {
+                best = (distance_squared, v, closest);
+            }
+        }
+    }
+    for &v in b {
+        for (start, end) in hull_edges(a) {
+            let closest = closest_on_segment(v, start, end);
+            let distance_squared = (closest - v).length_squared();
+            if distance_squared < best.0 {
+                best = (distance_squared, closest, v);
+            }
+        }
+    }
+    (best.1, best.2)
+}
+
+/// Rounded convex hull vs rounded convex hull.
+/// Overlapping vertices are separated along the axis of least penetration found by the separating axis theorem,
+/// otherwise only the rounded margins can overlap, along the line between the closest points.
+fn hull_hull(a: Hull, b: Hull) -> Option<Contact> {
+    let radius = a.radius + b.radius;
+    let best = match (
+        max_separation(a.vertices, b.vertices),
+        max_separation(b.vertices, a.vertices),
+    ) {
+        (Some((separation_a, normal_a)), Some((separation_b, normal_b))) => {
+            if separation_b > separation_a {
+                Some((separation_b, -normal_b, false))
+            } else {
+                Some((separation_a, normal_a, true))
+            }
+        }
+        (Some((separation, normal)), None) => Some((separation, normal, true)),
+        (None, Some((separation, normal))) => Some((separation, -normal, false)),
+        (None, None) => None,
+    };
+
+    // the edge normals are only enough to separate the hulls if one of them is a proper polygon,
+    // points and segments can also be separated along a line through their ends
+    let polygon = a.vertices.len() > 2 || b.vertices.len() > 2;
+    let vertices_overlap =
+        polygon && best.map_or(false, |(separation, ..)| separation <= scalar(0.));
+    if !vertices_overlap {
+        let (point_a, point_b) = closest_points(a.vertices, b.vertices);
+        let ab = point_b - point_a;
+        let distance_squared = ab.length_squared();
+        if distance_squared >= radius * radius {
+            return None;
+        }
+        if distance_squared > scalar(0.) {
+            let distance = distance_squared.sqrt();
+            let normal = ab / distance;
+            let penetration = radius - distance;
+            return Some(Contact {
+                penetration,
+                normal,
+                point: point_a + normal * (a.radius - penetration / scalar(2.)),
+            });
+        }
+        // crossing segments, which the separating axes can handle
+    }
+
+    let (separation, normal, axis_of_a) = best?;
+    let penetration = radius - separation;
+    // the contact point lies on the deepest vertices of the other hull
+    let point = if axis_of_a {
+        deepest_point(b.vertices, -normal) - normal * (b.radius - penetration / scalar(2.))
+    } else {
+        deepest_point(a.vertices, normal) + normal * (a.radius - penetration / scalar(2.))
+    };
+    Some(Contact {
+        penetration,
+        normal,
+        point,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::physics::math::{to_f32, to_vec2, vector};
+    use bevy::math::Vec2;
+
+    fn vec(x: f32, y: f32) -> Vector {
+        vector(Vec2::new(x, y))
+    }
 
     #[test]
     fn box_box_clear() {
-        assert!(box_box(Vec2::ZERO, Vec2::ONE, Vec2::new(1.1, 0.), Vec2::ONE).is_none());
-        assert!(box_box(Vec2::ZERO, Vec2::ONE, Vec2::new(-1.1, 0.), Vec2::ONE).is_none());
-        assert!(box_box(Vec2::ZERO, Vec2::ONE, Vec2::new(0., 1.1), Vec2::ONE).is_none());
-        assert!(box_box(Vec2::ZERO, Vec2::ONE, Vec2::new(0., -1.1), Vec2::ONE).is_none());
+        assert!(box_box(Vector::ZERO, Vector::ONE, vec(1.1, 0.), Vector::ONE).is_none());
+        assert!(box_box(Vector::ZERO, Vector::ONE, vec(-1.1, 0.), Vector::ONE).is_none());
+        assert!(box_box(Vector::ZERO, Vector::ONE, vec(0., 1.1), Vector::ONE).is_none());
+        assert!(box_box(Vector::ZERO, Vector::ONE, vec(0., -1.1), Vector::ONE).is_none());
     }
 
     #[test]
     fn box_box_intersection() {
-        assert!(box_box(Vec2::ZERO, Vec2::ONE, Vec2::ZERO, Vec2::ONE).is_some());
-        assert!(box_box(Vec2::ZERO, Vec2::ONE, Vec2::new(0.9, 0.9), Vec2::ONE).is_some());
-        assert!(box_box(Vec2::ZERO, Vec2::ONE, Vec2::new(-0.9, -0.9), Vec2::ONE).is_some());
+        assert!(box_box(Vector::ZERO, Vector::ONE, Vector::ZERO, Vector::ONE).is_some());
+        assert!(box_box(Vector::ZERO, Vector::ONE, vec(0.9, 0.9), Vector::ONE).is_some());
+        assert!(box_box(Vector::ZERO, Vector::ONE, vec(-0.9, -0.9), Vector::ONE).is_some());
     }
 
     #[test]
@@ -103,7 +673,9 @@ mod tests {
         let Contact {
             normal,
             penetration,
-        } = box_box(Vec2::ZERO, Vec2::ONE, Vec2::new(0.9, 0.), Vec2::ONE).unwrap();
+            ..
+        } = box_box(Vector::ZERO, Vector::ONE, vec(0.9, 0.), Vector::ONE).unwrap();
+        let (normal, penetration) = (to_vec2(normal), to_f32(penetration));
 
         assert!(normal.x > 0.999);
         assert!(normal.y < 0.001);
@@ -115,10 +687,361 @@ mod tests {
         let Contact {
             normal,
             penetration,
-        } = box_box(Vec2::ZERO, Vec2::ONE, Vec2::new(0., 0.9), Vec2::ONE).unwrap();
+            ..
+        } = box_box(Vector::ZERO, Vector::ONE, vec(0., 0.9), Vector::ONE).unwrap();
+        let (normal, penetration) = (to_vec2(normal), to_f32(penetration));
 
         assert!(normal.y > 0.999);
         assert!(normal.x < 0.001);
         assert!((penetration - 0.1).abs() < 0.001);
     }
+
+    #[test]
+    fn ball_box_clear() {
+        assert!(ball_box(Vector::ZERO, scalar(0.5), vec(1.1, 0.), Vector::ONE).is_none());
+        assert!(ball_box(Vector::ZERO, scalar(0.5), vec(-1.1, 0.), Vector::ONE).is_none());
+        assert!(ball_box(Vector::ZERO, scalar(0.5), vec(0., 1.1), Vector::ONE).is_none());
+        assert!(ball_box(Vector::ZERO, scalar(0.5), vec(0., -1.1), Vector::ONE).is_none());
+        // the aabbs overlap, but the ball misses the corner
+        assert!(ball_box(Vector::ZERO, scalar(0.5), vec(0.9, 0.9), Vector::ONE).is_none());
+    }
+
+    #[test]
+    fn ball_box_intersection() {
+        assert!(ball_box(Vector::ZERO, scalar(0.5), Vector::ZERO, Vector::ONE).is_some());
+        assert!(ball_box(Vector::ZERO, scalar(0.5), vec(0.8, 0.8), Vector::ONE).is_some());
+        assert!(ball_box(Vector::ZERO, scalar(0.5), vec(-0.8, -0.8), Vector::ONE).is_some());
+    }
+
+    #[test]
+    fn ball_box_contact_horizontal() {
+        let Contact {
+            normal,
+            penetration,
+            ..
+        } = ball_box(Vector::ZERO, scalar(0.5), vec(0.9, 0.), Vector::ONE).unwrap();
+        let (normal, penetration) = (to_vec2(normal), to_f32(penetration));
+
+        assert!(normal.x > 0.999);
+        assert!(normal.y < 0.001);
+        assert!((penetration - 0.1).abs() < 0.001);
+    }
+
+    #[test]
+    fn ball_box_contact_vertical() {
+        let Contact {
+            normal,
+            penetration,
+            ..
+        } = ball_box(Vector::ZERO, scalar(0.5), vec(0., 0.9), Vector::ONE).unwrap();
+        let (normal, penetration) = (to_vec2(normal), to_f32(penetration));
+
+        assert!(normal.y > 0.999);
+        assert!(normal.x < 0.001);
+        assert!((penetration - 0.1).abs() < 0.001);
+    }
+
+    #[test]
+    fn ball_box_contact_corner() {
+        let Contact {
+            normal,
+            penetration,
+            ..
+        } = ball_box(Vector::ZERO, scalar(0.5), vec(0.8, 0.8), Vector::ONE).unwrap();
+        let (normal, penetration) = (to_vec2(normal), to_f32(penetration));
+
+        let diagonal = std::f32::consts::FRAC_1_SQRT_2;
+        assert!((normal.x - diagonal).abs() < 0.001);
+        assert!((normal.y - diagonal).abs() < 0.001);
+        assert!((penetration - (0.5 - 0.3 * std::f32::consts::SQRT_2)).abs() < 0.001);
+    }
+
+    #[test]
+    fn obb_obb_unrotated_matches_box_box() {
+        let Contact {
+            normal,
+            penetration,
+            ..
+        } = obb_obb(
+            Vector::ZERO,
+            scalar(0.),
+            Vector::ONE,
+            vec(0.9, 0.),
+            scalar(0.),
+            Vector::ONE,
+        )
+        .unwrap();
+        let (normal, penetration) = (to_vec2(normal), to_f32(penetration));
+
+        assert!(normal.x > 0.999);
+        assert!((penetration - 0.1).abs() < 0.001);
+    }
+
+    #[test]
+    fn obb_obb_rotated_clear() {
+        // a diamond next to a box, the aabbs would overlap, but the boxes don't
+        let rot = std::f32::consts::FRAC_PI_4;
+        assert!(obb_obb(
+            Vector::ZERO,
+            scalar(0.),
+            Vector::ONE,
+            vec(1.1, 1.1),
+            scalar(rot),
+            Vector::ONE,
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn obb_obb_rotated_corner() {
+        // diamond resting its lower corner on a box
+        let rot = std::f32::consts::FRAC_PI_4;
+        let half_diagonal = std::f32::consts::SQRT_2 / 2.;
+        let Contact {
+            normal,
+            penetration,
+            point,
+        } = obb_obb(
+            Vector::ZERO,
+            scalar(0.),
+            Vector::ONE,
+            vec(0., 0.5 + half_diagonal - 0.1),
+            scalar(rot),
+            Vector::ONE,
+        )
+        .unwrap();
+        let (normal, penetration, point) = (to_vec2(normal), to_f32(penetration), to_vec2(point));
+
+        assert!(normal.y > 0.999);
+        assert!((penetration - 0.1).abs() < 0.001);
+        assert!(point.x.abs() < 0.001);
+        assert!((point.y - 0.45).abs() < 0.001);
+    }
+
+    #[test]
+    fn ball_obb_rotated() {
+        // ball above a diamond, touches the upper corner
+        let rot = std::f32::consts::FRAC_PI_4;
+        let half_diagonal = std::f32::consts::SQRT_2 / 2.;
+        let Contact {
+            normal,
+            penetration,
+            ..
+        } = ball_obb(
+            vec(0., half_diagonal + 0.4),
+            scalar(0.5),
+            Vector::ZERO,
+            scalar(rot),
+            Vector::ONE,
+        )
+        .unwrap();
+        let (normal, penetration) = (to_vec2(normal), to_f32(penetration));
+
+        assert!(normal.y < -0.999);
+        assert!((penetration - 0.1).abs() < 0.001);
+    }
+
+    fn capsule(half_height: f32, radius: f32) -> CapsuleCollider {
+        CapsuleCollider {
+            half_height: scalar(half_height),
+            radius: scalar(radius),
+        }
+    }
+
+    fn polygon(vertices: &[(f32, f32)]) -> PolygonCollider {
+        PolygonCollider {
+            vertices: vertices.iter().map(|&(x, y)| vec(x, y)).collect(),
+        }
+    }
+
+    #[test]
+    fn capsule_obb_standing() {
+        let capsule = capsule(0.5, 0.5);
+        assert!(capsule_obb(
+            vec(0., 1.6),
+            scalar(0.),
+            &capsule,
+            Vector::ZERO,
+            scalar(0.),
+            Vector::ONE
+        )
+        .is_none());
+
+        let Contact {
+            normal,
+            penetration,
+            ..
+        } = capsule_obb(
+            vec(0., 1.4),
+            scalar(0.),
+            &capsule,
+            Vector::ZERO,
+            scalar(0.),
+            Vector::ONE,
+        )
+        .unwrap();
+        let (normal, penetration) = (to_vec2(normal), to_f32(penetration));
+
+        assert!(normal.y < -0.999);
+        assert!((penetration - 0.1).abs() < 0.001);
+    }
+
+    #[test]
+    fn capsule_capsule_side_by_side() {
+        let capsule = capsule(0.5, 0.25);
+        let Contact {
+            normal,
+            penetration,
+            ..
+        } = capsule_capsule(
+            Vector::ZERO,
+            scalar(0.),
+            &capsule,
+            vec(0.4, 0.2),
+            scalar(0.),
+            &capsule,
+        )
+        .unwrap();
+        let (normal, penetration) = (to_vec2(normal), to_f32(penetration));
+
+        assert!(normal.x > 0.999);
+        assert!((penetration - 0.1).abs() < 0.001);
+    }
+
+    #[test]
+    fn capsule_capsule_stacked() {
+        // the segments are on the same line, so only their ends can separate them
+        let capsule = capsule(0.5, 0.25);
+        assert!(capsule_capsule(
+            Vector::ZERO,
+            scalar(0.),
+            &capsule,
+            vec(0., 1.6),
+            scalar(0.),
+            &capsule
+        )
+        .is_none());
+
+        let Contact {
+            normal,
+            penetration,
+            ..
+        } = capsule_capsule(
+            Vector::ZERO,
+            scalar(0.),
+            &capsule,
+            vec(0., 1.4),
+            scalar(0.),
+            &capsule,
+        )
+        .unwrap();
+        let (normal, penetration) = (to_vec2(normal), to_f32(penetration));
+
+        assert!(normal.y > 0.999);
+        assert!((penetration - 0.1).abs() < 0.001);
+    }
+
+    #[test]
+    fn capsule_capsule_crossed() {
+        // a horizontal capsule through the middle of an upright one
+        let capsule = capsule(0.5, 0.25);
+        let Contact { penetration, .. } = capsule_capsule(
+            Vector::ZERO,
+            scalar(0.),
+            &capsule,
+            vec(0.4, 0.),
+            scalar(std::f32::consts::FRAC_PI_2),
+            &capsule,
+        )
+        .unwrap();
+        assert!(to_f32(penetration) > 0.5);
+    }
+
+    #[test]
+    fn polygon_ball_ramp() {
+        let ramp = polygon(&[(-1., -1.), (1., -1.), (1., 1.)]);
+        let up_left = Vec2::new(-1., 1.).normalize();
+        let Contact {
+            normal,
+            penetration,
+            ..
+        } = polygon_ball(
+            Vector::ZERO,
+            scalar(0.),
+            &ramp,
+            vector(up_left * 0.4),
+            scalar(0.5),
+        )
+        .unwrap();
+        let (normal, penetration) = (to_vec2(normal), to_f32(penetration));
+
+        assert!((normal - up_left).length() < 0.001);
+        assert!((penetration - 0.1).abs() < 0.001);
+
+        assert!(polygon_ball(
+            Vector::ZERO,
+            scalar(0.),
+            &ramp,
+            vector(up_left * 0.6),
+            scalar(0.5)
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn polygon_polygon_matches_box_box() {
+        let square = polygon(&[(-0.5, -0.5), (0.5, -0.5), (0.5, 0.5), (-0.5, 0.5)]);
+        let Contact {
+            normal,
+            penetration,
+            ..
+        } = polygon_polygon(
+            Vector::ZERO,
+            scalar(0.),
+            &square,
+            vec(0.9, 0.),
+            scalar(0.),
+            &square,
+        )
+        .unwrap();
+        let (normal, penetration) = (to_vec2(normal), to_f32(penetration));
+
+        assert!(normal.x > 0.999);
+        assert!((penetration - 0.1).abs() < 0.001);
+
+        assert!(polygon_polygon(
+            Vector::ZERO,
+            scalar(0.),
+            &square,
+            vec(1.1, 0.),
+            scalar(0.),
+            &square
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn shape_shape_flips_normals() {
+        let capsule = capsule(0.5, 0.5);
+        let Contact { normal, .. } = shape_shape(
+            Vector::ZERO,
+            scalar(0.),
+            Shape::Box(Vector::ONE),
+            vec(0., 1.4),
+            scalar(0.),
+            Shape::Capsule(&capsule),
+        )
+        .unwrap();
+        assert!(to_vec2(normal).y > 0.999);
+    }
+
+    #[test]
+    fn shape_reach() {
+        let capsule = capsule(0.5, 0.25);
+        assert!(
+            (to_f32(Shape::Capsule(&capsule).reach(scalar(0.), Vector::Y)) - 0.75).abs() < 0.001
+        );
+        let ramp = polygon(&[(-1., -1.), (1., -1.), (1., 1.)]);
+        assert!((to_f32(Shape::Polygon(&ramp).reach(scalar(0.), -Vector::X)) - 1.).abs() < 0.001);
+    }
 }
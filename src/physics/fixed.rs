@@ -0,0 +1,379 @@
+//! Q32.32 fixed point numbers, used instead of `f32` when the `fixed-point` feature is enabled.
+//! All operations are plain integer math, so they give the same results on every platform.
+//! Operations saturate instead of overflowing, and division by zero saturates as well.
+
+use bevy::{prelude::*, reflect::FromReflect};
+use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign};
+
+const FRAC_BITS: u32 = 32;
+const ONE_RAW: i64 = 1 << FRAC_BITS;
+
+#[derive(Reflect, FromReflect, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[reflect(Hash, PartialEq)]
+pub struct Fixed(i64);
+
+impl Fixed {
+    pub const ZERO: Self = Self(0);
+    pub const ONE: Self = Self(ONE_RAW);
+    pub const MIN: Self = Self(i64::MIN);
+    pub const MAX: Self = Self(i64::MAX);
+    pub const PI: Self = Self(13493037705);
+    pub const FRAC_PI_2: Self = Self(6746518852);
+    pub const TAU: Self = Self(26986075409);
+
+    pub const fn from_bits(bits: i64) -> Self {
+        Self(bits)
+    }
+
+    pub const fn to_bits(self) -> i64 {
+        self.0
+    }
+
+    pub fn to_le_bytes(self) -> [u8; 8] {
+        self.0.to_le_bytes()
+    }
+
+    pub fn abs(self) -> Self {
+        Self(self.0.saturating_abs())
+    }
+
+    /// Like `f32::signum`, zero counts as positive
+    pub fn signum(self) -> Self {
+        if self.0 >= 0 {
+            Self::ONE
+        } else {
+            -Self::ONE
+        }
+    }
+
+    /// Negative numbers have no square root, they return zero instead of NaN
+    pub fn sqrt(self) -> Self {
+        if self.0 <= 0 {
+            return Self::ZERO;
+        }
+        // sqrt(raw / 2^32) * 2^32 = sqrt(raw * 2^32)
+        Self(isqrt((self.0 as u128) << FRAC_BITS) as i64)
+    }
+
+    pub fn sin(self) -> Self {
+        // wrap into [-pi, pi]
+        let mut x = Self(self.0.rem_euclid(Self::TAU.0));
+        if x > Self::PI {
+            x -= Self::TAU;
+        }
+        // mirror into [-pi/2, pi/2], where the taylor series converges quickly
+        if x > Self::FRAC_PI_2 {
+            x = Self::PI - x;
+        } else if x < -Self::FRAC_PI_2 {
+            x = -Self::PI - x;
+        }
+        // x - x^3/3! + x^5/5! - ..., in horner form
+        let x2 = x * x;
+        let mut result = Self::ONE;
+        for denominator in [110, 72, 42, 20, 6] {
+            result = Self::ONE - Self((x2 * result).0 / denominator);
+        }
+        result * x
+    }
+
+    pub fn cos(self) -> Self {
+        (self + Self::FRAC_PI_2).sin()
+    }
+
+    pub fn sin_cos(self) -> (Self, Self) {
+        (self.sin(), self.cos())
+    }
+}
+
+/// Integer square root, rounding down
+fn isqrt(n: u128) -> u128 {
+    let mut rest = n;
+    let mut result = 0;
+    let mut bit = 1 << 126;
+    while bit > n {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if rest >= result + bit {
+            rest -= result + bit;
+            result = (result >> 1) + bit;
+        } else {
+            result >>= 1;
+        }
+        bit >>= 2;
+    }
+    result
+}
+
+fn saturate(value: i128) -> Fixed {
+    Fixed(value.clamp(i64::MIN as i128, i64::MAX as i128) as i64)
+}
+
+impl std::fmt::Debug for Fixed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", f32::from(*self))
+    }
+}
+
+impl From<f32> for Fixed {
+    fn from(value: f32) -> Self {
+        // both the conversion to f64 and the multiplication by a power of two are exact
+        Self((value as f64 * ONE_RAW as f64) as i64)
+    }
+}
+
+impl From<Fixed> for f32 {
+    fn from(value: Fixed) -> Self {
+        (value.0 as f64 / ONE_RAW as f64) as f32
+    }
+}
+
+impl Add for Fixed {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self {
+        Self(self.0.saturating_add(rhs.0))
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self {
+        Self(self.0.saturating_sub(rhs.0))
+    }
+}
+
+impl Mul for Fixed {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self {
+        saturate((self.0 as i128 * rhs.0 as i128) >> FRAC_BITS)
+    }
+}
+
+impl Div for Fixed {
+    type Output = Self;
+    fn div(self, rhs: Self) -> Self {
+        if rhs.0 == 0 {
+            return match self.0.signum() {
+                1 => Self::MAX,
+                -1 => Self::MIN,
+                _ => Self::ZERO,
+            };
+        }
+        saturate(((self.0 as i128) << FRAC_BITS) / rhs.0 as i128)
+    }
+}
+
+impl Neg for Fixed {
+    type Output = Self;
+    fn neg(self) -> Self {
+        Self(self.0.saturating_neg())
+    }
+}
+
+impl AddAssign for Fixed {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
+impl SubAssign for Fixed {
+    fn sub_assign(&mut self, rhs: Self) {
+        *self = *self - rhs;
+    }
+}
+
+impl MulAssign for Fixed {
+    fn mul_assign(&mut self, rhs: Self) {
+        *self = *self * rhs;
+    }
+}
+
+impl DivAssign for Fixed {
+    fn div_assign(&mut self, rhs: Self) {
+        *self = *self / rhs;
+    }
+}
+
+/// Fixed point counterpart of `Vec2`, only has the parts of its api the physics module uses
+#[derive(Reflect, FromReflect, Default, Clone, Copy, Debug, PartialEq)]
+pub struct FixedVec2 {
+    pub x: Fixed,
+    pub y: Fixed,
+}
+
+impl FixedVec2 {
+    pub const ZERO: Self = Self::new(Fixed::ZERO, Fixed::ZERO);
+    pub const ONE: Self = Self::new(Fixed::ONE, Fixed::ONE);
+    pub const X: Self = Self::new(Fixed::ONE, Fixed::ZERO);
+    pub const Y: Self = Self::new(Fixed::ZERO, Fixed::ONE);
+
+    pub const fn new(x: Fixed, y: Fixed) -> Self {
+        Self { x, y }
+    }
+
+    pub fn splat(value: Fixed) -> Self {
+        Self::new(value, value)
+    }
+
+    pub fn dot(self, other: Self) -> Fixed {
+        self.x * other.x + self.y * other.y
+    }
+
+    /// Rotated by 90 degrees counter-clockwise
+    pub fn perp(self) -> Self {
+        Self::new(-self.y, self.x)
+    }
+
+    /// The 2d cross product
+    pub fn perp_dot(self, other: Self) -> Fixed {
+        self.x * other.y - self.y * other.x
+    }
+
+    pub fn length_squared(self) -> Fixed {
+        self.dot(self)
+    }
+
+    pub fn length(self) -> Fixed {
+        self.length_squared().sqrt()
+    }
+
+    pub fn normalize(self) -> Self {
+        self / self.length()
+    }
+
+    pub fn abs(self) -> Self {
+        Self::new(self.x.abs(), self.y.abs())
+    }
+
+    pub fn signum(self) -> Self {
+        Self::new(self.x.signum(), self.y.signum())
+    }
+
+    pub fn min(self, other: Self) -> Self {
+        Self::new(self.x.min(other.x), self.y.min(other.y))
+    }
+
+    pub fn max(self, other: Self) -> Self {
+        Self::new(self.x.max(other.x), self.y.max(other.y))
+    }
+}
+
+impl From<Vec2> for FixedVec2 {
+    fn from(value: Vec2) -> Self {
+        Self::new(value.x.into(), value.y.into())
+    }
+}
+
+impl From<FixedVec2> for Vec2 {
+    fn from(value: FixedVec2) -> Self {
+        Vec2::new(value.x.into(), value.y.into())
+    }
+}
+
+impl Add for FixedVec2 {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self {
+        Self::new(self.x + rhs.x, self.y + rhs.y)
+    }
+}
+
+impl Sub for FixedVec2 {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self {
+        Self::new(self.x - rhs.x, self.y - rhs.y)
+    }
+}
+
+impl Neg for FixedVec2 {
+    type Output = Self;
+    fn neg(self) -> Self {
+        Self::new(-self.x, -self.y)
+    }
+}
+
+impl Mul<Fixed> for FixedVec2 {
+    type Output = Self;
+    fn mul(self, rhs: Fixed) -> Self {
+        Self::new(self.x * rhs, self.y * rhs)
+    }
+}
+
+impl Mul<FixedVec2> for Fixed {
+    type Output = FixedVec2;
+    fn mul(self, rhs: FixedVec2) -> FixedVec2 {
+        rhs * self
+    }
+}
+
+impl Div<Fixed> for FixedVec2 {
+    type Output = Self;
+    fn div(self, rhs: Fixed) -> Self {
+        Self::new(self.x / rhs, self.y / rhs)
+    }
+}
+
+impl AddAssign for FixedVec2 {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
+impl SubAssign for FixedVec2 {
+    fn sub_assign(&mut self, rhs: Self) {
+        *self = *self - rhs;
+    }
+}
+
+impl MulAssign<Fixed> for FixedVec2 {
+    fn mul_assign(&mut self, rhs: Fixed) {
+        *self = *self * rhs;
+    }
+}
+
+impl DivAssign<Fixed> for FixedVec2 {
+    fn div_assign(&mut self, rhs: Fixed) {
+        *self = *self / rhs;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Fixed, b: f32) -> bool {
+        (f32::from(a) - b).abs() < 0.0001
+    }
+
+    #[test]
+    fn arithmetic() {
+        let a = Fixed::from(1.5);
+        let b = Fixed::from(-0.25);
+        assert!(close(a + b, 1.25));
+        assert!(close(a - b, 1.75));
+        assert!(close(a * b, -0.375));
+        assert!(close(a / b, -6.));
+    }
+
+    #[test]
+    fn sqrt() {
+        assert!(close(Fixed::from(2.).sqrt(), std::f32::consts::SQRT_2));
+        assert!(close(Fixed::from(10000.).sqrt(), 100.));
+        assert_eq!(Fixed::from(-1.).sqrt(), Fixed::ZERO);
+    }
+
+    #[test]
+    fn sin_cos() {
+        for i in -20..20 {
+            let angle = i as f32 * 0.4;
+            let (sin, cos) = Fixed::from(angle).sin_cos();
+            assert!(close(sin, angle.sin()));
+            assert!(close(cos, angle.cos()));
+        }
+    }
+
+    #[test]
+    fn divide_by_zero_saturates() {
+        assert_eq!(Fixed::ONE / Fixed::ZERO, Fixed::MAX);
+        assert_eq!(-Fixed::ONE / Fixed::ZERO, Fixed::MIN);
+    }
+}
@@ -0,0 +1,574 @@
+//! XPBD position constraints between two bodies, other than contacts.
+//! A joint is a component on an entity of its own that links `entity_a` to `entity_b`.
+//! Bodies without [`Mass`] don't move, so they make good anchors for swinging hazards.
+//!
+//! The joints store plain `Entity` ids, and a body that GGRS respawns while rolling back gets a
+//! new one, which the joint would silently skip from then on. So jointed bodies must never be
+//! despawned while a rollback could still bring them back.
+
+use bevy::prelude::*;
+use bevy_ggrs::Rollback;
+
+use super::{
+    components::*,
+    contact::rotate,
+    math::{scalar, wrap_angle, Scalar, Vector},
+    resources::PhysicsConfig,
+    systems::{body_key, BodyKey},
+    utils::QueryExt,
+};
+
+/// What the joints point to by default, only there so they can be registered for rollback
+fn no_entity() -> Entity {
+    Entity::new(u32::MAX)
+}
+
+/// Keeps an anchor on each body between `min_length` and `max_length` apart.
+/// The anchors are relative to the bodies' positions and rotate with them.
+#[derive(Component, Reflect, Debug, Clone, Copy)]
+#[reflect(Component)]
+pub struct DistanceJoint {
+    pub entity_a: Entity,
+    pub entity_b: Entity,
+    pub local_anchor_a: Vector,
+    pub local_anchor_b: Vector,
+    pub min_length: Scalar,
+    pub max_length: Scalar,
+    /// Inverse of the stiffness, zero makes the joint rigid
+    pub compliance: Scalar,
+}
+
+impl DistanceJoint {
+    /// A rod that keeps the bodies exactly `length` apart
+    pub fn new(entity_a: Entity, entity_b: Entity, length: Scalar) -> Self {
+        Self {
+            entity_a,
+            entity_b,
+            local_anchor_a: Vector::ZERO,
+            local_anchor_b: Vector::ZERO,
+            min_length: length,
+            max_length: length,
+            compliance: scalar(0.),
+        }
+    }
+
+    /// A rope that only pulls the bodies together once it is stretched to `length`
+    pub fn rope(entity_a: Entity, entity_b: Entity, length: Scalar) -> Self {
+        Self {
+            min_length: scalar(0.),
+            ..Self::new(entity_a, entity_b, length)
+        }
+    }
+
+    pub fn with_anchors(self, local_anchor_a: Vector, local_anchor_b: Vector) -> Self {
+        Self {
+            local_anchor_a,
+            local_anchor_b,
+            ..self
+        }
+    }
+
+    pub fn with_compliance(self, compliance: Scalar) -> Self {
+        Self { compliance, ..self }
+    }
+}
+
+impl Default for DistanceJoint {
+    fn default() -> Self {
+        Self::new(no_entity(), no_entity(), scalar(0.))
+    }
+}
+
+/// Pins an anchor on each body together, the bodies can still rotate around it
+#[derive(Component, Reflect, Debug, Clone, Copy)]
+#[reflect(Component)]
+pub struct RevoluteJoint {
+    pub entity_a: Entity,
+    pub entity_b: Entity,
+    pub local_anchor_a: Vector,
+    pub local_anchor_b: Vector,
+    /// Inverse of the stiffness, zero makes the joint rigid
+    pub compliance: Scalar,
+}
+
+impl RevoluteJoint {
+    pub fn new(entity_a: Entity, entity_b: Entity) -> Self {
+        Self {
+            entity_a,
+            entity_b,
+            local_anchor_a: Vector::ZERO,
+            local_anchor_b: Vector::ZERO,
+            compliance: scalar(0.),
+        }
+    }
+
+    pub fn with_anchors(self, local_anchor_a: Vector, local_anchor_b: Vector) -> Self {
+        Self {
+            local_anchor_a,
+            local_anchor_b,
+            ..self
+        }
+    }
+
+    pub fn with_compliance(self, compliance: Scalar) -> Self {
+        Self { compliance, ..self }
+    }
+}
+
+impl Default for RevoluteJoint {
+    fn default() -> Self {
+        Self::new(no_entity(), no_entity())
+    }
+}
+
+/// Lets the anchor of body b slide along `axis` through the anchor of body a,
+/// between `min_offset` and `max_offset`, and keeps the rotation of b relative to a at `rest_angle`
+#[derive(Component, Reflect, Debug, Clone, Copy)]
+#[reflect(Component)]
+pub struct PrismaticJoint {
+    pub entity_a: Entity,
+    pub entity_b: Entity,
+    pub local_anchor_a: Vector,
+    pub local_anchor_b: Vector,
+    /// Relative to body a and rotates with it, needs to be normalized
+    pub axis: Vector,
+    pub min_offset: Scalar,
+    pub max_offset: Scalar,
+    /// Rotation of b minus the rotation of a that the joint keeps, e.g. what it was when they were linked
+    pub rest_angle: Scalar,
+    /// Inverse of the stiffness, zero makes the joint rigid
+    pub compliance: Scalar,
+}
+
+impl PrismaticJoint {
+    /// Slides freely along all of `axis`
+    pub fn new(entity_a: Entity, entity_b: Entity, axis: Vector) -> Self {
+        Self {
+            entity_a,
+            entity_b,
+            local_anchor_a: Vector::ZERO,
+            local_anchor_b: Vector::ZERO,
+            axis,
+            min_offset: Scalar::MIN,
+            max_offset: Scalar::MAX,
+            rest_angle: scalar(0.),
+            compliance: scalar(0.),
+        }
+    }
+
+    pub fn with_limits(self, min_offset: Scalar, max_offset: Scalar) -> Self {
+        Self {
+            min_offset,
+            max_offset,
+            ..self
+        }
+    }
+
+    pub fn with_anchors(self, local_anchor_a: Vector, local_anchor_b: Vector) -> Self {
+        Self {
+            local_anchor_a,
+            local_anchor_b,
+            ..self
+        }
+    }
+
+    pub fn with_rest_angle(self, rest_angle: Scalar) -> Self {
+        Self { rest_angle, ..self }
+    }
+
+    pub fn with_compliance(self, compliance: Scalar) -> Self {
+        Self { compliance, ..self }
+    }
+}
+
+impl Default for PrismaticJoint {
+    fn default() -> Self {
+        Self::new(no_entity(), no_entity(), Vector::X)
+    }
+}
+
+/// The parts of a body the joints need
+pub(super) struct JointBody<'a> {
+    pos: Mut<'a, Pos>,
+    rot: Option<Mut<'a, Rot>>,
+    inv_mass: Scalar,
+    inv_inertia: Scalar,
+}
+
+impl<'a> JointBody<'a> {
+    fn new(
+        pos: Mut<'a, Pos>,
+        rot: Option<Mut<'a, Rot>>,
+        mass: Option<&Mass>,
+        inv_inertia: Option<&InvInertia>,
+    ) -> Self {
+        // bodies without a mass don't move, bodies without a rotation don't rotate
+        let inv_inertia = match (&rot, mass, inv_inertia) {
+            (Some(_), Some(_), Some(inv_inertia)) => inv_inertia.0,
+            _ => scalar(0.),
+        };
+        Self {
+            pos,
+            rot,
+            inv_mass: mass.map_or(scalar(0.), |mass| scalar(1.) / mass.0),
+            inv_inertia,
+        }
+    }
+
+    fn rot(&self) -> Scalar {
+        self.rot.as_ref().map_or(scalar(0.), |rot| rot.0)
+    }
+
+    /// Offset of an anchor from the position of the body, in world space
+    fn anchor(&self, local_anchor: Vector) -> Vector {
+        rotate(local_anchor, self.rot())
+    }
+
+    fn inverse_mass(&self, r: Vector, n: Vector) -> Scalar {
+        let rn = r.perp_dot(n);
+        self.inv_mass + self.inv_inertia * rn * rn
+    }
+
+    fn apply_impulse(&mut self, impulse: Vector, r: Vector) {
+        self.pos.0 += impulse * self.inv_mass;
+        if let Some(rot) = self.rot.as_mut() {
+            rot.0 += self.inv_inertia * r.perp_dot(impulse);
+        }
+    }
+
+    fn apply_angular_impulse(&mut self, impulse: Scalar) {
+        if let Some(rot) = self.rot.as_mut() {
+            rot.0 += self.inv_inertia * impulse;
+        }
+    }
+}
+
+/// Lets [`solve_joints`] handle every kind of joint the same way
+pub(super) trait Joint: Component {
+    fn entities(&self) -> (Entity, Entity);
+
+    fn compliance(&self) -> Scalar;
+
+    /// Moves both bodies towards satisfying the joint.
+    /// `alpha` is the compliance scaled to the substep.
+    fn constrain(&self, a: &mut JointBody, b: &mut JointBody, alpha: Scalar);
+}
+
+impl Joint for DistanceJoint {
+    fn entities(&self) -> (Entity, Entity) {
+        (self.entity_a, self.entity_b)
+    }
+
+    fn compliance(&self) -> Scalar {
+        self.compliance
+    }
+
+    fn constrain(&self, a: &mut JointBody, b: &mut JointBody, alpha: Scalar) {
+        let (r_a, r_b) = (a.anchor(self.local_anchor_a), b.anchor(self.local_anchor_b));
+        let delta = b.pos.0 + r_b - (a.pos.0 + r_a);
+        let length = delta.length();
+        if length <= scalar(0.) {
+            return; // no direction to push in
+        }
+        let target = length.max(self.min_length).min(self.max_length);
+        let error = delta * ((length - target) / length);
+        constrain_offset(a, r_a, b, r_b, error, alpha);
+    }
+}
+
+impl Joint for RevoluteJoint {
+    fn entities(&self) -> (Entity, Entity) {
+        (self.entity_a, self.entity_b)
+    }
+
+    fn compliance(&self) -> Scalar {
+        self.compliance
+    }
+
+    fn constrain(&self, a: &mut JointBody, b: &mut JointBody, alpha: Scalar) {
+        let (r_a, r_b) = (a.anchor(self.local_anchor_a), b.anchor(self.local_anchor_b));
+        let error = b.pos.0 + r_b - (a.pos.0 + r_a);
+        constrain_offset(a, r_a, b, r_b, error, alpha);
+    }
+}
+
+impl Joint for PrismaticJoint {
+    fn entities(&self) -> (Entity, Entity) {
+        (self.entity_a, self.entity_b)
+    }
+
+    fn compliance(&self) -> Scalar {
+        self.compliance
+    }
+
+    fn constrain(&self, a: &mut JointBody, b: &mut JointBody, alpha: Scalar) {
+        // rotations keep counting past a full turn, which doesn't make them any further apart
+        let angle_error = wrap_angle(b.rot() - a.rot() - self.rest_angle);
+        constrain_angle(a, b, angle_error, alpha);
+
+        let (r_a, r_b) = (a.anchor(self.local_anchor_a), b.anchor(self.local_anchor_b));
+        let delta = b.pos.0 + r_b - (a.pos.0 + r_a);
+        let axis = rotate(self.axis, a.rot());
+        let offset = delta.dot(axis).max(self.min_offset).min(self.max_offset);
+        let error = delta - axis * offset;
+        constrain_offset(a, r_a, b, r_b, error, alpha);
+    }
+}
+
+/// Pulls the anchors at `r_a` and `r_b` together, closing the gap `error` that points from a to b
+fn constrain_offset(
+    a: &mut JointBody,
+    r_a: Vector,
+    b: &mut JointBody,
+    r_b: Vector,
+    error: Vector,
+    alpha: Scalar,
+) {
+    let distance = error.length();
+    if distance <= scalar(0.) {
+        return;
+    }
+    let n = error / distance;
+    let w = a.inverse_mass(r_a, n) + b.inverse_mass(r_b, n);
+    if w <= scalar(0.) {
+        return; // both bodies are static
+    }
+    let impulse = n * (distance / (w + alpha));
+    a.apply_impulse(impulse, r_a);
+    b.apply_impulse(-impulse, r_b);
+}
+
+/// Rotates the bodies so the rotation of b minus the rotation of a shrinks by `error`
+fn constrain_angle(a: &mut JointBody, b: &mut JointBody, error: Scalar, alpha: Scalar) {
+    let w = a.inv_inertia + b.inv_inertia;
+    if w <= scalar(0.) {
+        return;
+    }
+    let impulse = error / (w + alpha);
+    a.apply_angular_impulse(impulse);
+    b.apply_angular_impulse(-impulse);
+}
+
+/// Wakes up the sleeping bodies linked to an awake or kinematic one, before the broadphase,
+/// the same as a collision pair would
+pub(super) fn wake_up_joints<J: Joint>(
+    joints: Query<&J>,
+    bodies: Query<(Option<&Mass>, Option<&Kinematic>)>,
+    mut sleep_states: Query<&mut SleepState>,
+    // kept around to avoid allocating every frame
+    mut to_wake: Local<Vec<Entity>>,
+) {
+    debug!("wake_up_joints");
+    let is_active = |entity| {
+        let sleeping = sleep_states
+            .get_component::<SleepState>(entity)
+            .map_or(false, |sleep| sleep.is_sleeping());
+        bodies.get(entity).map_or(false, |(mass, kinematic)| {
+            (mass.is_some() && !sleeping) || kinematic.is_some()
+        })
+    };
+    // collected first, so the joints don't wake each other up in whatever order the query has
+    to_wake.clear();
+    for joint in joints.iter() {
+        let (entity_a, entity_b) = joint.entities();
+        if is_active(entity_a) || is_active(entity_b) {
+            to_wake.extend([entity_a, entity_b]);
+        }
+    }
+    for &entity in to_wake.iter() {
+        if let Ok(mut sleep) = sleep_states.get_mut(entity) {
+            if sleep.is_sleeping() {
+                sleep.wake_up();
+            }
+        }
+    }
+}
+
+/// Solves every joint of type `J` once per substep, in rollback id order so all peers agree
+pub(super) fn solve_joints<J: Joint>(
+    joints: Query<(Entity, &J, Option<&Rollback>)>,
+    mut bodies: Query<(
+        &mut Pos,
+        Option<&mut Rot>,
+        Option<&Mass>,
+        Option<&InvInertia>,
+    )>,
+    config: Res<PhysicsConfig>,
+    // kept around to avoid allocating every frame
+    mut order: Local<Vec<(BodyKey, Entity)>>,
+) {
+    debug!("  solve_joints");
+    let sub_dt = config.sub_dt();
+    order.clear();
+    order.extend(
+        joints
+            .iter()
+            .map(|(entity, _, rollback)| (body_key(entity, rollback), entity)),
+    );
+    order.sort_unstable_by_key(|&(key, _)| key);
+
+    for &(_, entity) in order.iter() {
+        let (_, joint, _) = joints.get(entity).unwrap();
+        let (entity_a, entity_b) = joint.entities();
+        // one of the bodies may have been despawned
+        let ((pos_a, rot_a, mass_a, inv_inertia_a), (pos_b, rot_b, mass_b, inv_inertia_b)) =
+            match bodies.get_pair_mut(entity_a, entity_b) {
+                Ok(pair) => pair,
+                Err(_) => continue,
+            };
+        let mut a = JointBody::new(pos_a, rot_a, mass_a, inv_inertia_a);
+        let mut b = JointBody::new(pos_b, rot_b, mass_b, inv_inertia_b);
+        joint.constrain(&mut a, &mut b, joint.compliance() / (sub_dt * sub_dt));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rope_only_pulls() {
+        let mut world = World::default();
+        let anchor = world.spawn().insert(Pos(Vector::ZERO)).id();
+        let weight = world
+            .spawn()
+            .insert(Pos(Vector::new(scalar(0.), scalar(-1.))))
+            .insert(Mass::default())
+            .id();
+        let slack = DistanceJoint::rope(anchor, weight, scalar(2.));
+        let taut = DistanceJoint::rope(anchor, weight, scalar(0.5));
+        world.spawn().insert(slack);
+        world.insert_resource(PhysicsConfig::default());
+
+        let mut stage = SystemStage::single(solve_joints::<DistanceJoint>);
+        stage.run(&mut world);
+        assert_eq!(world.get::<Pos>(weight).unwrap().0.y, scalar(-1.));
+
+        world.spawn().insert(taut);
+        stage.run(&mut world);
+        assert_eq!(world.get::<Pos>(weight).unwrap().0.y, scalar(-0.5));
+        assert_eq!(world.get::<Pos>(anchor).unwrap().0, Vector::ZERO);
+    }
+
+    #[test]
+    fn joints_wake_up_sleeping_bodies() {
+        let mut world = World::default();
+        let awake = world
+            .spawn()
+            .insert(Pos::default())
+            .insert(Mass::default())
+            .insert(SleepState::default())
+            .id();
+        let asleep = world
+            .spawn()
+            .insert(Pos::default())
+            .insert(Mass::default())
+            .insert(SleepState::Sleeping)
+            .id();
+        let anchor = world.spawn().insert(Pos::default()).id();
+        let dozing = world
+            .spawn()
+            .insert(Pos::default())
+            .insert(Mass::default())
+            .insert(SleepState::Sleeping)
+            .id();
+        world.spawn().insert(RevoluteJoint::new(awake, asleep));
+        // a static anchor doesn't wake anything up
+        world.spawn().insert(RevoluteJoint::new(anchor, dozing));
+
+        SystemStage::single(wake_up_joints::<RevoluteJoint>).run(&mut world);
+        assert!(!world.get::<SleepState>(asleep).unwrap().is_sleeping());
+        assert!(world.get::<SleepState>(dozing).unwrap().is_sleeping());
+    }
+
+    #[test]
+    fn revolute_pins_anchors() {
+        let mut world = World::default();
+        let a = world
+            .spawn()
+            .insert(Pos(Vector::ZERO))
+            .insert(Mass::default())
+            .id();
+        let b = world
+            .spawn()
+            .insert(Pos(Vector::new(scalar(2.), scalar(0.))))
+            .insert(Mass::default())
+            .id();
+        world.spawn().insert(RevoluteJoint::new(a, b).with_anchors(
+            Vector::new(scalar(0.5), scalar(0.)),
+            Vector::new(scalar(-0.5), scalar(0.)),
+        ));
+        world.insert_resource(PhysicsConfig::default());
+
+        SystemStage::single(solve_joints::<RevoluteJoint>).run(&mut world);
+        // equal masses meet halfway
+        assert_eq!(world.get::<Pos>(a).unwrap().0.x, scalar(0.5));
+        assert_eq!(world.get::<Pos>(b).unwrap().0.x, scalar(1.5));
+    }
+
+    #[test]
+    fn prismatic_slides_within_limits() {
+        let mut world = World::default();
+        let rail = world.spawn().insert(Pos(Vector::ZERO)).id();
+        let cart = world
+            .spawn()
+            .insert(Pos(Vector::new(scalar(1.), scalar(1.))))
+            .insert(Mass::default())
+            .id();
+        let runaway = world
+            .spawn()
+            .insert(Pos(Vector::new(scalar(3.), scalar(0.))))
+            .insert(Mass::default())
+            .id();
+        for body in [cart, runaway] {
+            world.spawn().insert(
+                PrismaticJoint::new(rail, body, Vector::X).with_limits(scalar(-2.), scalar(2.)),
+            );
+        }
+        world.insert_resource(PhysicsConfig::default());
+
+        SystemStage::single(solve_joints::<PrismaticJoint>).run(&mut world);
+        assert_eq!(
+            world.get::<Pos>(cart).unwrap().0,
+            Vector::new(scalar(1.), scalar(0.))
+        );
+        assert_eq!(
+            world.get::<Pos>(runaway).unwrap().0,
+            Vector::new(scalar(2.), scalar(0.))
+        );
+    }
+
+    #[test]
+    fn prismatic_keeps_the_rest_angle() {
+        let mut world = World::default();
+        let rail = world.spawn().insert(Pos(Vector::ZERO)).id();
+        let spun = world
+            .spawn()
+            .insert(Pos::default())
+            .insert(Rot(scalar(std::f32::consts::TAU)))
+            .insert(Mass::default())
+            .insert(InvInertia(scalar(1.)))
+            .id();
+        let tilted = world
+            .spawn()
+            .insert(Pos::default())
+            .insert(Rot(scalar(0.2)))
+            .insert(Mass::default())
+            .insert(InvInertia(scalar(1.)))
+            .id();
+        world
+            .spawn()
+            .insert(PrismaticJoint::new(rail, spun, Vector::X));
+        world
+            .spawn()
+            .insert(PrismaticJoint::new(rail, tilted, Vector::X).with_rest_angle(scalar(0.5)));
+        world.insert_resource(PhysicsConfig::default());
+
+        SystemStage::single(solve_joints::<PrismaticJoint>).run(&mut world);
+        // a whole turn is already at rest, it doesn't get unwound
+        let spun_rot = world.get::<Rot>(spun).unwrap().0;
+        assert!((spun_rot - scalar(std::f32::consts::TAU)).abs() < scalar(0.001));
+        let tilted_rot = world.get::<Rot>(tilted).unwrap().0;
+        assert!((tilted_rot - scalar(0.5)).abs() < scalar(0.001));
+    }
+}
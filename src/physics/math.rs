@@ -0,0 +1,98 @@
+//! Number types used by the simulation.
+//! With the `fixed-point` feature enabled, these are fixed point types that give bit-identical
+//! results on every platform, otherwise they're just `f32` and `Vec2`.
+//!
+//! Values crossing between the simulation and the rest of the game go through the conversion
+//! helpers below, which do nothing when the types are the same.
+
+use bevy::math::Vec2;
+
+#[cfg(not(feature = "fixed-point"))]
+pub type Scalar = f32;
+#[cfg(not(feature = "fixed-point"))]
+pub type Vector = Vec2;
+
+#[cfg(feature = "fixed-point")]
+pub use super::fixed::{Fixed as Scalar, FixedVec2 as Vector};
+
+/// Converts a float constant or a value coming from outside the simulation to a [`Scalar`]
+#[cfg(not(feature = "fixed-point"))]
+#[inline]
+pub fn scalar(value: f32) -> Scalar {
+    value
+}
+
+#[cfg(feature = "fixed-point")]
+#[inline]
+pub fn scalar(value: f32) -> Scalar {
+    value.into()
+}
+
+/// Converts a vector coming from outside the simulation to a [`Vector`]
+#[cfg(not(feature = "fixed-point"))]
+#[inline]
+pub fn vector(value: Vec2) -> Vector {
+    value
+}
+
+#[cfg(feature = "fixed-point")]
+#[inline]
+pub fn vector(value: Vec2) -> Vector {
+    value.into()
+}
+
+/// Converts a [`Scalar`] back to a float, e.g. for rendering or game logic
+#[cfg(not(feature = "fixed-point"))]
+#[inline]
+pub fn to_f32(value: Scalar) -> f32 {
+    value
+}
+
+#[cfg(feature = "fixed-point")]
+#[inline]
+pub fn to_f32(value: Scalar) -> f32 {
+    value.into()
+}
+
+/// Converts a [`Vector`] back to a `Vec2`, e.g. for rendering or game logic
+#[cfg(not(feature = "fixed-point"))]
+#[inline]
+pub fn to_vec2(value: Vector) -> Vec2 {
+    value
+}
+
+#[cfg(feature = "fixed-point")]
+#[inline]
+pub fn to_vec2(value: Vector) -> Vec2 {
+    value.into()
+}
+
+/// The exact bits of a [`Scalar`], e.g. for peers to compare settings
+#[cfg(not(feature = "fixed-point"))]
+#[inline]
+pub fn to_bits(value: Scalar) -> u64 {
+    value.to_bits() as u64
+}
+
+#[cfg(feature = "fixed-point")]
+#[inline]
+pub fn to_bits(value: Scalar) -> u64 {
+    value.to_bits() as u64
+}
+
+/// Wraps an angle into [-π, π], e.g. the difference between two rotations that may be whole turns apart
+#[cfg(not(feature = "fixed-point"))]
+#[inline]
+pub fn wrap_angle(angle: Scalar) -> Scalar {
+    use std::f32::consts::{PI, TAU};
+    (angle + PI).rem_euclid(TAU) - PI
+}
+
+#[cfg(feature = "fixed-point")]
+#[inline]
+pub fn wrap_angle(angle: Scalar) -> Scalar {
+    let bits = (angle + Scalar::PI)
+        .to_bits()
+        .rem_euclid(Scalar::TAU.to_bits());
+    Scalar::from_bits(bits) - Scalar::PI
+}
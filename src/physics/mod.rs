@@ -1,58 +1,187 @@
 //! simplified version of bevy_xpbd
 
-use bevy::prelude::*;
+use bevy::{ecs::schedule::ShouldRun, prelude::*, reflect::GetTypeRegistration};
+use bevy_ggrs::GGRSPlugin;
 use bevy_system_graph::SystemGraph;
+use components::*;
+use ggrs::Config;
+use joints::{solve_joints, wake_up_joints, DistanceJoint, PrismaticJoint, RevoluteJoint};
 use systems::*;
 
 use resources::*;
 
+mod builder;
 mod bundle;
 pub mod components;
 mod contact;
+#[cfg(feature = "fixed-point")]
+mod fixed;
+mod joints;
+pub mod math;
+mod query;
 mod resources;
 mod systems;
 mod utils;
 
-pub struct PhysicsPlugin;
+/// Adds the physics resources.
+/// In a rollback game, add the stage to the rollback schedule with [`PhysicsScheduleExt`],
+/// and register the rollback types with [`PhysicsRollbackExt`].
+/// Without rollback, [`PhysicsPlugin::standalone`] runs a physics step on every app update instead.
+#[derive(Debug, Default)]
+pub struct PhysicsPlugin {
+    standalone: bool,
+}
 
-pub const PIXELS_PER_METER: f32 = 24.0 / 1.8; // assuming janitor is 1.80 tall and 24 pixels tall
+impl PhysicsPlugin {
+    pub fn standalone() -> Self {
+        Self { standalone: true }
+    }
+}
 
 impl Plugin for PhysicsPlugin {
     fn build(&self, app: &mut App) {
-        app.init_resource::<Gravity>()
-            // .init_resource::<LoopState>() // todo: for substepping
+        app.init_resource::<PhysicsConfig>()
+            .init_resource::<LoopState>()
             // These resources are cleared at the start of every physics frame, so they should be rollback safe
             // i.e. they do not need to be added as rollback resources.
             .init_resource::<CollisionPairs>()
+            .init_resource::<StaticCollisionPairs>()
             .init_resource::<Contacts>()
-            .init_resource::<StaticContacts>();
+            .init_resource::<StaticContacts>()
+            .init_resource::<CollisionEvents>()
+            // This one is diffed against to produce the events, so it does need to be rolled back
+            .init_resource::<Collisions>();
+
+        if self.standalone {
+            app.add_stage_before(
+                CoreStage::PostUpdate,
+                PhysicsUpdateStage,
+                create_physics_stage(),
+            );
+        }
+    }
+}
+
+/// Anything rollback types can be registered with, e.g. a [`GGRSPlugin`]
+pub trait RollbackRegistry: Sized {
+    fn register_rollback_type<T>(self) -> Self
+    where
+        T: GetTypeRegistration + Reflect + Default + Component;
+
+    /// Registers a type that is only used inside rollback types, e.g. for its type data
+    fn register_type<T: GetTypeRegistration>(self) -> Self {
+        self
+    }
+}
+
+impl<C: Config> RollbackRegistry for GGRSPlugin<C> {
+    fn register_rollback_type<T>(self) -> Self
+    where
+        T: GetTypeRegistration + Reflect + Default + Component,
+    {
+        GGRSPlugin::register_rollback_type::<T>(self)
+    }
+}
+
+/// Registers the physics types with GGRS, so they are saved and restored on rollback
+pub trait PhysicsRollbackExt {
+    fn register_physics_types(self) -> Self;
+}
+
+impl<R: RollbackRegistry> PhysicsRollbackExt for R {
+    fn register_physics_types(self) -> Self {
+        self.register_rollback_type::<Pos>()
+            .register_rollback_type::<Vel>()
+            .register_rollback_type::<PrevPos>()
+            .register_rollback_type::<PreSolveVel>()
+            .register_rollback_type::<Rot>()
+            .register_rollback_type::<AngVel>()
+            .register_rollback_type::<PrevRot>()
+            .register_rollback_type::<PreSolveAngVel>()
+            .register_rollback_type::<InvInertia>()
+            .register_rollback_type::<PhysicsMaterial>()
+            .register_type::<CombineRule>()
+            .register_rollback_type::<CollisionLayers>()
+            .register_rollback_type::<Sensor>()
+            .register_rollback_type::<Ccd>()
+            .register_rollback_type::<OneWay>()
+            .register_rollback_type::<DropThrough>()
+            .register_rollback_type::<Kinematic>()
+            .register_rollback_type::<KinematicPath>()
+            .register_rollback_type::<BoxCollider>()
+            .register_rollback_type::<CircleCollider>()
+            .register_rollback_type::<CapsuleCollider>()
+            .register_rollback_type::<PolygonCollider>()
+            .register_rollback_type::<Mass>()
+            .register_rollback_type::<GravityScale>()
+            .register_rollback_type::<ExternalForce>()
+            .register_rollback_type::<ExternalImpulse>()
+            .register_rollback_type::<LinearDamping>()
+            .register_rollback_type::<MaxSpeed>()
+            .register_rollback_type::<Aabb>()
+            .register_rollback_type::<SleepState>()
+            .register_rollback_type::<DistanceJoint>()
+            .register_rollback_type::<RevoluteJoint>()
+            .register_rollback_type::<PrismaticJoint>()
+            // read by gameplay before the next physics step
+            .register_rollback_type::<StaticContacts>()
+            .register_rollback_type::<Contacts>()
+            .register_rollback_type::<Collisions>()
+            // so a resimulated frame never runs with another peer's settings
+            .register_rollback_type::<PhysicsConfig>()
+    }
+}
 
-        // Normally, we would add the stage here, but since we're doing rollback, we will just do it in main instead
+/// Adds the physics stage to a rollback schedule
+pub trait PhysicsScheduleExt {
+    /// Adds the stage as [`PhysicsUpdateStage`], after the stages already in the schedule
+    fn with_physics_stage(self) -> Self;
+}
+
+impl PhysicsScheduleExt for Schedule {
+    fn with_physics_stage(self) -> Self {
+        self.with_stage(PhysicsUpdateStage, create_physics_stage())
     }
 }
 
 /// re-exports of things needed to to use the physics module
 pub mod prelude {
     pub use super::{
+        builder::{ColliderShape, InsertBodyExt, RigidBodyBuilder, RigidBodyType, SpawnBodyExt},
         bundle::*,
-        components::{BoxCollider, Pos, Vel},
-        resources::{Contacts, Gravity, StaticContacts},
-        PhysicsPlugin,
+        components::{
+            AngVel, BoxCollider, CapsuleCollider, Ccd, CircleCollider, CollisionLayers,
+            CombineRule, DropThrough, ExternalForce, ExternalImpulse, GravityScale, InvInertia,
+            Kinematic, KinematicPath, LinearDamping, Mass, MaxSpeed, OneWay, PhysicsMaterial,
+            PolygonCollider, Pos, Rot, Sensor, SleepState, Vel,
+        },
+        joints::{DistanceJoint, PrismaticJoint, RevoluteJoint},
+        math::{scalar, to_f32, to_vec2, vector, Scalar, Vector},
+        query::{QueryFilter, RayHit, SpatialQuery},
+        resources::{
+            CollisionEnded, CollisionEvents, CollisionStarted, Collisions, ContactManifold,
+            Contacts, PhysicsConfig, StaticContacts,
+        },
+        PhysicsPlugin, PhysicsRollbackExt, PhysicsScheduleExt, PhysicsUpdateStage,
+        RollbackRegistry,
     };
 }
 
-pub const DELTA_TIME: f32 = 1. / 60.;
-pub const NUM_SUBSTEPS: u32 = 1; // todo
-pub const SUB_DT: f32 = DELTA_TIME / NUM_SUBSTEPS as f32;
-/// Safety margin bigger than DELTA_TIME added to AABBs to account for sudden accelerations
-const COLLISION_PAIR_VEL_MARGIN_FACTOR: f32 = 2. * DELTA_TIME;
+/// Safety margin added to AABBs to account for sudden accelerations, in timesteps
+const COLLISION_PAIR_VEL_MARGIN_FACTOR: f32 = 2.;
+/// Bodies rotating slower than this (in radians per second) are at rest
+const SLEEP_ANG_SPEED: f32 = 0.1;
+/// How many frames in a row a body has to be at rest before it falls asleep
+pub const SLEEP_FRAMES: usize = 30;
 
 #[derive(SystemLabel, Debug, Hash, PartialEq, Eq, Clone)]
 enum Step {
+    WakeUp,
     ComputeAabbs,
     CollectCollisionPairs,
     Integrate,
     SolvePositions,
+    MergeContacts,
     UpdateVelocities,
     SolveVelocities,
 }
@@ -61,17 +190,36 @@ enum Step {
 pub struct PhysicsUpdateStage;
 
 pub fn create_physics_stage() -> SystemStage {
+    let wake_up_systems: SystemSet = {
+        let graph = SystemGraph::new();
+        graph
+            .root(wake_up_moved)
+            // one after the other, so bodies woken through one kind of joint are the same everywhere
+            .then(wake_up_joints::<DistanceJoint>)
+            .then(wake_up_joints::<RevoluteJoint>)
+            .then(wake_up_joints::<PrismaticJoint>);
+        graph.into()
+    };
+
     let solve_pos_systems: SystemSet = {
         let graph = SystemGraph::new();
         graph
-            .root(solve_pos_ball_ball)
+            // joints go first, so contacts get the last word and joints can't pull bodies into walls
+            .root(solve_joints::<DistanceJoint>)
+            .then(solve_joints::<RevoluteJoint>)
+            .then(solve_joints::<PrismaticJoint>)
             // Run solvers sequentially to make sure rollback is deterministic
             // box_box and ball_ball could probably run in parallel,
             // but just keep it simple for now, wasm isn't parallel anyway
+            .then(solve_pos_ball_ball)
             .then(solve_pos_box_box)
+            .then(solve_pos_ball_box)
+            .then(solve_pos_shapes)
             .then(solve_pos_static_ball_ball)
             .then(solve_pos_static_box_ball)
-            .then(solve_pos_static_box_box);
+            .then(solve_pos_static_ball_box)
+            .then(solve_pos_static_box_box)
+            .then(solve_pos_static_shapes);
         graph.into()
     };
 
@@ -82,105 +230,272 @@ pub fn create_physics_stage() -> SystemStage {
     };
 
     SystemStage::parallel()
-        // todo: needed if we implement substepping
-        // .with_run_criteria(run_criteria)
+        .with_run_criteria(run_criteria)
+        .with_system_set(
+            wake_up_systems
+                .with_run_criteria(first_substep)
+                .label(Step::WakeUp)
+                .before(Step::ComputeAabbs),
+        )
         .with_system_set(
             SystemSet::new()
                 .label(Step::ComputeAabbs)
                 .before(Step::CollectCollisionPairs)
+                .with_run_criteria(first_substep)
                 .with_system(update_aabb_box)
-                .with_system(update_aabb_ball),
+                .with_system(update_aabb_ball)
+                .with_system(update_aabb_capsule)
+                .with_system(update_aabb_polygon),
         )
         .with_system(
             collect_collision_pairs
-                // .with_run_criteria(first_substep) // todo: for substepping
+                .with_run_criteria(first_substep)
                 .label(Step::CollectCollisionPairs)
                 .before(Step::Integrate),
         )
         .with_system_set(
             SystemSet::new()
                 .label(Step::Integrate)
-                .with_system(integrate),
-            // .with_system(integrate_rot), // todo: for rotation
+                .with_run_criteria(first_iteration)
+                .with_system(integrate)
+                .with_system(integrate_kinematic)
+                .with_system(integrate_rot),
+        )
+        .with_system(
+            solve_ccd
+                .with_run_criteria(first_iteration)
+                .after(Step::Integrate)
+                .before(Step::SolvePositions),
+        )
+        .with_system(
+            clear_contacts
+                .with_run_criteria(first_iteration)
+                .before(Step::SolvePositions),
         )
-        .with_system(clear_contacts.before(Step::SolvePositions))
         .with_system_set(
             solve_pos_systems
                 .label(Step::SolvePositions)
                 .after(Step::Integrate),
         )
+        .with_system(
+            merge_contacts
+                .with_run_criteria(last_iteration)
+                .label(Step::MergeContacts)
+                .after(Step::SolvePositions),
+        )
         .with_system_set(
             SystemSet::new()
                 .label(Step::UpdateVelocities)
-                .after(Step::SolvePositions)
-                .with_system(update_vel),
-            // .with_system(update_ang_vel), todo: for rotation
+                .with_run_criteria(last_iteration)
+                .after(Step::MergeContacts)
+                .with_system(update_vel)
+                .with_system(update_ang_vel),
         )
         .with_system_set(
             solve_vel_systems
+                .with_run_criteria(last_iteration)
                 .label(Step::SolveVelocities)
                 .after(Step::UpdateVelocities),
         )
         .with_system(
             sync_transforms
-                // .with_run_criteria(last_substep) // todo: substepping
+                .with_run_criteria(last_substep)
+                .after(Step::SolveVelocities),
+        )
+        .with_system(
+            update_collisions
+                .with_run_criteria(last_substep)
                 .after(Step::SolveVelocities),
         )
+        .with_system(
+            update_sleep
+                .with_run_criteria(last_substep)
+                .after(Step::SolveVelocities),
+        )
+}
+
+// Substepping:
+// The broadphase and the transform sync run once per frame, while integration and the solvers run
+// `PhysicsConfig::substeps` times. Unlike the usual fixed timestep loop, we don't accumulate real time here:
+// GGRS already calls the stage exactly once per (re-)simulated frame, so every run is a full step.
+// This also means `LoopState` is back to its default at the end of each run, so it does not need
+// to be a rollback resource.
+//
+// Solver iterations:
+// Every substep is split into `PhysicsConfig::solver_iterations` passes over the stage.
+// The position solvers run in every pass, so stacked bodies get to push each other apart more than once,
+// while integration only runs in the first and the velocity update in the last pass.
+// The velocity solvers iterate over their contacts on their own, as the contacts don't change in between.
+
+#[derive(Debug, Default)]
+struct LoopState {
+    substepping: bool,
+    current_substep: u32,
+    current_iteration: u32,
+}
+
+fn run_criteria(config: Res<PhysicsConfig>, mut state: ResMut<LoopState>) -> ShouldRun {
+    if state.substepping {
+        state.current_iteration += 1;
+        if state.current_iteration >= config.solver_iterations {
+            state.current_iteration = 0;
+            state.current_substep += 1;
+        }
+
+        if state.current_substep < config.substeps {
+            return ShouldRun::YesAndCheckAgain;
+        } else {
+            // We finished a whole step
+            state.current_substep = 0;
+            state.substepping = false;
+            return ShouldRun::No;
+        }
+    }
+
+    state.substepping = true;
+    state.current_substep = 0;
+    state.current_iteration = 0;
+    ShouldRun::YesAndCheckAgain
+}
+
+fn first_substep(state: Res<LoopState>) -> ShouldRun {
+    if state.current_substep == 0 && state.current_iteration == 0 {
+        ShouldRun::Yes
+    } else {
+        ShouldRun::No
+    }
 }
 
-// Stuff for substepping, if we enable that:
-// We'd want to enable substepping if we have issues with tunneling, or collisions feel too "spongy"
-// (it's pretty cheap, the expensive parts of the simulation only run once per frame)
-
-// #[derive(Debug, Default)]
-// struct LoopState {
-//     has_added_time: bool,
-//     accumulator: f32,
-//     substepping: bool,
-//     current_substep: u32,
-// }
-
-// fn run_criteria(time: Res<Time>, mut state: ResMut<LoopState>) -> ShouldRun {
-//     if !state.has_added_time {
-//         state.has_added_time = true;
-//         state.accumulator += time.delta_seconds();
-//     }
-
-//     if state.substepping {
-//         state.current_substep += 1;
-
-//         if state.current_substep < NUM_SUBSTEPS {
-//             return ShouldRun::YesAndCheckAgain;
-//         } else {
-//             // We finished a whole step
-//             state.accumulator -= DELTA_TIME;
-//             state.current_substep = 0;
-//             state.substepping = false;
-//         }
-//     }
-
-//     if state.accumulator >= DELTA_TIME {
-//         state.substepping = true;
-//         state.current_substep = 0;
-//         ShouldRun::YesAndCheckAgain
-//     } else {
-//         state.has_added_time = false;
-//         ShouldRun::No
-//     }
-// }
-
-// fn first_substep(state: Res<LoopState>) -> ShouldRun {
-//     if state.current_substep == 0 {
-//         ShouldRun::Yes
-//     } else {
-//         ShouldRun::No
-//     }
-// }
-
-// fn last_substep(state: Res<LoopState>) -> ShouldRun {
-//     if state.current_substep == NUM_SUBSTEPS - 1 {
-//         ShouldRun::Yes
-//     } else {
-//         ShouldRun::No
-//     }
-// }
+fn last_substep(config: Res<PhysicsConfig>, state: Res<LoopState>) -> ShouldRun {
+    if state.current_substep + 1 >= config.substeps
+        && state.current_iteration + 1 >= config.solver_iterations
+    {
+        ShouldRun::Yes
+    } else {
+        ShouldRun::No
+    }
+}
+
+fn first_iteration(state: Res<LoopState>) -> ShouldRun {
+    if state.current_iteration == 0 {
+        ShouldRun::Yes
+    } else {
+        ShouldRun::No
+    }
+}
+
+fn last_iteration(config: Res<PhysicsConfig>, state: Res<LoopState>) -> ShouldRun {
+    if state.current_iteration + 1 >= config.solver_iterations {
+        ShouldRun::Yes
+    } else {
+        ShouldRun::No
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bundle::{DynamicBoxBundle, StaticBoxBundle};
+    use math::{scalar, Vector};
+
+    #[test]
+    fn box_stack_settles() {
+        let mut app = App::new();
+        app.add_plugin(PhysicsPlugin::standalone())
+            .insert_resource(PhysicsConfig {
+                substeps: 4,
+                solver_iterations: 4,
+                ..Default::default()
+            });
+
+        // ground with its top at y = 0
+        app.world.spawn().insert_bundle(StaticBoxBundle {
+            pos: Pos(Vector::new(scalar(0.), scalar(-0.5))),
+            collider: BoxCollider {
+                size: Vector::new(scalar(20.), scalar(1.)),
+            },
+            ..Default::default()
+        });
+        let expected: Vec<Vector> = (0..5)
+            .map(|i| Vector::new(scalar(0.), scalar(0.5) + scalar(i as f32)))
+            .collect();
+        let boxes: Vec<Entity> = expected
+            .iter()
+            .enumerate()
+            .map(|(i, &pos)| {
+                // start with a small gap, so the stack has to fall into place
+                let pos = pos + Vector::new(scalar(0.), scalar(0.05) * scalar(i as f32 + 1.));
+                app.world
+                    .spawn()
+                    .insert_bundle(DynamicBoxBundle {
+                        pos: Pos(pos),
+                        prev_pos: PrevPos(pos),
+                        ..Default::default()
+                    })
+                    .id()
+            })
+            .collect();
+
+        for _ in 0..600 {
+            app.update();
+        }
+
+        for (&entity, &expected) in boxes.iter().zip(&expected) {
+            let pos = app.world.get::<Pos>(entity).unwrap().0;
+            let vel = app.world.get::<Vel>(entity).unwrap().0;
+            assert!(
+                (pos - expected).length() < scalar(0.05),
+                "box at {:?} should have settled at {:?}",
+                pos,
+                expected
+            );
+            assert!(vel.length() < scalar(0.01), "box still moves at {:?}", vel);
+        }
+    }
+
+    #[test]
+    fn config_bytes_tell_settings_apart() {
+        let config = PhysicsConfig::with_unit_scale(scalar(24.));
+        assert_eq!(config.to_bytes(), config.to_bytes());
+        assert_eq!(config.to_bytes().len(), 7 * 8);
+        let more_substeps = PhysicsConfig {
+            substeps: 4,
+            ..config
+        };
+        assert_ne!(config.to_bytes(), more_substeps.to_bytes());
+        let other_scale = PhysicsConfig::with_unit_scale(scalar(25.));
+        assert_ne!(config.to_bytes(), other_scale.to_bytes());
+    }
+
+    #[test]
+    fn contact_lookups_use_the_index() {
+        let [a, b, c] = [0, 1, 2].map(Entity::from_raw);
+        let manifold = |entity_a, entity_b| {
+            ContactManifold::new(
+                entity_a,
+                entity_b,
+                Vector::new(scalar(0.), scalar(1.)),
+                Vector::new(scalar(0.), scalar(0.)),
+                scalar(0.),
+                scalar(0.),
+            )
+        };
+        let mut contacts = Contacts(vec![manifold(b, c), manifold(a, b)], Default::default());
+        contacts
+            .1
+            .rebuild(contacts.0.iter().map(|m| (m.entity_a, m.entity_b)));
+
+        let with_b: Vec<_> = contacts.with(b).map(|m| m.entity_b).collect();
+        assert_eq!(with_b, vec![c, a], "in the order of the list");
+        assert!(contacts.with(b).all(|m| m.entity_a == b));
+        assert_eq!(contacts.between(a, b).unwrap().normal.y, scalar(1.));
+        assert_eq!(contacts.between(b, a).unwrap().normal.y, scalar(-1.));
+        assert!(contacts.between(a, c).is_none());
+
+        let mut collisions = Collisions(vec![(a, b), (b, c)], Default::default());
+        collisions.1.rebuild(collisions.0.iter().cloned());
+        assert!(collisions.contains(c, b));
+        assert!(!collisions.contains(a, c));
+        assert_eq!(collisions.with(b).collect::<Vec<_>>(), vec![a, c]);
+    }
+}
@@ -0,0 +1,525 @@
+//! Spatial queries against the colliders of the physics world.
+//! Results only depend on rollback state, and are sorted by rollback id where several bodies tie,
+//! so they are safe to use from rollback systems.
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy_ggrs::Rollback;
+use std::cmp::Ordering;
+
+use super::{
+    components::{CollisionLayers, Pos, Rot},
+    contact::{self, closest_on_segment, rotate, Shape},
+    math::{scalar, Scalar, Vector},
+    systems::{body_key, BodyKey, Colliders},
+};
+
+/// Which bodies a query can hit
+#[derive(Debug, Clone, Copy)]
+pub struct QueryFilter {
+    /// Only bodies that are a member of one of these layers are hit
+    pub mask: u32,
+    /// A body that is never hit, usually the one doing the query
+    pub exclude: Option<Entity>,
+}
+
+impl Default for QueryFilter {
+    fn default() -> Self {
+        Self {
+            mask: CollisionLayers::ALL,
+            exclude: None,
+        }
+    }
+}
+
+impl QueryFilter {
+    pub fn new(mask: u32) -> Self {
+        Self {
+            mask,
+            ..Default::default()
+        }
+    }
+
+    pub fn excluding(self, entity: Entity) -> Self {
+        Self {
+            exclude: Some(entity),
+            ..self
+        }
+    }
+
+    fn accepts(&self, entity: Entity, layers: Option<&CollisionLayers>) -> bool {
+        let memberships = layers.map_or(CollisionLayers::ALL, |layers| layers.memberships);
+        self.exclude != Some(entity) && memberships & self.mask != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RayHit {
+    pub entity: Entity,
+    /// Distance travelled along the ray before the hit
+    pub toi: Scalar,
+    /// Where the ray, or the center of the cast shape, was at the time of impact
+    pub point: Vector,
+    /// Points away from the body that was hit
+    pub normal: Vector,
+}
+
+type BodyItem = (
+    Entity,
+    &'static Pos,
+    Option<&'static Rot>,
+    Colliders,
+    Option<&'static CollisionLayers>,
+    Option<&'static Rollback>,
+);
+
+/// Raycasts, circle casts and overlap tests against every collider.
+/// Sensors are included, use a [`QueryFilter`] to leave them out.
+#[derive(SystemParam)]
+pub struct SpatialQuery<'w, 's> {
+    bodies: Query<'w, 's, BodyItem>,
+}
+
+impl<'w, 's> SpatialQuery<'w, 's> {
+    /// The first body hit by a ray from `origin` along `dir`, at most `max_toi` away.
+    /// `dir` doesn't have to be normalized.
+    pub fn raycast(
+        &self,
+        origin: Vector,
+        dir: Vector,
+        max_toi: Scalar,
+        filter: QueryFilter,
+    ) -> Option<RayHit> {
+        self.circle_cast(origin, scalar(0.), dir, max_toi, filter)
+    }
+
+    /// Every body hit by a ray from `origin` along `dir`, at most `max_toi` away, closest first
+    pub fn raycast_all(
+        &self,
+        origin: Vector,
+        dir: Vector,
+        max_toi: Scalar,
+        filter: QueryFilter,
+    ) -> Vec<RayHit> {
+        self.circle_cast_all(origin, scalar(0.), dir, max_toi, filter)
+    }
+
+    /// The first body hit by a circle moving from `origin` along `dir`, at most `max_toi` away
+    pub fn circle_cast(
+        &self,
+        origin: Vector,
+        radius: Scalar,
+        dir: Vector,
+        max_toi: Scalar,
+        filter: QueryFilter,
+    ) -> Option<RayHit> {
+        self.cast(origin, radius, dir, max_toi, filter)
+            .min_by(by_toi)
+            .map(|(_, hit)| hit)
+    }
+
+    /// Every body hit by a circle moving from `origin` along `dir`, at most `max_toi` away, closest first
+    pub fn circle_cast_all(
+        &self,
+        origin: Vector,
+        radius: Scalar,
+        dir: Vector,
+        max_toi: Scalar,
+        filter: QueryFilter,
+    ) -> Vec<RayHit> {
+        let mut hits: Vec<_> = self.cast(origin, radius, dir, max_toi, filter).collect();
+        hits.sort_unstable_by(by_toi);
+        hits.into_iter().map(|(_, hit)| hit).collect()
+    }
+
+    /// Bodies that contain `point`, sorted by rollback id
+    pub fn point_intersections(&self, point: Vector, filter: QueryFilter) -> Vec<Entity> {
+        self.intersections(filter, |pos, rot, shape| match shape {
+            Shape::Ball(radius) => (point - pos).length_squared() <= radius * radius,
+            Shape::Box(size) => {
+                let local = rotate(point - pos, -rot).abs();
+                let half = size / scalar(2.);
+                local.x <= half.x && local.y <= half.y
+            }
+            shape => {
+                let dot = Shape::Ball(scalar(0.));
+                contact::shape_shape(point, scalar(0.), dot, pos, rot, shape).is_some()
+            }
+        })
+    }
+
+    /// Bodies that overlap the axis aligned box from `min` to `max`, sorted by rollback id
+    pub fn aabb_intersections(&self, min: Vector, max: Vector, filter: QueryFilter) -> Vec<Entity> {
+        let center = (min + max) / scalar(2.);
+        let size = max - min;
+        self.intersections(filter, |pos, rot, shape| {
+            contact::shape_shape(center, scalar(0.), Shape::Box(size), pos, rot, shape).is_some()
+        })
+    }
+
+    /// Bodies that overlap the circle around `center`, sorted by rollback id
+    pub fn circle_intersections(
+        &self,
+        center: Vector,
+        radius: Scalar,
+        filter: QueryFilter,
+    ) -> Vec<Entity> {
+        self.intersections(filter, |pos, rot, shape| {
+            contact::shape_shape(center, scalar(0.), Shape::Ball(radius), pos, rot, shape).is_some()
+        })
+    }
+
+    fn bodies(
+        &self,
+        filter: QueryFilter,
+    ) -> impl Iterator<Item = (BodyKey, Entity, Vector, Scalar, Shape<'_>)> + '_ {
+        self.bodies
+            .iter()
+            .filter_map(move |(entity, pos, rot, colliders, layers, rollback)| {
+                if !filter.accepts(entity, layers) {
+                    return None;
+                }
+                let shape = Shape::of(colliders)?;
+                let rot = rot.map_or(scalar(0.), |rot| rot.0);
+                Some((body_key(entity, rollback), entity, pos.0, rot, shape))
+            })
+    }
+
+    fn cast(
+        &self,
+        origin: Vector,
+        radius: Scalar,
+        dir: Vector,
+        max_toi: Scalar,
+        filter: QueryFilter,
+    ) -> impl Iterator<Item = (BodyKey, RayHit)> + '_ {
+        let dir = dir.normalize();
+        self.bodies(filter)
+            .filter_map(move |(key, entity, pos, rot, shape)| {
+                let (toi, normal) = cast_shape(origin, radius, dir, pos, rot, shape)?;
+                Some((
+                    key,
+                    RayHit {
+                        entity,
+                        toi,
+                        point: origin + dir * toi,
+                        normal,
+                    },
+                ))
+            })
+            .filter(move |(_, hit)| hit.toi <= max_toi)
+    }
+
+    fn intersections(
+        &self,
+        filter: QueryFilter,
+        test: impl Fn(Vector, Scalar, Shape) -> bool,
+    ) -> Vec<Entity> {
+        let mut hits: Vec<_> = self
+            .bodies(filter)
+            .filter(|(_, _, pos, rot, shape)| test(*pos, *rot, *shape))
+            .map(|(key, entity, ..)| (key, entity))
+            .collect();
+        hits.sort_unstable_by_key(|(key, _)| *key);
+        hits.into_iter().map(|(_, entity)| entity).collect()
+    }
+}
+
+// Helpers
+
+/// Closest hit first, ties are broken by rollback id
+fn by_toi(a: &(BodyKey, RayHit), b: &(BodyKey, RayHit)) -> Ordering {
+    a.1.toi
+        .partial_cmp(&b.1.toi)
+        .unwrap_or(Ordering::Equal)
+        .then(a.0.cmp(&b.0))
+}
+
+/// Time of impact and normal of a circle of `radius` moving from `origin` along the normalized `dir`,
+/// against any shape
+pub(super) fn cast_shape(
+    origin: Vector,
+    radius: Scalar,
+    dir: Vector,
+    center: Vector,
+    rot: Scalar,
+    shape: Shape,
+) -> Option<(Scalar, Vector)> {
+    match shape {
+        Shape::Ball(target_radius) => cast_circle(origin, radius, dir, center, target_radius),
+        Shape::Box(size) => cast_box(origin, radius, dir, center, rot, size),
+        shape => {
+            let (vertices, hull_radius) = shape.hull(center, rot);
+            cast_hull(origin, radius + hull_radius, dir, &vertices)
+        }
+    }
+}
+
+/// Time of impact and normal of a circle of `radius` moving from `origin` along the normalized `dir`,
+/// against a circle of `target_radius` at `center`.
+/// A circle that starts out overlapping hits immediately.
+pub(super) fn cast_circle(
+    origin: Vector,
+    radius: Scalar,
+    dir: Vector,
+    center: Vector,
+    target_radius: Scalar,
+) -> Option<(Scalar, Vector)> {
+    let r = radius + target_radius;
+    let m = origin - center;
+    let b = m.dot(dir);
+    let c = m.length_squared() - r * r;
+    if c <= scalar(0.) {
+        return Some((scalar(0.), -dir));
+    }
+    if b > scalar(0.) {
+        // moving away
+        return None;
+    }
+    let discriminant = b * b - c;
+    if discriminant < scalar(0.) {
+        return None;
+    }
+    let toi = -b - discriminant.sqrt();
+    Some((toi, (m + dir * toi) / r))
+}
+
+/// Same as [`cast_circle`], but against a box of `size` rotated by `rot`
+pub(super) fn cast_box(
+    origin: Vector,
+    radius: Scalar,
+    dir: Vector,
+    center: Vector,
+    rot: Scalar,
+    size: Vector,
+) -> Option<(Scalar, Vector)> {
+    // solve in the local space of the box
+    let local_origin = rotate(origin - center, -rot);
+    let local_dir = rotate(dir, -rot);
+    let (toi, normal) = cast_local_box(local_origin, radius, local_dir, size / scalar(2.))?;
+    Some((toi, rotate(normal, rot)))
+}
+
+/// Circle cast against an axis aligned box around the origin
+fn cast_local_box(
+    origin: Vector,
+    radius: Scalar,
+    dir: Vector,
+    half: Vector,
+) -> Option<(Scalar, Vector)> {
+    // the box grown by the radius, its corners are rounded off below
+    let grown = half + Vector::splat(radius);
+    let (enter_x, exit_x) = slab(origin.x, dir.x, grown.x)?;
+    let (enter_y, exit_y) = slab(origin.y, dir.y, grown.y)?;
+    let enter = enter_x.max(enter_y);
+    let exit = exit_x.min(exit_y);
+    if enter > exit || exit < scalar(0.) {
+        return None;
+    }
+
+    let toi = enter.max(scalar(0.));
+    let point = origin + dir * toi;
+    if radius > scalar(0.) && point.x.abs() > half.x && point.y.abs() > half.y {
+        // the grown box was hit in a corner, so the circle can only hit that corner of the box
+        let corner = half * point.signum();
+        return cast_circle(origin, radius, dir, corner, scalar(0.));
+    }
+
+    let normal = if enter <= scalar(0.) {
+        // started inside
+        -dir
+    } else if enter_x > enter_y {
+        Vector::X * -dir.x.signum()
+    } else {
+        Vector::Y * -dir.y.signum()
+    };
+    Some((toi, normal))
+}
+
+/// Ray cast against convex vertices in counter-clockwise order, rounded off by `radius`.
+/// The rounded hull is made of the edges pushed out by the radius, and a circle around every vertex.
+fn cast_hull(
+    origin: Vector,
+    radius: Scalar,
+    dir: Vector,
+    vertices: &[Vector],
+) -> Option<(Scalar, Vector)> {
+    if hull_contains(origin, radius, vertices) {
+        return Some((scalar(0.), -dir));
+    }
+
+    let mut best: Option<(Scalar, Vector)> = None;
+    let n = vertices.len();
+    for i in 0..n {
+        let (start, end) = (vertices[i], vertices[(i + 1) % n]);
+        let mut hit = if radius > scalar(0.) {
+            cast_circle(origin, scalar(0.), dir, start, radius)
+        } else {
+            None
+        };
+
+        let edge = end - start;
+        if edge != Vector::ZERO {
+            let normal = Vector::new(edge.y, -edge.x).normalize();
+            let approach = dir.dot(normal);
+            // only edges facing the ray can be hit
+            if approach < scalar(0.) {
+                let offset_start = start + normal * radius;
+                let toi = (offset_start - origin).dot(normal) / approach;
+                let along = (origin + dir * toi - offset_start).dot(edge) / edge.length_squared();
+                let on_edge = along >= scalar(0.) && along <= scalar(1.);
+                if toi >= scalar(0.) && on_edge && hit.map_or(true, |(hit_toi, _)| toi < hit_toi) {
+                    hit = Some((toi, normal));
+                }
+            }
+        }
+
+        if let Some(hit) = hit {
+            if best.map_or(true, |(best_toi, _)| hit.0 < best_toi) {
+                best = Some(hit);
+            }
+        }
+    }
+    best
+}
+
+/// Whether `point` is inside the convex vertices rounded off by `radius`
+fn hull_contains(point: Vector, radius: Scalar, vertices: &[Vector]) -> bool {
+    let n = vertices.len();
+    let edges = (0..n).map(|i| (vertices[i], vertices[(i + 1) % n]));
+    let inside_vertices = n > 2
+        && edges.clone().all(|(start, end)| {
+            let edge = end - start;
+            (point - start).perp_dot(edge) <= scalar(0.)
+        });
+    inside_vertices
+        || edges.any(|(start, end)| {
+            (closest_on_segment(point, start, end) - point).length_squared() <= radius * radius
+        })
+}
+
+/// When a ray enters and exits the slab between `-half` and `half` on one axis
+fn slab(origin: Scalar, dir: Scalar, half: Scalar) -> Option<(Scalar, Scalar)> {
+    if dir == scalar(0.) {
+        // parallel to the slab, either always or never inside
+        return if origin.abs() > half {
+            None
+        } else {
+            Some((Scalar::MIN, Scalar::MAX))
+        };
+    }
+    let t1 = (-half - origin) / dir;
+    let t2 = (half - origin) / dir;
+    Some((t1.min(t2), t1.max(t2)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::math::{to_f32, vector};
+
+    fn vec(x: f32, y: f32) -> Vector {
+        vector(Vec2::new(x, y))
+    }
+
+    fn close(a: Scalar, b: f32) -> bool {
+        (to_f32(a) - b).abs() < 0.001
+    }
+
+    #[test]
+    fn ray_circle() {
+        let (toi, normal) = cast_circle(
+            vec(-2., 0.),
+            scalar(0.),
+            Vector::X,
+            Vector::ZERO,
+            scalar(0.5),
+        )
+        .unwrap();
+        assert!(close(toi, 1.5));
+        assert!(close(normal.x, -1.));
+
+        // pointing away
+        assert!(cast_circle(
+            vec(-2., 0.),
+            scalar(0.),
+            -Vector::X,
+            Vector::ZERO,
+            scalar(0.5)
+        )
+        .is_none());
+        // passing by
+        assert!(cast_circle(
+            vec(-2., 1.),
+            scalar(0.),
+            Vector::X,
+            Vector::ZERO,
+            scalar(0.5)
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn ray_box() {
+        let (toi, normal) =
+            cast_local_box(vec(0., 2.), scalar(0.), -Vector::Y, vec(0.5, 0.5)).unwrap();
+        assert!(close(toi, 1.5));
+        assert!(close(normal.y, 1.));
+
+        assert!(cast_local_box(vec(1., 2.), scalar(0.), -Vector::Y, vec(0.5, 0.5)).is_none());
+    }
+
+    #[test]
+    fn ray_rotated_box() {
+        // diamond, the ray hits its left corner
+        let rot = scalar(std::f32::consts::FRAC_PI_4);
+        let (toi, _) = cast_box(
+            vec(-2., 0.),
+            scalar(0.),
+            Vector::X,
+            Vector::ZERO,
+            rot,
+            Vector::ONE,
+        )
+        .unwrap();
+        assert!(close(toi, 2. - std::f32::consts::SQRT_2 / 2.));
+    }
+
+    #[test]
+    fn circle_cast_box_corner() {
+        // a circle moving diagonally towards the corner of a box hits the rounded corner
+        let dir = vec(1., 1.).normalize();
+        let (toi, normal) = cast_local_box(vec(-2., -2.), scalar(0.5), dir, vec(0.5, 0.5)).unwrap();
+        let corner_dist = 1.5 * std::f32::consts::SQRT_2;
+        assert!(close(toi, corner_dist - 0.5));
+        assert!(close(normal.x, -std::f32::consts::FRAC_1_SQRT_2));
+    }
+
+    #[test]
+    fn starts_inside() {
+        let (toi, _) = cast_local_box(Vector::ZERO, scalar(0.), Vector::X, vec(0.5, 0.5)).unwrap();
+        assert!(close(toi, 0.));
+    }
+
+    #[test]
+    fn ray_capsule() {
+        // upright segment from (0, -0.5) to (0, 0.5), rounded off by 0.25
+        let segment = [vec(0., -0.5), vec(0., 0.5)];
+        let (toi, normal) = cast_hull(vec(-2., 0.), scalar(0.25), Vector::X, &segment).unwrap();
+        assert!(close(toi, 1.75));
+        assert!(close(normal.x, -1.));
+
+        // from above, hits the rounded end
+        let (toi, normal) = cast_hull(vec(0., 2.), scalar(0.25), -Vector::Y, &segment).unwrap();
+        assert!(close(toi, 1.25));
+        assert!(close(normal.y, 1.));
+    }
+
+    #[test]
+    fn ray_ramp() {
+        let ramp = [vec(-1., -1.), vec(1., -1.), vec(1., 1.)];
+        let (toi, normal) = cast_hull(vec(-0.5, 2.), scalar(0.), -Vector::Y, &ramp).unwrap();
+        assert!(close(toi, 2.5));
+        assert!(close(normal.x, -std::f32::consts::FRAC_1_SQRT_2));
+        assert!(cast_hull(vec(-1.5, 2.), scalar(0.), -Vector::Y, &ramp).is_none());
+    }
+}
@@ -1,28 +1,266 @@
-use bevy::prelude::*;
+use bevy::{prelude::*, reflect::FromReflect};
 
-use crate::round::{JUMP_HEIGHT, JUMP_TIME_TO_PEAK};
+use super::math::{scalar, to_bits, Scalar, Vector};
 
-use super::PIXELS_PER_METER;
-
-#[derive(Debug)]
-pub struct Gravity(pub Vec2);
+/// Settings of the simulation, the defaults are for a world measured in meters.
+/// It's a rollback resource so resimulated frames use the settings they first ran with,
+/// but that doesn't make peers agree on it: they compare [`PhysicsConfig::to_bytes`] before a match.
+/// It can be replaced when a match starts, e.g. with [`PhysicsConfig::with_unit_scale`] as a starting point.
+#[derive(Reflect, Component, Debug, Clone, Copy)]
+pub struct PhysicsConfig {
+    pub gravity: Vector,
+    /// Length of a physics step in seconds, i.e. of a frame
+    pub timestep: Scalar,
+    /// How many substeps each step is split into
+    pub substeps: u32,
+    /// How many world units make a meter, scales the speeds below which bodies count as at rest
+    pub unit_scale: Scalar,
+    /// How many times the position and velocity solvers run per substep, at least 1
+    pub solver_iterations: u32,
+    /// Contacts that approach slower than this don't bounce, which keeps resting bodies from jittering
+    pub restitution_threshold: Scalar,
+}
 
-impl Default for Gravity {
+impl Default for PhysicsConfig {
     fn default() -> Self {
-        // For real-world gravity,
-        // we should probably tweak this, though. Maybe even have per object gravity?
-        // Self(Vec2::new(0., -9.81 * PIXELS_PER_METER))
-        let grav = (-2. * JUMP_HEIGHT) / JUMP_TIME_TO_PEAK; // derived as suggested in: https://www.youtube.com/watch?v=hG9SzQxaCm8
-        Self(Vec2::new(0., grav * PIXELS_PER_METER))
+        Self::with_unit_scale(scalar(1.))
+    }
+}
+
+impl PhysicsConfig {
+    /// Defaults for a world with `unit_scale` units (e.g. pixels) per meter, with real-world gravity
+    pub fn with_unit_scale(unit_scale: Scalar) -> Self {
+        Self {
+            gravity: Vector::new(scalar(0.), scalar(-9.81) * unit_scale),
+            timestep: scalar(1. / 60.),
+            substeps: 1,
+            unit_scale,
+            solver_iterations: 1,
+            restitution_threshold: unit_scale, // 1 m/s
+        }
+    }
+
+    pub fn sub_dt(&self) -> Scalar {
+        self.timestep / scalar(self.substeps.max(1) as f32)
+    }
+
+    /// Bodies slower than this are at rest, and may fall asleep
+    pub fn sleep_speed(&self) -> Scalar {
+        scalar(0.1) * self.unit_scale
+    }
+
+    /// The exact settings, little-endian, for peers to make sure they simulate the same world
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(7 * 8);
+        for value in [
+            to_bits(self.gravity.x),
+            to_bits(self.gravity.y),
+            to_bits(self.timestep),
+            self.substeps as u64,
+            to_bits(self.unit_scale),
+            self.solver_iterations as u64,
+            to_bits(self.restitution_threshold),
+        ] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes
     }
 }
 
+/// Pairs of dynamic bodies whose AABBs overlap and whose layers interact, ordered by rollback id
 #[derive(Component, Reflect, Default, Debug, Hash)]
 #[reflect(Hash)]
 pub struct CollisionPairs(pub Vec<(Entity, Entity)>);
 
+/// Pairs of a dynamic and a static body whose AABBs overlap and whose layers interact,
+/// ordered by rollback id.
+/// The static solvers only look at these pairs, so they respect the layers as well.
+#[derive(Component, Reflect, Default, Debug, Hash)]
+#[reflect(Hash)]
+pub struct StaticCollisionPairs(pub Vec<(Entity, Entity)>);
+
+/// A contact between two bodies in the last substep, as found by the position solvers.
+/// The impulses are filled in by the velocity solvers.
+#[derive(Reflect, FromReflect, Debug, Clone, Copy)]
+pub struct ContactManifold {
+    pub entity_a: Entity,
+    pub entity_b: Entity,
+    /// Points from a to b
+    pub normal: Vector,
+    pub point: Vector,
+    /// How deep the bodies overlapped before they were pushed apart
+    pub penetration: Scalar,
+    /// Size of the positional impulse that pushed the bodies apart
+    pub position_impulse: Scalar,
+    /// Size of the velocity impulse along the normal, positive when it pushes the bodies apart
+    pub normal_impulse: Scalar,
+    /// Size of the friction impulse
+    pub tangent_impulse: Scalar,
+    /// How fast the bodies approached each other along the normal before the substep
+    pub impact_speed: Scalar,
+}
+
+impl ContactManifold {
+    pub fn new(
+        entity_a: Entity,
+        entity_b: Entity,
+        normal: Vector,
+        point: Vector,
+        penetration: Scalar,
+        position_impulse: Scalar,
+    ) -> Self {
+        Self {
+            entity_a,
+            entity_b,
+            normal,
+            point,
+            penetration,
+            position_impulse,
+            normal_impulse: scalar(0.),
+            tangent_impulse: scalar(0.),
+            impact_speed: scalar(0.),
+        }
+    }
+
+    /// The same contact, seen from entity b
+    pub fn flipped(&self) -> Self {
+        Self {
+            entity_a: self.entity_b,
+            entity_b: self.entity_a,
+            normal: -self.normal,
+            ..*self
+        }
+    }
+
+    /// This contact seen from `entity`, if it is one of the two bodies
+    pub fn seen_from(&self, entity: Entity) -> Option<Self> {
+        if self.entity_a == entity {
+            Some(*self)
+        } else if self.entity_b == entity {
+            Some(self.flipped())
+        } else {
+            None
+        }
+    }
+}
+
+/// Where the entries of each entity are in one of the lists below, sorted by entity, so looking
+/// them up is a binary search instead of a scan.
+/// The physics step rebuilds it whenever it changes the list, before anything else reads it,
+/// so it isn't rolled back with the list.
+#[derive(Default, Debug, Clone, Hash)]
+pub struct EntityIndex(Vec<(Entity, usize)>);
+
+impl EntityIndex {
+    pub(crate) fn rebuild(&mut self, pairs: impl Iterator<Item = (Entity, Entity)>) {
+        self.0.clear();
+        for (i, (a, b)) in pairs.enumerate() {
+            self.0.push((a, i));
+            self.0.push((b, i));
+        }
+        self.0.sort_unstable();
+    }
+
+    /// Where the entries of `entity` are, in the order of the list
+    fn get(&self, entity: Entity) -> impl Iterator<Item = usize> + '_ {
+        let start = self.0.partition_point(|&(e, _)| e < entity);
+        self.0[start..]
+            .iter()
+            .take_while(move |&&(e, _)| e == entity)
+            .map(|&(_, i)| i)
+    }
+}
+
+/// Contacts between two dynamic bodies, ordered by rollback id
 #[derive(Component, Reflect, Default, Debug)]
-pub struct Contacts(pub Vec<(Entity, Entity, Vec2)>);
+pub struct Contacts(
+    pub Vec<ContactManifold>,
+    #[reflect(ignore)] pub(crate) EntityIndex,
+);
+
+impl Contacts {
+    /// Every contact of `entity`, seen from it, i.e. `entity` is always entity a
+    pub fn with(&self, entity: Entity) -> impl Iterator<Item = ContactManifold> + '_ {
+        self.1
+            .get(entity)
+            .filter_map(move |i| self.0[i].seen_from(entity))
+    }
 
+    /// The contact between `a` and `b`, seen from `a`
+    pub fn between(&self, a: Entity, b: Entity) -> Option<ContactManifold> {
+        self.with(a).find(|c| c.entity_b == b)
+    }
+}
+
+/// Contacts between a dynamic body (entity a) and a static one (entity b), ordered by rollback id
 #[derive(Component, Reflect, Default, Debug)]
-pub struct StaticContacts(pub Vec<(Entity, Entity, Vec2)>);
+pub struct StaticContacts(
+    pub Vec<ContactManifold>,
+    #[reflect(ignore)] pub(crate) EntityIndex,
+);
+
+impl StaticContacts {
+    /// Every contact of the dynamic body `entity` with a static one
+    pub fn with(&self, entity: Entity) -> impl Iterator<Item = &ContactManifold> + '_ {
+        self.1
+            .get(entity)
+            .map(move |i| &self.0[i])
+            .filter(move |c| c.entity_a == entity)
+    }
+}
+
+/// Pairs of bodies that touched (or overlapped, for sensors) in the last substep of the previous physics step,
+/// ordered by rollback id.
+/// This is what collision events are diffed against, so it has to be a rollback resource.
+#[derive(Component, Reflect, Default, Debug, Hash)]
+#[reflect(Hash)]
+pub struct Collisions(
+    pub Vec<(Entity, Entity)>,
+    #[reflect(ignore)] pub(crate) EntityIndex,
+);
+
+impl Collisions {
+    /// Whether `a` and `b` are touching, in any order
+    pub fn contains(&self, a: Entity, b: Entity) -> bool {
+        self.with(a).any(|other| other == b)
+    }
+
+    /// Everything touching `entity`
+    pub fn with(&self, entity: Entity) -> impl Iterator<Item = Entity> + '_ {
+        self.1.get(entity).map(move |i| match self.0[i] {
+            (a, b) if a == entity => b,
+            (a, _) => a,
+        })
+    }
+}
+
+/// Two bodies started touching
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CollisionStarted(pub Entity, pub Entity);
+
+/// Two bodies stopped touching, one of them may have been despawned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CollisionEnded(pub Entity, pub Entity);
+
+impl CollisionStarted {
+    /// Whether this is about `a` and `b`, in any order
+    pub fn is(&self, a: Entity, b: Entity) -> bool {
+        (self.0, self.1) == (a, b) || (self.0, self.1) == (b, a)
+    }
+}
+
+impl CollisionEnded {
+    /// Whether this is about `a` and `b`, in any order
+    pub fn is(&self, a: Entity, b: Entity) -> bool {
+        (self.0, self.1) == (a, b) || (self.0, self.1) == (b, a)
+    }
+}
+
+/// Collisions that started and ended in the last physics step.
+/// We don't use bevy's `Events` here, since they outlive the frame they were sent in.
+/// These are rebuilt from scratch every step, so a resimulated frame never sees events of a mispredicted one.
+#[derive(Default, Debug)]
+pub struct CollisionEvents {
+    pub started: Vec<CollisionStarted>,
+    pub ended: Vec<CollisionEnded>,
+}
@@ -1,295 +1,1746 @@
 use crate::physics::contact;
-use crate::physics::contact::Contact;
+use crate::physics::contact::{Contact, Shape};
+use crate::physics::query::cast_shape;
 use crate::physics::utils::QueryExt;
-use crate::physics::SUB_DT;
 
 use super::components::*;
+use super::math::{scalar, to_f32, to_vec2, Scalar, Vector};
 use super::resources::*;
-use super::COLLISION_PAIR_VEL_MARGIN_FACTOR;
-use bevy::prelude::*;
+use super::{COLLISION_PAIR_VEL_MARGIN_FACTOR, SLEEP_ANG_SPEED, SLEEP_FRAMES};
+use bevy::{prelude::*, utils::HashMap};
+use bevy_ggrs::Rollback;
+use std::cmp::Ordering;
 
-pub fn update_aabb_ball(mut query: Query<(&mut Aabb, &Pos, &Vel, &CircleCollider)>) {
-    for (mut aabb, pos, vel, circle) in query.iter_mut() {
-        let margin = COLLISION_PAIR_VEL_MARGIN_FACTOR * vel.0.length();
-        let half_extents = Vec2::splat(circle.radius + margin);
+/// Static bodies don't move, so they don't need a margin
+fn aabb_margin(vel: Option<&Vel>, config: &PhysicsConfig) -> Scalar {
+    vel.map_or(scalar(0.), |vel| {
+        scalar(COLLISION_PAIR_VEL_MARGIN_FACTOR) * config.timestep * vel.0.length()
+    })
+}
+
+/// Bodies without a [`SleepState`] never sleep
+fn is_sleeping(sleep: Option<&SleepState>) -> bool {
+    sleep.map_or(false, SleepState::is_sleeping)
+}
+
+pub fn update_aabb_ball(
+    mut query: Query<(
+        &mut Aabb,
+        &Pos,
+        Option<&Vel>,
+        &CircleCollider,
+        Option<&SleepState>,
+    )>,
+    config: Res<PhysicsConfig>,
+) {
+    for (mut aabb, pos, vel, circle, sleep) in query.iter_mut() {
+        if is_sleeping(sleep) {
+            continue;
+        }
+        let margin = aabb_margin(vel, &config);
+        let half_extents = Vector::splat(circle.radius + margin);
         aabb.min = pos.0 - half_extents;
         aabb.max = pos.0 + half_extents;
     }
 }
 
-pub fn update_aabb_box(mut query: Query<(&mut Aabb, &Pos, &Vel, &BoxCollider)>) {
-    for (mut aabb, pos, vel, r#box) in query.iter_mut() {
-        let margin = COLLISION_PAIR_VEL_MARGIN_FACTOR * vel.0.length();
-        let half_extents = r#box.size / 2. + Vec2::splat(margin);
+pub fn update_aabb_box(
+    mut query: Query<(
+        &mut Aabb,
+        &Pos,
+        &Rot,
+        Option<&Vel>,
+        &BoxCollider,
+        Option<&SleepState>,
+    )>,
+    config: Res<PhysicsConfig>,
+) {
+    for (mut aabb, pos, rot, vel, r#box, sleep) in query.iter_mut() {
+        if is_sleeping(sleep) {
+            continue;
+        }
+        let margin = aabb_margin(vel, &config);
+        // extents of the rotated box
+        let (sin, cos) = rot.0.sin_cos();
+        let half_size = r#box.size / scalar(2.);
+        let rotated_half_size = Vector::new(
+            cos.abs() * half_size.x + sin.abs() * half_size.y,
+            sin.abs() * half_size.x + cos.abs() * half_size.y,
+        );
+        let half_extents = rotated_half_size + Vector::splat(margin);
         aabb.min = pos.0 - half_extents;
         aabb.max = pos.0 + half_extents;
     }
 }
 
+pub fn update_aabb_capsule(
+    mut query: Query<(
+        &mut Aabb,
+        &Pos,
+        &Rot,
+        Option<&Vel>,
+        &CapsuleCollider,
+        Option<&SleepState>,
+    )>,
+    config: Res<PhysicsConfig>,
+) {
+    for (mut aabb, pos, rot, vel, capsule, sleep) in query.iter_mut() {
+        if is_sleeping(sleep) {
+            continue;
+        }
+        let margin = aabb_margin(vel, &config);
+        // extents of the rotated segment, which points up when not rotated
+        let (sin, cos) = rot.0.sin_cos();
+        let half_segment = Vector::new(sin.abs(), cos.abs()) * capsule.half_height;
+        let half_extents = half_segment + Vector::splat(capsule.radius + margin);
+        aabb.min = pos.0 - half_extents;
+        aabb.max = pos.0 + half_extents;
+    }
+}
+
+pub fn update_aabb_polygon(
+    mut query: Query<(
+        &mut Aabb,
+        &Pos,
+        &Rot,
+        Option<&Vel>,
+        &PolygonCollider,
+        Option<&SleepState>,
+    )>,
+    config: Res<PhysicsConfig>,
+) {
+    for (mut aabb, pos, rot, vel, polygon, sleep) in query.iter_mut() {
+        if is_sleeping(sleep) {
+            continue;
+        }
+        let margin = Vector::splat(aabb_margin(vel, &config));
+        let (sin, cos) = rot.0.sin_cos();
+        let mut min = Vector::splat(Scalar::MAX);
+        let mut max = Vector::splat(Scalar::MIN);
+        for v in polygon.vertices.iter() {
+            let rotated = Vector::new(v.x * cos - v.y * sin, v.x * sin + v.y * cos);
+            min = min.min(rotated);
+            max = max.max(rotated);
+        }
+        aabb.min = pos.0 + min - margin;
+        aabb.max = pos.0 + max + margin;
+    }
+}
+
+/// Whichever collider a body has, see [`Shape::of`]
+pub(super) type ColliderItem<'a> = (
+    Option<&'a CircleCollider>,
+    Option<&'a BoxCollider>,
+    Option<&'a CapsuleCollider>,
+    Option<&'a PolygonCollider>,
+);
+
+/// [`ColliderItem`] as part of a query
+pub(super) type Colliders = ColliderItem<'static>;
+
+/// Sort key that is the same on all peers, even if they allocated entities differently
+pub(super) type BodyKey = (u32, u32);
+
+pub(super) fn body_key(entity: Entity, rollback: Option<&Rollback>) -> BodyKey {
+    match rollback {
+        Some(rollback) => (0, rollback.id()),
+        // only for entities that are not rolled back, so it doesn't matter if peers disagree here
+        None => (1, entity.id()),
+    }
+}
+
+/// Keys of both bodies, both entities and whether the second body is dynamic
+type KeyedPair = (BodyKey, BodyKey, Entity, Entity, bool);
+
+/// An entry in the sweep and prune list
+pub struct Proxy {
+    key: BodyKey,
+    entity: Entity,
+    aabb: Aabb,
+    layers: CollisionLayers,
+    dynamic: bool,
+    /// Awake dynamic bodies and kinematic bodies wake up the sleeping bodies they overlap
+    awake: bool,
+    /// Whether the body is awake once the broadphase is done
+    woken: bool,
+}
+
+/// Sweep and prune along the x axis.
+/// Both the sweep order and the resulting pairs are sorted by rollback id,
+/// so the solvers handle contacts in the same order on every peer.
+/// Sleeping bodies paired with an awake one are woken up first, so they get all their pairs right away.
+/// Pairs of two bodies that are still sleeping are left out, but sleeping bodies keep their pairs
+/// with static bodies and sensors, so they keep resting on them and touching them.
 pub fn collect_collision_pairs(
-    query: Query<(Entity, &Aabb)>,
+    query: Query<(
+        Entity,
+        &Aabb,
+        Option<&CollisionLayers>,
+        Option<&Mass>,
+        Option<&Kinematic>,
+        Option<&Rollback>,
+    )>,
+    mut sleep_states: Query<&mut SleepState>,
     mut collision_pairs: ResMut<CollisionPairs>,
+    mut static_collision_pairs: ResMut<StaticCollisionPairs>,
+    // kept around to avoid allocating every frame
+    mut proxies: Local<Vec<Proxy>>,
+    mut active: Local<Vec<usize>>,
+    mut overlaps: Local<Vec<(usize, usize)>>,
+    mut keyed_pairs: Local<Vec<KeyedPair>>,
 ) {
     debug!("collect_collision_pairs");
     collision_pairs.0.clear();
+    static_collision_pairs.0.clear();
+    proxies.clear();
+    active.clear();
+    overlaps.clear();
+    keyed_pairs.clear();
+
+    proxies.extend(
+        query
+            .iter()
+            .map(|(entity, aabb, layers, mass, kinematic, rollback)| {
+                let sleeping = sleep_states
+                    .get(entity)
+                    .map_or(false, |sleep| sleep.is_sleeping());
+                let awake = (mass.is_some() && !sleeping) || kinematic.is_some();
+                Proxy {
+                    key: body_key(entity, rollback),
+                    entity,
+                    aabb: *aabb,
+                    layers: layers.copied().unwrap_or_default(),
+                    dynamic: mass.is_some(),
+                    awake,
+                    woken: awake,
+                }
+            }),
+    );
+    proxies.sort_unstable_by(|a, b| {
+        a.aabb
+            .min
+            .x
+            .partial_cmp(&b.aabb.min.x)
+            .unwrap_or(Ordering::Equal)
+            .then(a.key.cmp(&b.key))
+    });
+
+    for i in 0..proxies.len() {
+        let proxy = &proxies[i];
+        // everything that ends before this proxy starts can't overlap with anything after it either
+        active.retain(|&j| proxies[j].aabb.max.x >= proxy.aabb.min.x);
+
+        for &j in active.iter() {
+            let other = &proxies[j];
+            if !proxy.dynamic && !other.dynamic {
+                continue;
+            }
+            if !proxy.layers.interacts_with(&other.layers) {
+                continue;
+            }
+            if !proxy.aabb.intersects(&other.aabb) {
+                continue;
+            }
+            // dynamic pairs have the lower key first, mixed pairs have the dynamic body first
+            overlaps.push(if proxy.dynamic && other.dynamic {
+                if proxy.key < other.key {
+                    (i, j)
+                } else {
+                    (j, i)
+                }
+            } else if proxy.dynamic {
+                (i, j)
+            } else {
+                (j, i)
+            });
+        }
+
+        active.push(i);
+    }
+
+    // only bodies that were awake to begin with wake up others, so the order doesn't matter
+    for &(a, b) in overlaps.iter() {
+        if proxies[a].awake || proxies[b].awake {
+            proxies[a].woken = true;
+            proxies[b].woken = true;
+        }
+    }
 
-    let mut iter = query.iter_combinations();
-    while let Some([(entity_a, aabb_a), (entity_b, aabb_b)]) = iter.fetch_next() {
-        if aabb_a.intersects(aabb_b) {
+    for &(a, b) in overlaps.iter() {
+        let (a, b) = (&proxies[a], &proxies[b]);
+        // sleeping bodies stay where they are on their own
+        if b.dynamic && !a.woken && !b.woken {
+            continue;
+        }
+        keyed_pairs.push((a.key, b.key, a.entity, b.entity, b.dynamic));
+    }
+
+    for proxy in proxies.iter().filter(|proxy| proxy.woken && !proxy.awake) {
+        if let Ok(mut sleep) = sleep_states.get_mut(proxy.entity) {
+            sleep.wake_up();
+        }
+    }
+
+    keyed_pairs.sort_unstable_by_key(|(key_a, key_b, ..)| (*key_a, *key_b));
+    for (_, _, entity_a, entity_b, dynamic) in keyed_pairs.iter().cloned() {
+        if dynamic {
             collision_pairs.0.push((entity_a, entity_b));
+        } else {
+            static_collision_pairs.0.push((entity_a, entity_b));
         }
     }
 }
 
 pub fn integrate(
-    mut query: Query<(&mut Pos, &mut PrevPos, &mut Vel, &mut PreSolveVel, &Mass)>,
-    gravity: Res<Gravity>,
+    mut query: Query<(
+        &mut Pos,
+        &mut PrevPos,
+        &mut Vel,
+        &mut PreSolveVel,
+        &Mass,
+        Option<&GravityScale>,
+        Option<&ExternalForce>,
+        Option<&mut ExternalImpulse>,
+        Option<&LinearDamping>,
+        Option<&MaxSpeed>,
+        Option<&SleepState>,
+    )>,
+    config: Res<PhysicsConfig>,
 ) {
     debug!("  integrate");
-    for (mut pos, mut prev_pos, mut vel, mut pre_solve_vel, mass) in query.iter_mut() {
+    let sub_dt = config.sub_dt();
+    for (
+        mut pos,
+        mut prev_pos,
+        mut vel,
+        mut pre_solve_vel,
+        mass,
+        gravity_scale,
+        external_force,
+        external_impulse,
+        damping,
+        max_speed,
+        sleep,
+    ) in query.iter_mut()
+    {
+        if is_sleeping(sleep) {
+            continue;
+        }
         prev_pos.0 = pos.0;
 
-        let gravitation_force = mass.0 * gravity.0;
-        let external_forces = gravitation_force;
-        vel.0 += SUB_DT * external_forces / mass.0;
-        pos.0 += SUB_DT * vel.0;
+        // impulses only apply once, so they are used up in the first substep
+        if let Some(mut impulse) = external_impulse {
+            if impulse.0 != Vector::ZERO {
+                vel.0 += impulse.0 / mass.0;
+                impulse.0 = Vector::ZERO;
+            }
+        }
+
+        let gravity_scale = gravity_scale.map_or(scalar(1.), |scale| scale.0);
+        let gravitation_force = mass.0 * config.gravity * gravity_scale;
+        let external_forces = gravitation_force + external_force.map_or(Vector::ZERO, |f| f.0);
+        vel.0 += sub_dt * external_forces / mass.0;
+        if let Some(damping) = damping {
+            vel.0 /= scalar(1.) + sub_dt * damping.0;
+        }
+        if let Some(max_speed) = max_speed {
+            let speed = vel.0.length();
+            if speed > max_speed.0 {
+                vel.0 *= max_speed.0 / speed;
+            }
+        }
+        pos.0 += sub_dt * vel.0;
         pre_solve_vel.0 = vel.0;
     }
 }
 
+/// Kinematic bodies follow their path, or keep their velocity
+pub fn integrate_kinematic(
+    mut query: Query<
+        (&mut Pos, &mut PrevPos, &mut Vel, Option<&mut KinematicPath>),
+        (With<Kinematic>, Without<Mass>),
+    >,
+    config: Res<PhysicsConfig>,
+) {
+    debug!("  integrate_kinematic");
+    let sub_dt = config.sub_dt();
+    for (mut pos, mut prev_pos, mut vel, path) in query.iter_mut() {
+        prev_pos.0 = pos.0;
+        if let Some(mut path) = path {
+            let target = if path.forward { path.end } else { path.start };
+            let to_target = target - pos.0;
+            let distance = to_target.length();
+            if distance <= path.speed * sub_dt {
+                // arrive exactly, and turn around
+                vel.0 = to_target / sub_dt;
+                path.forward = !path.forward;
+            } else {
+                vel.0 = to_target / distance * path.speed;
+            }
+        }
+        pos.0 += sub_dt * vel.0;
+    }
+}
+
+pub fn integrate_rot(
+    mut query: Query<(
+        &mut Rot,
+        &mut PrevRot,
+        &AngVel,
+        &mut PreSolveAngVel,
+        Option<&SleepState>,
+    )>,
+    config: Res<PhysicsConfig>,
+) {
+    debug!("  integrate_rot");
+    let sub_dt = config.sub_dt();
+    for (mut rot, mut prev_rot, ang_vel, mut pre_solve_ang_vel, sleep) in query.iter_mut() {
+        if is_sleeping(sleep) {
+            continue;
+        }
+        prev_rot.0 = rot.0;
+        // no external torques for now
+        rot.0 += sub_dt * ang_vel.0;
+        pre_solve_ang_vel.0 = ang_vel.0;
+    }
+}
+
+/// Distance in meters a swept body is moved past its time of impact,
+/// so the position solvers still see the contact and respond to it.
+/// Scaled by [`PhysicsConfig::unit_scale`], like the sleep speed.
+const CCD_SKIN: f32 = 0.1;
+
+type CcdItem<'a> = (
+    &'a Pos,
+    Option<&'a PrevPos>,
+    Option<&'a Rot>,
+    ColliderItem<'a>,
+    Option<&'a Ccd>,
+    Option<&'a OneWay>,
+);
+
+/// Sweeps the bodies marked with [`Ccd`] from their previous position to the integrated one,
+/// against everything they were paired with in the broadphase, and moves them back to the earliest hit.
+/// The dynamic bodies they hit are moved back to the time of impact as well, static ones stay put.
+/// Bodies that move less than their inscribed radius in a substep can't tunnel, so they are skipped.
+pub fn solve_ccd(
+    mut query: Query<
+        (
+            &mut Pos,
+            Option<&PrevPos>,
+            Option<&Rot>,
+            Colliders,
+            Option<&Ccd>,
+            Option<&OneWay>,
+        ),
+        Without<Sensor>,
+    >,
+    collision_pairs: Res<CollisionPairs>,
+    static_collision_pairs: Res<StaticCollisionPairs>,
+    config: Res<PhysicsConfig>,
+    // earliest hit of each body, as a fraction of its motion
+    mut hits: Local<Vec<(Entity, Scalar, Vector)>>,
+) {
+    debug!("  solve_ccd");
+    hits.clear();
+    let skin = scalar(CCD_SKIN) * config.unit_scale;
+
+    // dynamic pairs are swept both ways, and the bodies in them can both be moved back
+    let pairs = static_collision_pairs
+        .0
+        .iter()
+        .map(|&(a, b)| (a, b, false))
+        .chain(
+            collision_pairs
+                .0
+                .iter()
+                .flat_map(|&(a, b)| [(a, b, true), (b, a, true)]),
+        );
+    for (entity, other, dynamic) in pairs {
+        let (body, other_body) = match (query.get(entity), query.get(other)) {
+            (Ok(body), Ok(other_body)) if body.4.is_some() => (body, other_body),
+            _ => continue,
+        };
+        if let Some((fraction, new_pos, other_new_pos)) = sweep(body, other_body, skin) {
+            add_hit(&mut hits, entity, fraction, new_pos);
+            if dynamic {
+                add_hit(&mut hits, other, fraction, other_new_pos);
+            }
+        }
+    }
+
+    for (entity, _, new_pos) in hits.iter() {
+        if let Ok((mut pos, ..)) = query.get_mut(*entity) {
+            pos.0 = *new_pos;
+        }
+    }
+}
+
+/// Keeps the earliest hit of each body
+fn add_hit(
+    hits: &mut Vec<(Entity, Scalar, Vector)>,
+    entity: Entity,
+    fraction: Scalar,
+    pos: Vector,
+) {
+    match hits
+        .iter_mut()
+        .find(|(hit_entity, ..)| *hit_entity == entity)
+    {
+        Some(hit) if fraction < hit.1 => *hit = (entity, fraction, pos),
+        Some(_) => {}
+        None => hits.push((entity, fraction, pos)),
+    }
+}
+
+/// Time of impact of the inscribed circle of `body` against `other`, relative to the motion of both,
+/// as a fraction of the motion, and where each of them is at that time, with `body` moved `skin` further
+fn sweep(body: CcdItem, other: CcdItem, skin: Scalar) -> Option<(Scalar, Vector, Vector)> {
+    let (pos, prev_pos, _, colliders, ..) = body;
+    let (other_pos, other_prev_pos, other_rot, other_colliders, _, one_way) = other;
+
+    let radius = Shape::of(colliders)?.inscribed_radius();
+    let start = prev_pos.map_or(pos.0, |prev_pos| prev_pos.0);
+    let motion = pos.0 - start;
+    let other_start = other_prev_pos.map_or(other_pos.0, |prev_pos| prev_pos.0);
+    let other_motion = other_pos.0 - other_start;
+    let relative_motion = motion - other_motion;
+    let distance = relative_motion.length();
+    if distance <= radius {
+        return None;
+    }
+    let dir = relative_motion / distance;
+    if one_way.map_or(false, |one_way| dir.dot(one_way.normal) >= scalar(0.)) {
+        // passing through the open side
+        return None;
+    }
+
+    let other_rot = other_rot.map_or(scalar(0.), |rot| rot.0);
+    let (toi, _) = cast_shape(
+        start,
+        radius,
+        dir,
+        other_start,
+        other_rot,
+        Shape::of(other_colliders)?,
+    )?;
+    // hits at zero already overlap, that's up to the position solvers
+    if toi <= scalar(0.) || toi >= distance {
+        return None;
+    }
+    let fraction = toi / distance;
+    Some((
+        fraction,
+        start + motion * fraction + dir * skin,
+        other_start + other_motion * fraction,
+    ))
+}
+
 pub fn clear_contacts(mut contacts: ResMut<Contacts>, mut static_contacts: ResMut<StaticContacts>) {
     debug!("  clear_contacts");
     contacts.0.clear();
     static_contacts.0.clear();
 }
 
+/// With more than one solver iteration, the position solvers report a pair once for every
+/// iteration it still overlapped in. Merges those into one manifold per pair, in the order the
+/// pairs were first found, with the latest geometry, the deepest penetration and the total impulse.
+/// Then indexes them by entity, for `Contacts::with` and friends.
+pub fn merge_contacts(
+    mut contacts: ResMut<Contacts>,
+    mut static_contacts: ResMut<StaticContacts>,
+    mut index: Local<HashMap<(Entity, Entity), usize>>,
+) {
+    debug!("  merge_contacts");
+    merge_manifolds(&mut contacts.0, &mut index);
+    merge_manifolds(&mut static_contacts.0, &mut index);
+    let Contacts(manifolds, by_entity) = &mut *contacts;
+    by_entity.rebuild(manifolds.iter().map(|c| (c.entity_a, c.entity_b)));
+    let StaticContacts(manifolds, by_entity) = &mut *static_contacts;
+    by_entity.rebuild(manifolds.iter().map(|c| (c.entity_a, c.entity_b)));
+}
+
+fn merge_manifolds(
+    manifolds: &mut Vec<ContactManifold>,
+    index: &mut HashMap<(Entity, Entity), usize>,
+) {
+    index.clear();
+    let mut merged = 0;
+    for i in 0..manifolds.len() {
+        let manifold = manifolds[i];
+        match index.get(&(manifold.entity_a, manifold.entity_b)) {
+            Some(&first) => {
+                let earlier = manifolds[first];
+                manifolds[first] = ContactManifold {
+                    penetration: earlier.penetration.max(manifold.penetration),
+                    position_impulse: earlier.position_impulse + manifold.position_impulse,
+                    ..manifold
+                };
+            }
+            None => {
+                index.insert((manifold.entity_a, manifold.entity_b), merged);
+                manifolds[merged] = manifold;
+                merged += 1;
+            }
+        }
+    }
+    manifolds.truncate(merged);
+}
+
 pub fn solve_pos_ball_ball(
-    mut query: Query<(&mut Pos, &CircleCollider, &Mass)>,
+    mut query: Query<(&mut Pos, &mut Rot, &CircleCollider, &Mass, &InvInertia), Without<Sensor>>,
     mut contacts: ResMut<Contacts>,
     collision_pairs: Res<CollisionPairs>,
 ) {
     debug!("  solve_pos");
     for (entity_a, entity_b) in collision_pairs.0.iter().cloned() {
-        if let Ok(((mut pos_a, circle_a, mass_a), (mut pos_b, circle_b, mass_b))) =
-            query.get_pair_mut(entity_a, entity_b)
+        if let Ok((
+            (mut pos_a, mut rot_a, circle_a, mass_a, inv_inertia_a),
+            (mut pos_b, mut rot_b, circle_b, mass_b, inv_inertia_b),
+        )) = query.get_pair_mut(entity_a, entity_b)
         {
             if let Some(Contact {
                 normal,
                 penetration,
+                point,
             }) = contact::ball_ball(pos_a.0, circle_a.radius, pos_b.0, circle_b.radius)
             {
-                constrain_body_positions(
-                    &mut pos_a,
-                    &mut pos_b,
-                    mass_a,
-                    mass_b,
+                let pos_impulse = constrain_body_positions(
+                    PosBody {
+                        pos: &mut pos_a,
+                        rot: &mut rot_a,
+                        mass: mass_a,
+                        inv_inertia: inv_inertia_a,
+                    },
+                    PosBody {
+                        pos: &mut pos_b,
+                        rot: &mut rot_b,
+                        mass: mass_b,
+                        inv_inertia: inv_inertia_b,
+                    },
                     normal,
                     penetration,
+                    point,
                 );
-                contacts.0.push((entity_a, entity_b, normal));
+                contacts.0.push(ContactManifold::new(
+                    entity_a,
+                    entity_b,
+                    normal,
+                    point,
+                    penetration,
+                    pos_impulse,
+                ));
             }
         }
     }
 }
 
 pub fn solve_pos_box_box(
-    mut query: Query<(&mut Pos, &BoxCollider, &Mass)>,
+    mut query: Query<(&mut Pos, &mut Rot, &BoxCollider, &Mass, &InvInertia), Without<Sensor>>,
     mut contacts: ResMut<Contacts>,
     collision_pairs: Res<CollisionPairs>,
 ) {
     for (entity_a, entity_b) in collision_pairs.0.iter().cloned() {
-        if let Ok(((mut pos_a, box_a, mass_a), (mut pos_b, box_b, mass_b))) =
-            query.get_pair_mut(entity_a, entity_b)
+        if let Ok((
+            (mut pos_a, mut rot_a, box_a, mass_a, inv_inertia_a),
+            (mut pos_b, mut rot_b, box_b, mass_b, inv_inertia_b),
+        )) = query.get_pair_mut(entity_a, entity_b)
         {
             if let Some(Contact {
                 normal,
                 penetration,
-            }) = contact::box_box(pos_a.0, box_a.size, pos_b.0, box_b.size)
+                point,
+            }) = contact::obb_obb(pos_a.0, rot_a.0, box_a.size, pos_b.0, rot_b.0, box_b.size)
             {
-                constrain_body_positions(
-                    &mut pos_a,
-                    &mut pos_b,
-                    mass_a,
-                    mass_b,
+                let pos_impulse = constrain_body_positions(
+                    PosBody {
+                        pos: &mut pos_a,
+                        rot: &mut rot_a,
+                        mass: mass_a,
+                        inv_inertia: inv_inertia_a,
+                    },
+                    PosBody {
+                        pos: &mut pos_b,
+                        rot: &mut rot_b,
+                        mass: mass_b,
+                        inv_inertia: inv_inertia_b,
+                    },
                     normal,
                     penetration,
+                    point,
                 );
-                contacts.0.push((entity_a, entity_b, normal));
+                contacts.0.push(ContactManifold::new(
+                    entity_a,
+                    entity_b,
+                    normal,
+                    point,
+                    penetration,
+                    pos_impulse,
+                ));
+            }
+        }
+    }
+}
+
+pub fn solve_pos_ball_box(
+    mut query: Query<
+        (
+            &mut Pos,
+            &mut Rot,
+            Option<&CircleCollider>,
+            Option<&BoxCollider>,
+            &Mass,
+            &InvInertia,
+        ),
+        Without<Sensor>,
+    >,
+    mut contacts: ResMut<Contacts>,
+    collision_pairs: Res<CollisionPairs>,
+) {
+    for (entity_a, entity_b) in collision_pairs.0.iter().cloned() {
+        if let Ok((
+            (mut pos_a, mut rot_a, circle_a, box_a, mass_a, inv_inertia_a),
+            (mut pos_b, mut rot_b, circle_b, box_b, mass_b, inv_inertia_b),
+        )) = query.get_pair_mut(entity_a, entity_b)
+        {
+            // the pair can come in either order
+            let contact = match (circle_a, box_a, circle_b, box_b) {
+                (Some(circle_a), None, None, Some(box_b)) => {
+                    contact::ball_obb(pos_a.0, circle_a.radius, pos_b.0, rot_b.0, box_b.size)
+                }
+                (None, Some(box_a), Some(circle_b), None) => {
+                    contact::ball_obb(pos_b.0, circle_b.radius, pos_a.0, rot_a.0, box_a.size)
+                        .map(Contact::flipped)
+                }
+                _ => None,
+            };
+            if let Some(Contact {
+                normal,
+                penetration,
+                point,
+            }) = contact
+            {
+                let pos_impulse = constrain_body_positions(
+                    PosBody {
+                        pos: &mut pos_a,
+                        rot: &mut rot_a,
+                        mass: mass_a,
+                        inv_inertia: inv_inertia_a,
+                    },
+                    PosBody {
+                        pos: &mut pos_b,
+                        rot: &mut rot_b,
+                        mass: mass_b,
+                        inv_inertia: inv_inertia_b,
+                    },
+                    normal,
+                    penetration,
+                    point,
+                );
+                contacts.0.push(ContactManifold::new(
+                    entity_a,
+                    entity_b,
+                    normal,
+                    point,
+                    penetration,
+                    pos_impulse,
+                ));
             }
         }
     }
 }
 
 pub fn solve_pos_static_ball_ball(
-    mut dynamics: Query<(Entity, &mut Pos, &CircleCollider), With<Mass>>,
-    statics: Query<(Entity, &Pos, &CircleCollider), Without<Mass>>,
+    mut dynamics: Query<
+        (&mut Pos, &mut Rot, &CircleCollider, &Mass, &InvInertia),
+        (With<Mass>, Without<Sensor>),
+    >,
+    statics: Query<(&Pos, &CircleCollider), (Without<Mass>, Without<Sensor>)>,
     mut contacts: ResMut<StaticContacts>,
+    collision_pairs: Res<StaticCollisionPairs>,
 ) {
-    for (entity_a, mut pos_a, circle_a) in dynamics.iter_mut() {
-        for (entity_b, pos_b, circle_b) in statics.iter() {
+    for (entity_a, entity_b) in collision_pairs.0.iter().cloned() {
+        if let (
+            Ok((mut pos_a, mut rot_a, circle_a, mass_a, inv_inertia_a)),
+            Ok((pos_b, circle_b)),
+        ) = (dynamics.get_mut(entity_a), statics.get(entity_b))
+        {
             if let Some(Contact {
                 normal,
                 penetration,
+                point,
             }) = contact::ball_ball(pos_a.0, circle_a.radius, pos_b.0, circle_b.radius)
             {
-                constrain_body_position(&mut pos_a, normal, penetration);
-                contacts.0.push((entity_a, entity_b, normal));
+                let pos_impulse = constrain_body_position(
+                    PosBody {
+                        pos: &mut pos_a,
+                        rot: &mut rot_a,
+                        mass: mass_a,
+                        inv_inertia: inv_inertia_a,
+                    },
+                    normal,
+                    penetration,
+                    point,
+                );
+                contacts.0.push(ContactManifold::new(
+                    entity_a,
+                    entity_b,
+                    normal,
+                    point,
+                    penetration,
+                    pos_impulse,
+                ));
             }
         }
     }
 }
 
 pub fn solve_pos_static_box_ball(
-    mut dynamics: Query<(Entity, &mut Pos, &CircleCollider), With<Mass>>,
-    statics: Query<(Entity, &Pos, &BoxCollider), Without<Mass>>,
+    mut dynamics: Query<
+        (
+            &mut Pos,
+            &PrevPos,
+            &mut Rot,
+            &CircleCollider,
+            &Mass,
+            &InvInertia,
+            Option<&DropThrough>,
+        ),
+        (With<Mass>, Without<Sensor>),
+    >,
+    statics: Query<
+        (&Pos, Option<&PrevPos>, &Rot, &BoxCollider, Option<&OneWay>),
+        (Without<Mass>, Without<Sensor>),
+    >,
+    mut contacts: ResMut<StaticContacts>,
+    collision_pairs: Res<StaticCollisionPairs>,
+    config: Res<PhysicsConfig>,
+) {
+    let slop = scalar(ONE_WAY_SLOP) * config.unit_scale;
+    for (entity_a, entity_b) in collision_pairs.0.iter().cloned() {
+        if let (
+            Ok((mut pos_a, prev_pos_a, mut rot_a, circle_a, mass_a, inv_inertia_a, drop_through)),
+            Ok((pos_b, prev_pos_b, rot_b, box_b, one_way)),
+        ) = (dynamics.get_mut(entity_a), statics.get(entity_b))
+        {
+            let contact = contact::ball_obb(pos_a.0, circle_a.radius, pos_b.0, rot_b.0, box_b.size);
+            let contact = match (contact, one_way) {
+                (Some(_), Some(_)) if drop_through.is_some() => None,
+                (Some(contact), Some(one_way)) => one_way_contact(
+                    contact,
+                    one_way,
+                    slop,
+                    (prev_pos_a.0, pos_a.0),
+                    (prev_pos_b.map_or(pos_b.0, |prev_pos| prev_pos.0), pos_b.0),
+                    circle_a.radius + Shape::Box(box_b.size).reach(rot_b.0, one_way.normal),
+                ),
+                (contact, _) => contact,
+            };
+            if let Some(Contact {
+                normal,
+                penetration,
+                point,
+            }) = contact
+            {
+                let pos_impulse = constrain_body_position(
+                    PosBody {
+                        pos: &mut pos_a,
+                        rot: &mut rot_a,
+                        mass: mass_a,
+                        inv_inertia: inv_inertia_a,
+                    },
+                    normal,
+                    penetration,
+                    point,
+                );
+                contacts.0.push(ContactManifold::new(
+                    entity_a,
+                    entity_b,
+                    normal,
+                    point,
+                    penetration,
+                    pos_impulse,
+                ));
+            }
+        }
+    }
+}
+
+pub fn solve_pos_static_ball_box(
+    mut dynamics: Query<
+        (&mut Pos, &mut Rot, &BoxCollider, &Mass, &InvInertia),
+        (With<Mass>, Without<Sensor>),
+    >,
+    statics: Query<(&Pos, &CircleCollider), (Without<Mass>, Without<Sensor>)>,
     mut contacts: ResMut<StaticContacts>,
+    collision_pairs: Res<StaticCollisionPairs>,
 ) {
-    for (entity_a, mut pos_a, circle_a) in dynamics.iter_mut() {
-        for (entity_b, pos_b, box_b) in statics.iter() {
+    for (entity_a, entity_b) in collision_pairs.0.iter().cloned() {
+        if let (Ok((mut pos_a, mut rot_a, box_a, mass_a, inv_inertia_a)), Ok((pos_b, circle_b))) =
+            (dynamics.get_mut(entity_a), statics.get(entity_b))
+        {
             if let Some(Contact {
                 normal,
                 penetration,
-            }) = contact::ball_box(pos_a.0, circle_a.radius, pos_b.0, box_b.size)
+                point,
+            }) = contact::ball_obb(pos_b.0, circle_b.radius, pos_a.0, rot_a.0, box_a.size)
+                .map(Contact::flipped)
             {
-                constrain_body_position(&mut pos_a, normal, penetration);
-                contacts.0.push((entity_a, entity_b, normal));
+                let pos_impulse = constrain_body_position(
+                    PosBody {
+                        pos: &mut pos_a,
+                        rot: &mut rot_a,
+                        mass: mass_a,
+                        inv_inertia: inv_inertia_a,
+                    },
+                    normal,
+                    penetration,
+                    point,
+                );
+                contacts.0.push(ContactManifold::new(
+                    entity_a,
+                    entity_b,
+                    normal,
+                    point,
+                    penetration,
+                    pos_impulse,
+                ));
             }
         }
     }
 }
 
 pub fn solve_pos_static_box_box(
-    mut dynamics: Query<(Entity, &mut Pos, &BoxCollider), With<Mass>>,
-    statics: Query<(Entity, &Pos, &BoxCollider), Without<Mass>>,
+    mut dynamics: Query<
+        (
+            &mut Pos,
+            &PrevPos,
+            &mut Rot,
+            &BoxCollider,
+            &Mass,
+            &InvInertia,
+            Option<&DropThrough>,
+        ),
+        (With<Mass>, Without<Sensor>),
+    >,
+    statics: Query<
+        (&Pos, Option<&PrevPos>, &Rot, &BoxCollider, Option<&OneWay>),
+        (Without<Mass>, Without<Sensor>),
+    >,
+    mut contacts: ResMut<StaticContacts>,
+    collision_pairs: Res<StaticCollisionPairs>,
+    config: Res<PhysicsConfig>,
+) {
+    let slop = scalar(ONE_WAY_SLOP) * config.unit_scale;
+    for (entity_a, entity_b) in collision_pairs.0.iter().cloned() {
+        if let (
+            Ok((mut pos_a, prev_pos_a, mut rot_a, box_a, mass_a, inv_inertia_a, drop_through)),
+            Ok((pos_b, prev_pos_b, rot_b, box_b, one_way)),
+        ) = (dynamics.get_mut(entity_a), statics.get(entity_b))
+        {
+            let contact =
+                contact::obb_obb(pos_a.0, rot_a.0, box_a.size, pos_b.0, rot_b.0, box_b.size);
+            let contact = match (contact, one_way) {
+                (Some(_), Some(_)) if drop_through.is_some() => None,
+                (Some(contact), Some(one_way)) => one_way_contact(
+                    contact,
+                    one_way,
+                    slop,
+                    (prev_pos_a.0, pos_a.0),
+                    (prev_pos_b.map_or(pos_b.0, |prev_pos| prev_pos.0), pos_b.0),
+                    Shape::Box(box_a.size).reach(rot_a.0, -one_way.normal)
+                        + Shape::Box(box_b.size).reach(rot_b.0, one_way.normal),
+                ),
+                (contact, _) => contact,
+            };
+            if let Some(Contact {
+                normal,
+                penetration,
+                point,
+            }) = contact
+            {
+                let pos_impulse = constrain_body_position(
+                    PosBody {
+                        pos: &mut pos_a,
+                        rot: &mut rot_a,
+                        mass: mass_a,
+                        inv_inertia: inv_inertia_a,
+                    },
+                    normal,
+                    penetration,
+                    point,
+                );
+                contacts.0.push(ContactManifold::new(
+                    entity_a,
+                    entity_b,
+                    normal,
+                    point,
+                    penetration,
+                    pos_impulse,
+                ));
+            }
+        }
+    }
+}
+
+/// Position solve for dynamic pairs with a capsule or a polygon in them
+pub fn solve_pos_shapes(
+    mut query: Query<(&mut Pos, &mut Rot, Colliders, &Mass, &InvInertia), Without<Sensor>>,
+    mut contacts: ResMut<Contacts>,
+    collision_pairs: Res<CollisionPairs>,
+) {
+    for (entity_a, entity_b) in collision_pairs.0.iter().cloned() {
+        if let Ok((
+            (mut pos_a, mut rot_a, colliders_a, mass_a, inv_inertia_a),
+            (mut pos_b, mut rot_b, colliders_b, mass_b, inv_inertia_b),
+        )) = query.get_pair_mut(entity_a, entity_b)
+        {
+            let (shape_a, shape_b) = match (Shape::of(colliders_a), Shape::of(colliders_b)) {
+                (Some(a), Some(b)) if !(a.is_ball_or_box() && b.is_ball_or_box()) => (a, b),
+                _ => continue,
+            };
+            if let Some(Contact {
+                normal,
+                penetration,
+                point,
+            }) = contact::shape_shape(pos_a.0, rot_a.0, shape_a, pos_b.0, rot_b.0, shape_b)
+            {
+                let pos_impulse = constrain_body_positions(
+                    PosBody {
+                        pos: &mut pos_a,
+                        rot: &mut rot_a,
+                        mass: mass_a,
+                        inv_inertia: inv_inertia_a,
+                    },
+                    PosBody {
+                        pos: &mut pos_b,
+                        rot: &mut rot_b,
+                        mass: mass_b,
+                        inv_inertia: inv_inertia_b,
+                    },
+                    normal,
+                    penetration,
+                    point,
+                );
+                contacts.0.push(ContactManifold::new(
+                    entity_a,
+                    entity_b,
+                    normal,
+                    point,
+                    penetration,
+                    pos_impulse,
+                ));
+            }
+        }
+    }
+}
+
+/// Position solve for dynamic vs static pairs with a capsule or a polygon in them
+pub fn solve_pos_static_shapes(
+    mut dynamics: Query<
+        (
+            &mut Pos,
+            &PrevPos,
+            &mut Rot,
+            Colliders,
+            &Mass,
+            &InvInertia,
+            Option<&DropThrough>,
+        ),
+        (With<Mass>, Without<Sensor>),
+    >,
+    statics: Query<
+        (
+            &Pos,
+            Option<&PrevPos>,
+            Option<&Rot>,
+            Colliders,
+            Option<&OneWay>,
+        ),
+        (Without<Mass>, Without<Sensor>),
+    >,
     mut contacts: ResMut<StaticContacts>,
+    collision_pairs: Res<StaticCollisionPairs>,
+    config: Res<PhysicsConfig>,
 ) {
-    for (entity_a, mut pos_a, box_a) in dynamics.iter_mut() {
-        for (entity_b, pos_b, box_b) in statics.iter() {
+    let slop = scalar(ONE_WAY_SLOP) * config.unit_scale;
+    for (entity_a, entity_b) in collision_pairs.0.iter().cloned() {
+        if let (
+            Ok((
+                mut pos_a,
+                prev_pos_a,
+                mut rot_a,
+                colliders_a,
+                mass_a,
+                inv_inertia_a,
+                drop_through,
+            )),
+            Ok((pos_b, prev_pos_b, rot_b, colliders_b, one_way)),
+        ) = (dynamics.get_mut(entity_a), statics.get(entity_b))
+        {
+            let (shape_a, shape_b) = match (Shape::of(colliders_a), Shape::of(colliders_b)) {
+                (Some(a), Some(b)) if !(a.is_ball_or_box() && b.is_ball_or_box()) => (a, b),
+                _ => continue,
+            };
+            let rot_b = rot_b.map_or(scalar(0.), |rot| rot.0);
+            let contact = contact::shape_shape(pos_a.0, rot_a.0, shape_a, pos_b.0, rot_b, shape_b);
+            let contact = match (contact, one_way) {
+                (Some(_), Some(_)) if drop_through.is_some() => None,
+                (Some(contact), Some(one_way)) => one_way_contact(
+                    contact,
+                    one_way,
+                    slop,
+                    (prev_pos_a.0, pos_a.0),
+                    (prev_pos_b.map_or(pos_b.0, |prev_pos| prev_pos.0), pos_b.0),
+                    shape_a.reach(rot_a.0, -one_way.normal) + shape_b.reach(rot_b, one_way.normal),
+                ),
+                (contact, _) => contact,
+            };
             if let Some(Contact {
                 normal,
                 penetration,
-            }) = contact::box_box(pos_a.0, box_a.size, pos_b.0, box_b.size)
+                point,
+            }) = contact
             {
-                constrain_body_position(&mut pos_a, normal, penetration);
-                contacts.0.push((entity_a, entity_b, normal));
+                let pos_impulse = constrain_body_position(
+                    PosBody {
+                        pos: &mut pos_a,
+                        rot: &mut rot_a,
+                        mass: mass_a,
+                        inv_inertia: inv_inertia_a,
+                    },
+                    normal,
+                    penetration,
+                    point,
+                );
+                contacts.0.push(ContactManifold::new(
+                    entity_a,
+                    entity_b,
+                    normal,
+                    point,
+                    penetration,
+                    pos_impulse,
+                ));
             }
         }
     }
 }
 
-pub fn update_vel(mut query: Query<(&Pos, &PrevPos, &mut Vel)>) {
+pub fn update_vel(
+    mut query: Query<(&Pos, &PrevPos, &mut Vel, Option<&SleepState>)>,
+    config: Res<PhysicsConfig>,
+) {
     debug!("  update_vel");
-    for (pos, prev_pos, mut vel) in query.iter_mut() {
-        vel.0 = (pos.0 - prev_pos.0) / SUB_DT;
+    let sub_dt = config.sub_dt();
+    for (pos, prev_pos, mut vel, sleep) in query.iter_mut() {
+        if is_sleeping(sleep) {
+            continue;
+        }
+        vel.0 = (pos.0 - prev_pos.0) / sub_dt;
+    }
+}
+
+pub fn update_ang_vel(
+    mut query: Query<(&Rot, &PrevRot, &mut AngVel, Option<&SleepState>)>,
+    config: Res<PhysicsConfig>,
+) {
+    debug!("  update_ang_vel");
+    let sub_dt = config.sub_dt();
+    for (rot, prev_rot, mut ang_vel, sleep) in query.iter_mut() {
+        if is_sleeping(sleep) {
+            continue;
+        }
+        ang_vel.0 = (rot.0 - prev_rot.0) / sub_dt;
     }
 }
 
 pub fn solve_vel(
-    mut query: Query<(&mut Vel, &PreSolveVel, &Mass, &Restitution)>,
-    contacts: Res<Contacts>,
+    mut query: Query<(
+        &Pos,
+        &mut Vel,
+        &mut AngVel,
+        &PreSolveVel,
+        &PreSolveAngVel,
+        &Mass,
+        &InvInertia,
+        &PhysicsMaterial,
+    )>,
+    mut contacts: ResMut<Contacts>,
+    config: Res<PhysicsConfig>,
 ) {
     debug!("  solve_vel");
-    for (entity_a, entity_b, n) in contacts.0.iter().cloned() {
-        let (
-            (mut vel_a, pre_solve_vel_a, mass_a, restitution_a),
-            (mut vel_b, pre_solve_vel_b, mass_b, restitution_b),
-        ) = query.get_pair_mut(entity_a, entity_b).unwrap();
-        constrain_body_velocities(
-            &mut vel_a,
-            &mut vel_b,
-            pre_solve_vel_a,
-            pre_solve_vel_b,
-            mass_a,
-            mass_b,
-            restitution_a,
-            restitution_b,
-            n,
-        );
+    let sub_dt = config.sub_dt();
+    for _ in 0..config.solver_iterations {
+        for contact in contacts.0.iter_mut() {
+            let ContactManifold {
+                entity_a,
+                entity_b,
+                normal: n,
+                point,
+                position_impulse,
+                tangent_impulse,
+                ..
+            } = *contact;
+            let (
+                (
+                    pos_a,
+                    mut vel_a,
+                    mut ang_vel_a,
+                    pre_solve_vel_a,
+                    pre_solve_ang_vel_a,
+                    mass_a,
+                    inv_inertia_a,
+                    material_a,
+                ),
+                (
+                    pos_b,
+                    mut vel_b,
+                    mut ang_vel_b,
+                    pre_solve_vel_b,
+                    pre_solve_ang_vel_b,
+                    mass_b,
+                    inv_inertia_b,
+                    material_b,
+                ),
+            ) = query.get_pair_mut(entity_a, entity_b).unwrap();
+            let response = constrain_body_velocities(
+                VelBody {
+                    vel: &mut vel_a,
+                    ang_vel: &mut ang_vel_a,
+                    pre_solve_vel: pre_solve_vel_a,
+                    pre_solve_ang_vel: pre_solve_ang_vel_a,
+                    mass: mass_a,
+                    inv_inertia: inv_inertia_a,
+                    r: point - pos_a.0,
+                },
+                VelBody {
+                    vel: &mut vel_b,
+                    ang_vel: &mut ang_vel_b,
+                    pre_solve_vel: pre_solve_vel_b,
+                    pre_solve_ang_vel: pre_solve_ang_vel_b,
+                    mass: mass_b,
+                    inv_inertia: inv_inertia_b,
+                    r: point - pos_b.0,
+                },
+                VelContact {
+                    n,
+                    material: material_a.combine(material_b),
+                    normal_impulse: position_impulse / sub_dt,
+                    friction_applied: tangent_impulse,
+                    restitution_threshold: config.restitution_threshold,
+                },
+            );
+            response.record(contact);
+        }
     }
 }
 
 pub fn solve_vel_statics(
-    mut dynamics: Query<(&mut Vel, &PreSolveVel, &Restitution), With<Mass>>,
-    statics: Query<&Restitution, Without<Mass>>,
-    contacts: Res<StaticContacts>,
+    mut dynamics: Query<
+        (
+            &Pos,
+            &mut Vel,
+            &mut AngVel,
+            &PreSolveVel,
+            &PreSolveAngVel,
+            &Mass,
+            &InvInertia,
+            &PhysicsMaterial,
+        ),
+        With<Mass>,
+    >,
+    // kinematic bodies have a velocity, other static bodies stand still
+    statics: Query<(&PhysicsMaterial, Option<&Vel>), Without<Mass>>,
+    mut contacts: ResMut<StaticContacts>,
+    config: Res<PhysicsConfig>,
 ) {
-    for (entity_a, entity_b, n) in contacts.0.iter().cloned() {
-        let (mut vel_a, pre_solve_vel_a, restitution_a) = dynamics.get_mut(entity_a).unwrap();
-        let restitution_b = statics.get(entity_b).unwrap();
-        constrain_body_velocity(&mut vel_a, pre_solve_vel_a, restitution_a, restitution_b, n);
+    let sub_dt = config.sub_dt();
+    for _ in 0..config.solver_iterations {
+        for contact in contacts.0.iter_mut() {
+            let ContactManifold {
+                entity_a,
+                entity_b,
+                normal: n,
+                point,
+                position_impulse,
+                tangent_impulse,
+                ..
+            } = *contact;
+            let (
+                pos_a,
+                mut vel_a,
+                mut ang_vel_a,
+                pre_solve_vel_a,
+                pre_solve_ang_vel_a,
+                mass_a,
+                inv_inertia_a,
+                material_a,
+            ) = dynamics.get_mut(entity_a).unwrap();
+            let (material_b, vel_b) = statics.get(entity_b).unwrap();
+            let response = constrain_body_velocity(
+                VelBody {
+                    vel: &mut vel_a,
+                    ang_vel: &mut ang_vel_a,
+                    pre_solve_vel: pre_solve_vel_a,
+                    pre_solve_ang_vel: pre_solve_ang_vel_a,
+                    mass: mass_a,
+                    inv_inertia: inv_inertia_a,
+                    r: point - pos_a.0,
+                },
+                vel_b.map_or(Vector::ZERO, |vel| vel.0),
+                VelContact {
+                    n,
+                    material: material_a.combine(material_b),
+                    normal_impulse: position_impulse / sub_dt,
+                    friction_applied: tangent_impulse,
+                    restitution_threshold: config.restitution_threshold,
+                },
+            );
+            response.record(contact);
+        }
+    }
+}
+
+/// Wakes up sleeping bodies that were given a velocity or an impulse since they fell asleep,
+/// before the broadphase decides what they collide with
+pub fn wake_up_moved(
+    mut query: Query<(
+        &Vel,
+        Option<&AngVel>,
+        Option<&ExternalImpulse>,
+        &mut SleepState,
+    )>,
+) {
+    debug!("wake_up_moved");
+    for (vel, ang_vel, impulse, mut sleep) in query.iter_mut() {
+        let moved = vel.0 != Vector::ZERO
+            || ang_vel.map_or(false, |ang_vel| ang_vel.0 != scalar(0.))
+            || impulse.map_or(false, |impulse| impulse.0 != Vector::ZERO);
+        if sleep.is_sleeping() && moved {
+            sleep.wake_up();
+        }
     }
 }
 
-/// Copies positions from the physics world to bevy Transforms
-pub fn sync_transforms(mut query: Query<(&mut bevy::transform::components::Transform, &Pos)>) {
+/// Counts how long each body has been at rest, and puts it to sleep once it's been long enough
+pub fn update_sleep(
+    mut query: Query<(&mut Vel, Option<&mut AngVel>, &mut SleepState), With<Mass>>,
+    config: Res<PhysicsConfig>,
+) {
+    debug!("update_sleep");
+    for (mut vel, mut ang_vel, mut sleep) in query.iter_mut() {
+        let frames = match *sleep {
+            SleepState::Awake(frames) => frames,
+            SleepState::Sleeping => continue,
+        };
+        let at_rest = vel.0.length() < config.sleep_speed()
+            && ang_vel
+                .as_ref()
+                .map_or(true, |ang_vel| ang_vel.0.abs() < scalar(SLEEP_ANG_SPEED));
+        *sleep = if !at_rest {
+            SleepState::Awake(0)
+        } else if frames + 1 < SLEEP_FRAMES {
+            SleepState::Awake(frames + 1)
+        } else {
+            // stop completely, so the body doesn't wake itself up again
+            vel.0 = Vector::ZERO;
+            if let Some(ang_vel) = ang_vel.as_mut() {
+                ang_vel.0 = scalar(0.);
+            }
+            SleepState::Sleeping
+        };
+    }
+}
+
+/// The parts of a body needed to check it for overlaps, whatever its shape
+type ShapeItem<'a> = (&'a Pos, Option<&'a Rot>, ColliderItem<'a>);
+
+/// Finds out which bodies touch after the last substep, and which started or stopped touching.
+/// Sensors are left out by the solvers, so their overlaps are checked here.
+pub fn update_collisions(
+    bodies: Query<(
+        &Pos,
+        Option<&Rot>,
+        Colliders,
+        Option<&Sensor>,
+        Option<&Rollback>,
+    )>,
+    collision_pairs: Res<CollisionPairs>,
+    static_collision_pairs: Res<StaticCollisionPairs>,
+    contacts: Res<Contacts>,
+    static_contacts: Res<StaticContacts>,
+    sleep_states: Query<&SleepState>,
+    mut collisions: ResMut<Collisions>,
+    mut events: ResMut<CollisionEvents>,
+    mut current: Local<Vec<(BodyKey, BodyKey, Entity, Entity)>>,
+) {
+    debug!("update_collisions");
+    current.clear();
+
+    let is_sensor = |entity| matches!(bodies.get(entity), Ok((.., Some(_), _)));
+    let overlaps = |a, b| match (bodies.get(a), bodies.get(b)) {
+        (Ok((pos_a, rot_a, colliders_a, ..)), Ok((pos_b, rot_b, colliders_b, ..))) => {
+            shape_contact((pos_a, rot_a, colliders_a), (pos_b, rot_b, colliders_b)).is_some()
+        }
+        _ => false,
+    };
+    let key = |entity| body_key(entity, bodies.get(entity).ok().and_then(|(.., r)| r));
+
+    let touching = contacts
+        .0
+        .iter()
+        .map(|c| (c.entity_a, c.entity_b))
+        .chain(static_contacts.0.iter().map(|c| (c.entity_a, c.entity_b)));
+    let sensor_overlaps = collision_pairs
+        .0
+        .iter()
+        .chain(static_collision_pairs.0.iter())
+        .cloned()
+        .filter(|&(a, b)| (is_sensor(a) || is_sensor(b)) && overlaps(a, b));
+    // two sleeping bodies aren't paired up, but they still touch
+    let sleeping = |entity| {
+        sleep_states
+            .get(entity)
+            .map_or(false, SleepState::is_sleeping)
+    };
+    let sleeping_pairs = collisions
+        .0
+        .iter()
+        .cloned()
+        .filter(|&(a, b)| sleeping(a) && sleeping(b));
+    for (a, b) in touching.chain(sensor_overlaps).chain(sleeping_pairs) {
+        let (key_a, key_b) = (key(a), key(b));
+        current.push(if key_a < key_b {
+            (key_a, key_b, a, b)
+        } else {
+            (key_b, key_a, b, a)
+        });
+    }
+    // bodies can touch in several places
+    current.sort_unstable_by_key(|(key_a, key_b, ..)| (*key_a, *key_b));
+    current.dedup_by_key(|(key_a, key_b, ..)| (*key_a, *key_b));
+
+    events.started.clear();
+    events.ended.clear();
+    for (.., a, b) in current.iter().cloned() {
+        if !collisions.0.contains(&(a, b)) {
+            events.started.push(CollisionStarted(a, b));
+        }
+    }
+    for (a, b) in collisions.0.iter().cloned() {
+        if !current.iter().any(|&(.., c, d)| (c, d) == (a, b)) {
+            events.ended.push(CollisionEnded(a, b));
+        }
+    }
+
+    collisions.0.clear();
+    collisions
+        .0
+        .extend(current.iter().map(|&(.., a, b)| (a, b)));
+    let Collisions(pairs, by_entity) = &mut *collisions;
+    by_entity.rebuild(pairs.iter().cloned());
+}
+
+/// Copies positions and rotations from the physics world to bevy Transforms
+pub fn sync_transforms(
+    mut query: Query<(
+        &mut bevy::transform::components::Transform,
+        &Pos,
+        Option<&Rot>,
+    )>,
+) {
     debug!("sync_transforms");
-    for (mut transform, pos) in query.iter_mut() {
+    for (mut transform, pos, rot) in query.iter_mut() {
         let z = transform.translation.z;
-        transform.translation = pos.0.extend(z);
+        transform.translation = to_vec2(pos.0).extend(z);
+        if let Some(rot) = rot {
+            transform.rotation = Quat::from_rotation_z(to_f32(rot.0));
+        }
     }
 }
 
 // Helpers, not systems:
 
+/// The parts of a body the position constraints need
+struct PosBody<'a> {
+    pos: &'a mut Pos,
+    rot: &'a mut Rot,
+    mass: &'a Mass,
+    inv_inertia: &'a InvInertia,
+}
+
+/// The parts of a body the velocity constraints need
+struct VelBody<'a> {
+    vel: &'a mut Vel,
+    ang_vel: &'a mut AngVel,
+    pre_solve_vel: &'a PreSolveVel,
+    pre_solve_ang_vel: &'a PreSolveAngVel,
+    mass: &'a Mass,
+    inv_inertia: &'a InvInertia,
+    /// contact point relative to the center of mass
+    r: Vector,
+}
+
+/// A contact as seen by the velocity constraints
+struct VelContact {
+    /// Points from body a to body b
+    n: Vector,
+    /// The combined material of both bodies
+    material: PhysicsMaterial,
+    /// The normal impulse the position solve needed, limits how much friction the contact can apply
+    normal_impulse: Scalar,
+    /// The friction impulse earlier solver iterations already applied
+    friction_applied: Scalar,
+    restitution_threshold: Scalar,
+}
+
+/// What the velocity constraints did to a contact
+struct VelResponse {
+    /// Impulse on body a
+    impulse: Vector,
+    normal_impulse: Scalar,
+    tangent_impulse: Scalar,
+    impact_speed: Scalar,
+}
+
+impl VelResponse {
+    fn record(&self, contact: &mut ContactManifold) {
+        // summed over the solver iterations
+        contact.normal_impulse += self.normal_impulse;
+        contact.tangent_impulse += self.tangent_impulse;
+        contact.impact_speed = self.impact_speed;
+    }
+}
+
+impl VelBody<'_> {
+    fn pre_solve_point_vel(&self) -> Vector {
+        point_vel(self.pre_solve_vel.0, self.pre_solve_ang_vel.0, self.r)
+    }
+
+    fn point_vel(&self) -> Vector {
+        point_vel(self.vel.0, self.ang_vel.0, self.r)
+    }
+
+    fn apply_impulse(&mut self, impulse: Vector) {
+        self.vel.0 += impulse / self.mass.0;
+        self.ang_vel.0 += self.inv_inertia.0 * self.r.perp_dot(impulse);
+    }
+}
+
+/// Velocity of the point at `r` on a body moving with `vel` and spinning with `ang_vel`
+fn point_vel(vel: Vector, ang_vel: Scalar, r: Vector) -> Vector {
+    vel + ang_vel * r.perp()
+}
+
+/// Narrowphase for two bodies of any shape, for when there is no dedicated solver for the pair
+fn shape_contact(
+    (pos_a, rot_a, colliders_a): ShapeItem,
+    (pos_b, rot_b, colliders_b): ShapeItem,
+) -> Option<Contact> {
+    let rot_a = rot_a.map_or(scalar(0.), |rot| rot.0);
+    let rot_b = rot_b.map_or(scalar(0.), |rot| rot.0);
+    contact::shape_shape(
+        pos_a.0,
+        rot_a,
+        Shape::of(colliders_a)?,
+        pos_b.0,
+        rot_b,
+        Shape::of(colliders_b)?,
+    )
+}
+
+/// Inverse mass of a body as seen from a constraint applied at `r` along `n`
+fn generalized_inverse_mass(mass: &Mass, inv_inertia: &InvInertia, r: Vector, n: Vector) -> Scalar {
+    let rn = r.perp_dot(n);
+    scalar(1.) / mass.0 + inv_inertia.0 * rn * rn
+}
+
 fn constrain_body_positions(
-    pos_a: &mut Pos,
-    pos_b: &mut Pos,
-    mass_a: &Mass,
-    mass_b: &Mass,
-    n: Vec2,
-    penetration_depth: f32,
-) {
-    let w_a = 1. / mass_a.0;
-    let w_b = 1. / mass_b.0;
+    a: PosBody,
+    b: PosBody,
+    n: Vector,
+    penetration_depth: Scalar,
+    point: Vector,
+) -> Scalar {
+    let r_a = point - a.pos.0;
+    let r_b = point - b.pos.0;
+    let w_a = generalized_inverse_mass(a.mass, a.inv_inertia, r_a, n);
+    let w_b = generalized_inverse_mass(b.mass, b.inv_inertia, r_b, n);
     let w_sum = w_a + w_b;
     let pos_impulse = n * (-penetration_depth / w_sum);
-    pos_a.0 += pos_impulse * w_a;
-    pos_b.0 -= pos_impulse * w_b;
+    a.pos.0 += pos_impulse / a.mass.0;
+    b.pos.0 -= pos_impulse / b.mass.0;
+    a.rot.0 += a.inv_inertia.0 * r_a.perp_dot(pos_impulse);
+    b.rot.0 -= b.inv_inertia.0 * r_b.perp_dot(pos_impulse);
+    penetration_depth / w_sum
+}
+
+/// How far in meters a body may have been inside a one-way platform and still land on it.
+/// Scaled by [`PhysicsConfig::unit_scale`].
+const ONE_WAY_SLOP: f32 = 0.5;
+
+/// Replaces a contact with a one-way platform by one along the normal of the platform,
+/// or drops it if the body came from the other side.
+/// `slop` is how far the body may have been inside the platform before,
+/// `body` and `platform` are the previous and current positions,
+/// `extents` is how far both shapes reach along the normal combined.
+fn one_way_contact(
+    contact: Contact,
+    one_way: &OneWay,
+    slop: Scalar,
+    body: (Vector, Vector),
+    platform: (Vector, Vector),
+    extents: Scalar,
+) -> Option<Contact> {
+    let n = one_way.normal;
+    let prev_separation = (body.0 - platform.0).dot(n);
+    if prev_separation < extents - slop {
+        return None;
+    }
+    let penetration = extents - (body.1 - platform.1).dot(n);
+    if penetration <= scalar(0.) {
+        return None;
+    }
+    Some(Contact {
+        // contact normals point from the body to the platform
+        normal: -n,
+        penetration,
+        point: contact.point,
+    })
 }
 
-// todo: just inline this, it's not worth a function
-fn constrain_body_position(pos: &mut Pos, n: Vec2, penetration_depth: f32) {
-    pos.0 -= n * penetration_depth;
+/// Pushes a single body out of a static one
+fn constrain_body_position(
+    a: PosBody,
+    n: Vector,
+    penetration_depth: Scalar,
+    point: Vector,
+) -> Scalar {
+    let r = point - a.pos.0;
+    let w = generalized_inverse_mass(a.mass, a.inv_inertia, r, n);
+    let pos_impulse = n * (-penetration_depth / w);
+    a.pos.0 += pos_impulse / a.mass.0;
+    a.rot.0 += a.inv_inertia.0 * r.perp_dot(pos_impulse);
+    penetration_depth / w
 }
 
-fn constrain_body_velocities(
-    vel_a: &mut Vel,
-    vel_b: &mut Vel,
-    pre_solve_vel_a: &PreSolveVel,
-    pre_solve_vel_b: &PreSolveVel,
-    mass_a: &Mass,
-    mass_b: &Mass,
-    restitution_a: &Restitution,
-    restitution_b: &Restitution,
-    n: Vec2,
-) {
-    let pre_solve_relative_vel = pre_solve_vel_a.0 - pre_solve_vel_b.0;
-    let pre_solve_normal_vel = Vec2::dot(pre_solve_relative_vel, n);
+fn constrain_body_velocities(mut a: VelBody, mut b: VelBody, contact: VelContact) -> VelResponse {
+    let pre_solve_relative_vel = a.pre_solve_point_vel() - b.pre_solve_point_vel();
+    let relative_vel = a.point_vel() - b.point_vel();
+    let response = contact_vel_impulse(
+        pre_solve_relative_vel,
+        relative_vel,
+        |dir| {
+            generalized_inverse_mass(a.mass, a.inv_inertia, a.r, dir)
+                + generalized_inverse_mass(b.mass, b.inv_inertia, b.r, dir)
+        },
+        &contact,
+    );
 
-    let relative_vel = vel_a.0 - vel_b.0;
-    let normal_vel = Vec2::dot(relative_vel, n);
-    let restitution = (restitution_a.0 + restitution_b.0) / 2.;
+    a.apply_impulse(response.impulse);
+    b.apply_impulse(-response.impulse);
+    response
+}
 
-    let w_a = 1. / mass_a.0;
-    let w_b = 1. / mass_b.0;
-    let w_sum = w_a + w_b;
+/// Like [`constrain_body_velocities`], against a static or kinematic body moving at `static_vel`
+fn constrain_body_velocity(mut a: VelBody, static_vel: Vector, contact: VelContact) -> VelResponse {
+    let response = contact_vel_impulse(
+        a.pre_solve_point_vel() - static_vel,
+        a.point_vel() - static_vel,
+        |dir| generalized_inverse_mass(a.mass, a.inv_inertia, a.r, dir),
+        &contact,
+    );
+
+    a.apply_impulse(response.impulse);
+    response
+}
 
-    let restitution_velocity = (-restitution * pre_solve_normal_vel).min(0.);
-    let vel_impulse = n * ((-normal_vel + restitution_velocity) / w_sum);
+/// Impulse on body a that applies restitution along the normal and coulomb friction along the tangent.
+/// `inverse_mass` is the combined generalized inverse mass of both bodies in a given direction.
+fn contact_vel_impulse(
+    pre_solve_relative_vel: Vector,
+    relative_vel: Vector,
+    inverse_mass: impl Fn(Vector) -> Scalar,
+    contact: &VelContact,
+) -> VelResponse {
+    let n = contact.n;
+    let pre_solve_normal_vel = Vector::dot(pre_solve_relative_vel, n);
+    let normal_vel = Vector::dot(relative_vel, n);
 
-    vel_a.0 += vel_impulse * w_a;
-    vel_b.0 -= vel_impulse * w_b;
+    // slow contacts don't bounce, so resting bodies don't jitter
+    let restitution = if pre_solve_normal_vel > contact.restitution_threshold {
+        contact.material.restitution
+    } else {
+        scalar(0.)
+    };
+    let restitution_velocity = (-restitution * pre_solve_normal_vel).min(scalar(0.));
+    let normal_impulse = (normal_vel - restitution_velocity) / inverse_mass(n);
+    let mut vel_impulse = n * -normal_impulse;
+
+    let mut friction_impulse = scalar(0.);
+    let tangent_vel = relative_vel - n * normal_vel;
+    let tangent_speed = tangent_vel.length();
+    if tangent_speed > scalar(0.) {
+        let tangent = tangent_vel / tangent_speed;
+        // the impulse that would stop the sliding completely
+        let stick_impulse = tangent_speed / inverse_mass(tangent);
+        // the friction limits hold for the sum over all solver iterations
+        let static_limit =
+            contact.material.static_friction * contact.normal_impulse - contact.friction_applied;
+        let dynamic_limit =
+            contact.material.dynamic_friction * contact.normal_impulse - contact.friction_applied;
+        friction_impulse = if stick_impulse <= static_limit {
+            stick_impulse
+        } else {
+            dynamic_limit.min(stick_impulse).max(scalar(0.))
+        };
+        vel_impulse -= tangent * friction_impulse;
+    }
+
+    VelResponse {
+        impulse: vel_impulse,
+        normal_impulse,
+        tangent_impulse: friction_impulse,
+        impact_speed: pre_solve_normal_vel.max(scalar(0.)),
+    }
 }
 
-fn constrain_body_velocity(
-    vel_a: &mut Vel,
-    pre_solve_vel_a: &PreSolveVel,
-    restitution_a: &Restitution,
-    restitution_b: &Restitution,
-    n: Vec2,
-) {
-    let pre_solve_normal_vel = Vec2::dot(pre_solve_vel_a.0, n);
-    let normal_vel = Vec2::dot(vel_a.0, n);
-    let restitution = (restitution_a.0 + restitution_b.0) / 2.;
-    vel_a.0 += n * (-normal_vel + (-restitution * pre_solve_normal_vel).min(0.));
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ccd_moves_both_bodies_back() {
+        let mut world = World::default();
+        let mut ball = |from: f32, to: f32| {
+            world
+                .spawn()
+                .insert(Pos(Vector::new(scalar(to), scalar(0.))))
+                .insert(PrevPos(Vector::new(scalar(from), scalar(0.))))
+                .insert(CircleCollider {
+                    radius: scalar(0.5),
+                })
+                .id()
+        };
+        // heading straight through each other in a single substep
+        let a = ball(-5., 5.);
+        let b = ball(5., -5.);
+        world.entity_mut(a).insert(Ccd);
+        world.insert_resource(CollisionPairs(vec![(a, b)]));
+        world.insert_resource(StaticCollisionPairs::default());
+        let config = PhysicsConfig::with_unit_scale(scalar(2.));
+        let skin = scalar(CCD_SKIN) * config.unit_scale;
+        world.insert_resource(config);
+
+        SystemStage::single(solve_ccd).run(&mut world);
+        let (pos_a, pos_b) = (
+            world.get::<Pos>(a).unwrap().0,
+            world.get::<Pos>(b).unwrap().0,
+        );
+        // touching, but for the skin that leaves the contact to the position solvers
+        let gap = pos_b.x - pos_a.x - scalar(1.);
+        assert!((gap + skin).abs() < scalar(0.001), "gap of {:?}", gap);
+        // both moved the same fraction of their motion
+        assert!((pos_a.x + pos_b.x - skin).abs() < scalar(0.001));
+    }
 }
@@ -1,5 +1,7 @@
 use bevy::prelude::*;
 
+use crate::{checksum::ReflectChecksum, desync::ReflectDebug};
+
 #[derive(Default, Component, Reflect)]
 #[reflect(Component)]
 pub struct Attacker {
@@ -60,8 +62,8 @@ pub struct DefenderControls {
     pub fire: bool,
 }
 
-#[derive(Clone, Copy, Component, Reflect, Debug, PartialEq, Eq)]
-#[reflect(Component)]
+#[derive(Clone, Copy, Component, Reflect, Debug, PartialEq, Eq, Hash)]
+#[reflect(Component, Hash, Checksum, Debug)]
 pub enum FacingDirection {
     Left,
     Right,
@@ -74,8 +76,8 @@ impl Default for FacingDirection {
 }
 
 // the usize counts the number of frames the attacker has been in that state
-#[derive(Clone, Copy, Component, Reflect, Debug)]
-#[reflect(Component)]
+#[derive(Clone, Copy, Component, Reflect, Debug, Hash)]
+#[reflect(Component, Hash, Checksum, Debug)]
 pub enum AttackerState {
     Idle(usize),
     Jump(usize),
@@ -133,8 +135,8 @@ impl Default for AttackerState {
 }
 
 // the usize counts the number of frames the defender has been in that state
-#[derive(Clone, Copy, Component, Reflect, Debug)]
-#[reflect(Component)]
+#[derive(Clone, Copy, Component, Reflect, Debug, Hash)]
+#[reflect(Component, Hash, Checksum, Debug)]
 pub enum DefenderState {
     Idle(usize),
     Fire(usize),
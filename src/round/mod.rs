@@ -21,12 +21,24 @@ const FRAMES_PER_SPRITE: usize = 10; // TODO: variable frame length per animatio
 // physics param
 const ATTACKER_SIZE: f32 = 24.;
 const MAX_SPEED: f32 = 100.;
-pub const JUMP_HEIGHT: f32 = 2. * ATTACKER_SIZE;
-pub const JUMP_TIME_TO_PEAK: f32 = 1.;
+/// How quickly the janitor gets up to speed, has to beat the friction that stops him
+const MAX_ACCEL: f32 = 2000.;
+const JUMP_HEIGHT: f32 = 2. * ATTACKER_SIZE;
+const JUMP_TIME_TO_PEAK: f32 = 1.;
 const DEFENDER_SIZE: f32 = 168.;
 const GROUND_LEVEL: f32 = -100.;
 const CAKE_SIZE: f32 = 16.;
 
+// physics
+const PIXELS_PER_METER: f32 = 24.0 / 1.8; // assuming janitor is 1.80 tall and 24 pixels tall
+const PHYSICS_SUBSTEPS: u32 = 4;
+
+// collision layers
+const LAYER_WORLD: u32 = 1 << 0;
+const LAYER_ATTACKER: u32 = 1 << 1;
+const LAYER_CAKE: u32 = 1 << 2;
+const LAYER_SPLAT: u32 = 1 << 3;
+
 // controls
 const CROSSHAIR_SPEED: f32 = 3.;
 const IDLE_THRESH: f32 = 0.01;
@@ -45,3 +57,5 @@ const DEF_X_POS: f32 = 250.;
 const MIN_SPLAT: u32 = 1;
 const MAX_SPLAT: u32 = 5;
 const SPLAT_SPREAD: f32 = 20.;
+/// Cakes that hit something at least this fast splat into MAX_SPLAT pieces
+const SPLAT_IMPACT_SPEED: f32 = 400.;
@@ -1,6 +1,8 @@
 use bevy::{prelude::*, utils::HashMap};
 use bytemuck::{Pod, Zeroable};
 
+use crate::{checksum::ReflectChecksum, desync::ReflectDebug};
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, PartialEq, Pod, Zeroable)]
 pub struct Input {
@@ -13,8 +15,8 @@ pub struct FrameCount {
     pub frame: u32,
 }
 
-#[derive(Copy, Clone, Reflect, Hash, Component)]
-#[reflect(Hash)]
+#[derive(Copy, Clone, Debug, Reflect, Hash, Component)]
+#[reflect(Hash, Checksum, Debug)]
 pub enum RoundState {
     InterludeStart,
     Interlude,
@@ -31,6 +33,8 @@ pub enum ConnectionStatus {
     Running,
     Interrupted,
     Disconnected,
+    /// The peers disagree about the state of the game
+    Desynced,
 }
 
 impl std::fmt::Display for ConnectionStatus {
@@ -40,6 +44,7 @@ impl std::fmt::Display for ConnectionStatus {
             ConnectionStatus::Running => write!(f, "Running"),
             ConnectionStatus::Interrupted => write!(f, "Interrupted"),
             ConnectionStatus::Disconnected => write!(f, "Disconnected"),
+            ConnectionStatus::Desynced => write!(f, "Desynced"),
         }
     }
 }
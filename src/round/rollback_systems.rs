@@ -4,7 +4,6 @@ use ggrs::InputStatus;
 use rand::{Rng, SeedableRng};
 
 use crate::{
-    checksum::Checksum,
     menu::{connect::LocalHandles, win::MatchResult},
     physics::prelude::*,
     round::{prelude::*, resources::Input},
@@ -15,8 +14,9 @@ use crate::{
 use super::{
     ATTACKER_SIZE, CAKE_SIZE, CROSSHAIR_SPEED, DEFENDER_SIZE, DEF_X_POS, FRAMES_PER_SPRITE,
     GROUND_LEVEL, IDLE_THRESH, INPUT_ACT, INPUT_DOWN, INPUT_LEFT, INPUT_RIGHT, INPUT_UP,
-    INTERLUDE_LENGTH, JUMP_HEIGHT, JUMP_TIME_TO_PEAK, LAND_FRAMES, MAX_SPEED, MAX_SPLAT, MIN_SPLAT,
-    NUM_ROUNDS, ROUND_LENGTH, SPLAT_SPREAD, STUN_FRAMES,
+    INTERLUDE_LENGTH, JUMP_HEIGHT, JUMP_TIME_TO_PEAK, LAND_FRAMES, LAYER_ATTACKER, LAYER_CAKE,
+    LAYER_SPLAT, LAYER_WORLD, MAX_ACCEL, MAX_SPEED, MAX_SPLAT, MIN_SPLAT, NUM_ROUNDS, ROUND_LENGTH,
+    SPLAT_IMPACT_SPEED, SPLAT_SPREAD, STUN_FRAMES,
 };
 
 /*
@@ -97,47 +97,32 @@ pub fn spawn_world(
     mut rip: ResMut<RollbackIdProvider>,
     font_assets: Res<FontAssets>,
 ) {
-    // todo: could import the body builder from bevy_xpbd to clean this up
     let ground_size = Vec2::new(2000., 2000.); // should just be bigger than the screen
+    let wall = |x: f32, y: f32| {
+        RigidBodyBuilder::new_static()
+            .with_pos(vector(Vec2::new(x, y)))
+            .with_box(vector(ground_size))
+            .with_layers(CollisionLayers::new(LAYER_WORLD, CollisionLayers::ALL))
+    };
 
     // ground
     commands
-        .spawn_bundle(StaticBoxBundle {
-            pos: Pos(Vec2::new(0., -ground_size.y / 2. + GROUND_LEVEL)),
-            collider: BoxCollider { size: ground_size },
-            ..Default::default()
-        })
-        .insert(Rollback::new(rip.next_id()))
+        .spawn_body(wall(0., -ground_size.y / 2. + GROUND_LEVEL), &mut rip)
         .insert(RoundEntity);
 
     // left
     commands
-        .spawn_bundle(StaticBoxBundle {
-            pos: Pos(Vec2::new(-SCREEN_X / 4. - ground_size.y / 2., 0.)),
-            collider: BoxCollider { size: ground_size },
-            ..Default::default()
-        })
-        .insert(Rollback::new(rip.next_id()))
+        .spawn_body(wall(-SCREEN_X / 4. - ground_size.y / 2., 0.), &mut rip)
         .insert(RoundEntity);
 
     // right
     commands
-        .spawn_bundle(StaticBoxBundle {
-            pos: Pos(Vec2::new(SCREEN_X / 4. + ground_size.y / 2., 0.)),
-            collider: BoxCollider { size: ground_size },
-            ..Default::default()
-        })
-        .insert(Rollback::new(rip.next_id()))
+        .spawn_body(wall(SCREEN_X / 4. + ground_size.y / 2., 0.), &mut rip)
         .insert(RoundEntity);
 
     // up
     commands
-        .spawn_bundle(StaticBoxBundle {
-            pos: Pos(Vec2::new(0., SCREEN_Y / 4. + ground_size.y / 2.)),
-            collider: BoxCollider { size: ground_size },
-            ..Default::default()
-        })
-        .insert(Rollback::new(rip.next_id()))
+        .spawn_body(wall(0., SCREEN_Y / 4. + ground_size.y / 2.), &mut rip)
         .insert(RoundEntity);
 
     // screen timer
@@ -183,19 +168,29 @@ pub fn spawn_attackers(
                 texture_atlas: sprites.janitor_idle.clone(),
                 ..Default::default()
             })
-            .insert_bundle(DynamicBoxBundle {
-                pos: Pos(Vec2::new(x, y)),
-                collider: BoxCollider {
-                    size: Vec2::new(ATTACKER_SIZE / 2., ATTACKER_SIZE),
-                },
-                ..Default::default()
-            })
+            .insert_body(
+                RigidBodyBuilder::new_dynamic()
+                    .with_pos(vector(Vec2::new(x, y)))
+                    // rounded, so the janitor doesn't snag on corners
+                    .with_capsule(scalar(ATTACKER_SIZE / 4.), scalar(ATTACKER_SIZE / 4.))
+                    // friction is what stops him once he lets go of the controls
+                    .with_material(PhysicsMaterial {
+                        static_friction: scalar(0.8),
+                        dynamic_friction: scalar(0.6),
+                        friction_combine: CombineRule::Max,
+                        ..Default::default()
+                    })
+                    .with_layers(CollisionLayers::new(
+                        LAYER_ATTACKER,
+                        LAYER_WORLD | LAYER_ATTACKER | LAYER_CAKE | LAYER_SPLAT,
+                    )),
+                &mut rip,
+            )
+            .insert(ExternalImpulse::default())
             .insert(Attacker { handle })
             .insert(AttackerState::Idle(0))
             .insert(FacingDirection::Right)
             .insert(AttackerControls::default())
-            .insert(Checksum::default())
-            .insert(Rollback::new(rip.next_id()))
             .insert(RoundEntity);
     }
 }
@@ -222,7 +217,6 @@ pub fn spawn_defender(
         .insert(DefenderState::Idle(0))
         .insert(FacingDirection::Right)
         .insert(DefenderControls::default())
-        .insert(Checksum::default())
         .insert(Rollback::new(rip.next_id()))
         .insert(RoundEntity);
 
@@ -234,7 +228,6 @@ pub fn spawn_defender(
             ..Default::default()
         })
         .insert(Crosshair)
-        .insert(Checksum::default())
         .insert(Rollback::new(rip.next_id()))
         .insert(RoundEntity);
 }
@@ -253,7 +246,7 @@ pub fn update_defender_state(
     mut commands: Commands,
     sprites: Res<MiscAssets>,
     mut rip: ResMut<RollbackIdProvider>,
-    gravity: Res<Gravity>,
+    physics_config: Res<PhysicsConfig>,
     mut def_query: Query<(&Transform, &DefenderControls, &mut DefenderState)>,
     crosshair_query: Query<&Transform, With<Crosshair>>,
 ) {
@@ -289,27 +282,40 @@ pub fn update_defender_state(
 
     for t in crosshair_query.iter() {
         if should_shoot {
-            let dist_x = (t.translation.x - cake_x).min(0.);
-            let dist_y = (t.translation.y - cake_y).max(0.);
-            let cake_vx = 2. * dist_x / JUMP_TIME_TO_PEAK; // TODO: is this correct correct if the crosshair is supposed to be the apex of the parabola?
-            let cake_vy = f32::sqrt(-2. * dist_y * gravity.0.y);
+            // the launch velocity is computed with physics numbers, so it's the same on all peers
+            let dist_x = scalar((t.translation.x - cake_x).min(0.));
+            let dist_y = scalar((t.translation.y - cake_y).max(0.));
+            let cake_vx = scalar(2.) * dist_x / scalar(JUMP_TIME_TO_PEAK); // TODO: is this correct correct if the crosshair is supposed to be the apex of the parabola?
+            let cake_vy = (scalar(-2.) * dist_y * physics_config.gravity.y).sqrt();
             commands
                 .spawn_bundle(SpriteBundle {
                     texture: sprites.cake.clone(),
                     transform: Transform::from_xyz(cake_x, cake_y, 10.),
                     ..Default::default()
                 })
-                .insert_bundle(DynamicBoxBundle {
-                    pos: Pos(Vec2::new(cake_x, cake_y)),
-                    collider: BoxCollider {
-                        size: Vec2::new(CAKE_SIZE, CAKE_SIZE),
-                    },
-                    vel: Vel(Vec2::new(cake_vx, cake_vy)),
-                    ..Default::default()
-                })
+                .insert_body(
+                    RigidBodyBuilder::new_dynamic()
+                        .with_pos(vector(Vec2::new(cake_x, cake_y)))
+                        .with_box(vector(Vec2::new(CAKE_SIZE, CAKE_SIZE)))
+                        .rotating()
+                        .with_vel(Vector::new(cake_vx, cake_vy))
+                        // sticky, so it doesn't skate along whatever it hits, and tumbles instead
+                        .with_material(PhysicsMaterial {
+                            static_friction: scalar(0.8),
+                            dynamic_friction: scalar(0.6),
+                            friction_combine: CombineRule::Max,
+                            ..Default::default()
+                        })
+                        // cakes fly through each other
+                        .with_layers(CollisionLayers::new(
+                            LAYER_CAKE,
+                            LAYER_WORLD | LAYER_ATTACKER,
+                        )),
+                    &mut rip,
+                )
                 .insert(Cake)
-                .insert(Checksum::default())
-                .insert(Rollback::new(rip.next_id()))
+                // fast enough to skip past a janitor or the floor in one frame
+                .insert(Ccd)
                 .insert(RoundEntity);
         }
     }
@@ -329,6 +335,7 @@ pub fn update_attacker_state(
         &mut FacingDirection,
     )>,
 ) {
+    let idle_thresh = scalar(IDLE_THRESH);
     for (id, vel, contr, mut state, mut face_dir) in query.iter_mut() {
         // update facing direction
         if contr.horizontal < -IDLE_THRESH {
@@ -342,11 +349,11 @@ pub fn update_attacker_state(
         //update state
         match *state {
             AttackerState::Idle(ref mut f) => {
-                if vel.0.y < -IDLE_THRESH {
+                if vel.0.y < -idle_thresh {
                     *state = AttackerState::Fall(0);
                     continue;
                 }
-                if vel.0.y > IDLE_THRESH {
+                if vel.0.y > idle_thresh {
                     *state = AttackerState::Jump(0);
                     continue;
                 }
@@ -357,26 +364,15 @@ pub fn update_attacker_state(
                 *f += 1;
             }
             AttackerState::Jump(ref mut f) => {
-                if vel.0.y < IDLE_THRESH {
+                if vel.0.y < idle_thresh {
                     *state = AttackerState::Fall(0);
                     continue;
                 }
                 *f += 1;
             }
             AttackerState::Fall(ref mut f) => {
-                if static_contacts
-                    .0
-                    .iter()
-                    .any(|(e, _, n)| *e == id && n.y < 0.)
-                    || contacts.0.iter().any(|(a, b, n)| {
-                        if *a == id {
-                            n.y < 0.
-                        } else if *b == id {
-                            n.y > 0.
-                        } else {
-                            false
-                        }
-                    })
+                if static_contacts.with(id).any(|c| c.normal.y < scalar(0.))
+                    || contacts.with(id).any(|c| c.normal.y < scalar(0.))
                 {
                     *state = AttackerState::Land(0);
                     continue;
@@ -384,7 +380,7 @@ pub fn update_attacker_state(
                 *f += 1;
             }
             AttackerState::Land(ref mut f) => {
-                if vel.0.y < -IDLE_THRESH {
+                if vel.0.y < -idle_thresh {
                     *state = AttackerState::Fall(0);
                     continue;
                 }
@@ -395,11 +391,11 @@ pub fn update_attacker_state(
                 *f += 1;
             }
             AttackerState::Walk(ref mut f) => {
-                if vel.0.y < -IDLE_THRESH {
+                if vel.0.y < -idle_thresh {
                     *state = AttackerState::Fall(0);
                     continue;
                 }
-                if vel.0.y > IDLE_THRESH {
+                if vel.0.y > idle_thresh {
                     *state = AttackerState::Jump(0);
                     continue;
                 }
@@ -481,23 +477,46 @@ pub fn apply_defender_inputs(
 }
 
 pub fn move_attackers(
-    mut query: Query<(&mut Vel, &AttackerState, &AttackerControls), With<Rollback>>,
-    gravity: Res<Gravity>,
+    mut commands: Commands,
+    mut query: Query<
+        (
+            Entity,
+            &Vel,
+            &Mass,
+            &mut ExternalImpulse,
+            &AttackerState,
+            &AttackerControls,
+            Option<&DropThrough>,
+        ),
+        With<Rollback>,
+    >,
+    physics_config: Res<PhysicsConfig>,
 ) {
-    for (mut vel, state, controls) in query.iter_mut() {
-        // just set horizontal velocity for now
-        // this totally overwrites any velocity on the x axis, which might not be ideal...
-        vel.0.x = 0.;
-        if state.can_walk() {
-            vel.0.x = controls.horizontal * MAX_SPEED;
+    for (entity, vel, mass, mut impulse, state, controls, drop_through) in query.iter_mut() {
+        // accelerate towards the walking speed, but no harder than a janitor can push off,
+        // so knockback and moving platforms still carry him along. Without input, friction stops him.
+        if state.can_walk() && controls.horizontal.abs() > IDLE_THRESH {
+            let target_vx = scalar(controls.horizontal * MAX_SPEED);
+            let max_dv = scalar(MAX_ACCEL) * physics_config.timestep;
+            let dv = (target_vx - vel.0.x).max(-max_dv).min(max_dv);
+            impulse.0.x += mass.0 * dv;
         }
 
         if controls.vertical > 0. && state.can_jump() {
-            let v0 = f32::sqrt(-2. * JUMP_HEIGHT * gravity.0.y);
-            vel.0.y = controls.vertical * v0;
+            let v0 = (scalar(-2. * JUMP_HEIGHT) * physics_config.gravity.y).sqrt();
+            let target_vy = scalar(controls.vertical) * v0;
+            impulse.0.y += mass.0 * (target_vy - vel.0.y);
             // vel.0.y = controls.accel * MAX_SPEED;
         }
 
+        // holding down drops through one-way platforms
+        let dropping = controls.vertical < 0.;
+        if dropping && drop_through.is_none() {
+            commands.entity(entity).insert(DropThrough);
+        } else if !dropping && drop_through.is_some() {
+            commands.entity(entity).remove::<DropThrough>();
+        }
+
         // todo: could just be added in the physics inner loop system
         // // constrain cube to plane
         // let bounds = (ARENA_SIZE - CUBE_SIZE) * 0.5;
@@ -529,6 +548,7 @@ pub fn move_crosshair(
 
 pub fn cake_collision(
     mut commands: Commands,
+    collision_events: Res<CollisionEvents>,
     contacts: Res<Contacts>,
     static_contacts: Res<StaticContacts>,
     mut rip: ResMut<RollbackIdProvider>,
@@ -538,34 +558,42 @@ pub fn cake_collision(
     cakes: Query<(Entity, &Transform), With<Cake>>,
 ) {
     for (cake, t) in cakes.iter() {
-        let mut cake_collided = false;
+        // how hard the cake hit whatever it hit, if it hit anything
+        let mut impact_speed: Option<Scalar> = None;
+        let mut hit = |speed: Scalar| {
+            impact_speed = Some(impact_speed.map_or(speed, |fastest| fastest.max(speed)));
+        };
         //check for attacker collision
         for (attacker, mut state) in attackers.iter_mut() {
-            if contacts
-                .0
+            if collision_events
+                .started
                 .iter()
-                .any(|(a, c, _)| *a == attacker && *c == cake)
+                .any(|collision| collision.is(attacker, cake))
             {
                 if !state.is_stunned() {
                     *state = AttackerState::Hit(0);
                 }
-                cake_collided = true;
+                let speed = contacts
+                    .between(cake, attacker)
+                    .map_or(scalar(0.), |c| c.impact_speed);
+                hit(speed);
             }
         }
         // check for ground collision
-        if static_contacts
-            .0
-            .iter()
-            .any(|(c, _, n)| *c == cake && n.y < 0.)
-        {
-            cake_collided = true;
+        for contact in static_contacts.with(cake) {
+            if contact.normal.y < scalar(0.) {
+                hit(contact.impact_speed);
+            }
         }
         // splat
-        if cake_collided {
+        if let Some(impact_speed) = impact_speed {
             commands.entity(cake).despawn_recursive();
 
+            // harder hits make more of a mess
+            let impact = (to_f32(impact_speed) / SPLAT_IMPACT_SPEED).min(1.);
+            let max_splat = MIN_SPLAT + ((MAX_SPLAT - MIN_SPLAT) as f32 * impact).round() as u32;
             let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(frame_count.frame as u64);
-            for i in 0..rng.gen_range(MIN_SPLAT..MAX_SPLAT) {
+            for i in 0..rng.gen_range(MIN_SPLAT..=max_splat) {
                 let rand_splat = rng.gen::<f32>() * 2. - 1.; // between -1 and 1
                 let mut x_pos: f32 = t.translation.x + rand_splat * SPLAT_SPREAD;
                 x_pos = x_pos.clamp(-SCREEN_X / 4. + 13., SCREEN_X / 4.);
@@ -580,9 +608,16 @@ pub fn cake_collision(
                         transform: Transform::from_xyz(x_pos, GROUND_LEVEL + 12., 10.),
                         ..Default::default()
                     })
+                    // janitors walk over splats and clean them up
+                    .insert_body(
+                        RigidBodyBuilder::new_static()
+                            .with_pos(vector(Vec2::new(x_pos, GROUND_LEVEL + 12.)))
+                            .with_box(vector(Vec2::new(2., 24.)))
+                            .with_layers(CollisionLayers::new(LAYER_SPLAT, LAYER_ATTACKER)),
+                        &mut rip,
+                    )
+                    .insert(Sensor)
                     .insert(Splat)
-                    .insert(Checksum::default())
-                    .insert(Rollback::new(rip.next_id()))
                     .insert(RoundEntity);
             }
         }
@@ -591,18 +626,20 @@ pub fn cake_collision(
 
 pub fn splat_cleaning(
     mut commands: Commands,
-    attackers: Query<(&Transform, &AttackerState), With<Attacker>>,
-    splats: Query<(Entity, &Transform), With<Splat>>,
+    collisions: Res<Collisions>,
+    attackers: Query<(Entity, &AttackerState), With<Attacker>>,
+    splats: Query<Entity, With<Splat>>,
 ) {
-    for (t_attack, state) in attackers.iter() {
+    for (attacker, state) in attackers.iter() {
         if !state.can_clean() {
             continue;
         }
 
-        for (splat, t_splat) in splats.iter() {
-            if (t_splat.translation.x - t_attack.translation.x).abs() < 1. {
-                commands.entity(splat).despawn_recursive();
-            }
+        for splat in collisions
+            .with(attacker)
+            .filter(|entity| splats.get(*entity).is_ok())
+        {
+            commands.entity(splat).despawn_recursive();
         }
     }
 }
@@ -671,3 +708,67 @@ pub fn cleanup_round(
     }
     //println!("\nROUND END {:?}", *round_data);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::{components::PrevPos, SLEEP_FRAMES};
+
+    #[test]
+    fn sleeping_janitor_still_cleans() {
+        let mut app = App::new();
+        app.add_plugin(PhysicsPlugin::standalone())
+            .add_system(splat_cleaning);
+
+        // ground with its top at y = 0
+        app.world.spawn().insert_bundle(StaticBoxBundle {
+            pos: Pos(Vector::new(scalar(0.), scalar(-0.5))),
+            collider: BoxCollider {
+                size: Vector::new(scalar(20.), scalar(1.)),
+            },
+            ..Default::default()
+        });
+        let janitor_pos = Vector::new(scalar(0.), scalar(0.5));
+        let janitor = app
+            .world
+            .spawn()
+            .insert_bundle(DynamicBoxBundle {
+                pos: Pos(janitor_pos),
+                prev_pos: PrevPos(janitor_pos),
+                ..Default::default()
+            })
+            .insert(Attacker { handle: 0 })
+            .insert(AttackerState::Idle(0))
+            .id();
+        for _ in 0..4 * SLEEP_FRAMES {
+            app.update();
+            if app.world.get::<SleepState>(janitor).unwrap().is_sleeping() {
+                break;
+            }
+        }
+        assert!(
+            app.world.get::<SleepState>(janitor).unwrap().is_sleeping(),
+            "the janitor should have fallen asleep"
+        );
+
+        // lands on him while he's asleep
+        let splat = app
+            .world
+            .spawn()
+            .insert_bundle(StaticBoxBundle {
+                pos: Pos(janitor_pos),
+                ..Default::default()
+            })
+            .insert(Sensor)
+            .insert(Splat)
+            .id();
+        for _ in 0..2 {
+            app.update();
+        }
+
+        assert!(
+            app.world.get_entity(splat).is_none(),
+            "the splat wasn't cleaned"
+        );
+    }
+}
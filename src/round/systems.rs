@@ -3,13 +3,16 @@ use bevy_ggrs::SessionType;
 use ggrs::{P2PSession, PlayerHandle};
 
 use crate::{
-    menu::connect::LocalHandles, AttackerAssets, DefenderAssets, FontAssets, GGRSConfig,
-    MiscAssets, BUTTON_TEXT, NUM_PLAYERS, SCREEN_X, SCREEN_Y,
+    desync::{DesyncDiagnostics, PeerChecksums, RollbackFrame},
+    menu::connect::LocalHandles,
+    physics::prelude::*,
+    AttackerAssets, DefenderAssets, FontAssets, GGRSConfig, MiscAssets, BUTTON_TEXT, FPS,
+    NUM_PLAYERS, SCREEN_X, SCREEN_Y,
 };
 
 use super::{
     prelude::*, FRAMES_PER_SPRITE, GROUND_LEVEL, INPUT_ACT, INPUT_DOWN, INPUT_LEFT, INPUT_RIGHT,
-    INPUT_UP, ROUND_LENGTH,
+    INPUT_UP, JUMP_HEIGHT, JUMP_TIME_TO_PEAK, PHYSICS_SUBSTEPS, PIXELS_PER_METER, ROUND_LENGTH,
 };
 
 pub fn input(
@@ -98,10 +101,26 @@ pub fn on_round_end(state: Res<RoundState>) -> ShouldRun {
     }
 }
 
+/// The physics settings of a match.
+/// Online, the peers compare them before the match starts and refuse to play if they differ.
+pub fn physics_config() -> PhysicsConfig {
+    // For real-world gravity, we would just keep the default of with_unit_scale.
+    // Bodies can scale it with GravityScale.
+    let grav = (-2. * JUMP_HEIGHT) / JUMP_TIME_TO_PEAK; // derived as suggested in: https://www.youtube.com/watch?v=hG9SzQxaCm8
+    PhysicsConfig {
+        gravity: vector(Vec2::new(0., grav * PIXELS_PER_METER)),
+        timestep: scalar(1. / FPS as f32),
+        substeps: PHYSICS_SUBSTEPS,
+        ..PhysicsConfig::with_unit_scale(scalar(PIXELS_PER_METER))
+    }
+}
+
 pub fn setup_game(mut commands: Commands, misc_sprites: Res<MiscAssets>) {
     commands.insert_resource(RoundState::InterludeStart);
     commands.insert_resource(FrameCount::default());
+    commands.insert_resource(RollbackFrame::default());
     commands.insert_resource(RoundData::default());
+    commands.insert_resource(physics_config());
     let mut cam = OrthographicCameraBundle::new_2d();
     cam.orthographic_projection.scale = 1. / 2.; // Asset pixels are 2 times bigger than "device points"
     commands.spawn_bundle(cam).insert(GameEntity);
@@ -159,6 +178,10 @@ pub fn handle_p2p_events(
 ) {
     for event in session.events() {
         info!("GGRS Event: {:?}", event);
+        // a desync stays on screen, the game won't recover from it
+        if let ConnectionStatus::Desynced = con_info.status {
+            continue;
+        }
         match event {
             ggrs::GGRSEvent::Synchronized { .. } => con_info.status = ConnectionStatus::Running,
             ggrs::GGRSEvent::Disconnected { .. } => {
@@ -213,6 +236,9 @@ pub fn update_screen_timer(
 pub fn cleanup_game(query: Query<Entity, With<GameEntity>>, mut commands: Commands) {
     commands.remove_resource::<RoundData>();
     commands.remove_resource::<FrameCount>();
+    commands.remove_resource::<RollbackFrame>();
+    commands.remove_resource::<DesyncDiagnostics>();
+    commands.remove_resource::<PeerChecksums>();
     commands.remove_resource::<LocalHandles>();
     commands.remove_resource::<P2PSession<GGRSConfig>>();
     commands.remove_resource::<SessionType>();
@@ -0,0 +1,294 @@
+//! The rollback schedule and the types it rolls back, shared by the game and the sync test harness
+
+use bevy::prelude::*;
+use bevy_ggrs::GGRSPlugin;
+
+use crate::{
+    checksum::{checksum_world, Checksum, RollbackTypes},
+    desync::{advance_rollback_frame, record_frame_state, RollbackFrame},
+    physics::prelude::*,
+    round::prelude::*,
+    GGRSConfig,
+};
+
+const ROLLBACK_SYSTEMS: &str = "rollback_systems";
+const CHECKSUM_UPDATE: &str = "checksum_update";
+const CHECKSUM_WORLD: &str = "checksum_world";
+
+#[derive(SystemLabel, Debug, Clone, Hash, Eq, PartialEq)]
+enum SystemLabel {
+    UpdateState,
+    Input,
+    Move,
+    End,
+}
+
+/// Everything that runs once per (re-)simulated frame
+pub fn rollback_schedule() -> Schedule {
+    Schedule::default()
+        // adding physics in a separate stage for now,
+        // could perhaps merge with the stage below for increased parallelism...
+        // but this is a web jam game, so we don't *really* care about that now...
+        .with_physics_stage()
+        .with_stage_after(
+            PhysicsUpdateStage,
+            ROLLBACK_SYSTEMS,
+            SystemStage::parallel()
+                // interlude start
+                .with_system_set(
+                    SystemSet::new()
+                        .with_run_criteria(on_interlude_start)
+                        .with_system(setup_interlude),
+                )
+                // interlude
+                .with_system_set(
+                    SystemSet::new()
+                        .with_run_criteria(on_interlude)
+                        .with_system(run_interlude),
+                )
+                // interlude end
+                .with_system_set(
+                    SystemSet::new()
+                        .with_run_criteria(on_interlude_end)
+                        .with_system(cleanup_interlude),
+                )
+                // round start
+                .with_system_set(
+                    SystemSet::new()
+                        .with_run_criteria(on_round_start)
+                        .with_system(spawn_attackers)
+                        .with_system(spawn_defender)
+                        .with_system(spawn_world)
+                        .with_system(start_round),
+                )
+                // round
+                .with_system_set(
+                    SystemSet::new()
+                        .with_run_criteria(on_round)
+                        .with_system(update_attacker_state)
+                        .with_system(update_defender_state)
+                        .label(SystemLabel::UpdateState),
+                )
+                .with_system_set(
+                    SystemSet::new()
+                        .with_run_criteria(on_round)
+                        .with_system(apply_attacker_inputs)
+                        .with_system(apply_defender_inputs)
+                        .label(SystemLabel::Input)
+                        .after(SystemLabel::UpdateState),
+                )
+                .with_system_set(
+                    SystemSet::new()
+                        .with_run_criteria(on_round)
+                        .with_system(move_attackers)
+                        .with_system(move_crosshair)
+                        .with_system(cake_collision)
+                        .with_system(splat_cleaning)
+                        .label(SystemLabel::Move)
+                        .after(SystemLabel::Input),
+                )
+                .with_system_set(
+                    SystemSet::new()
+                        .with_run_criteria(on_round)
+                        .with_system(check_round_end)
+                        .label(SystemLabel::End)
+                        .after(SystemLabel::Move),
+                )
+                // round end
+                .with_system_set(
+                    SystemSet::new()
+                        .after(SystemLabel::End)
+                        .with_run_criteria(on_round_end)
+                        .with_system(cleanup_round),
+                ),
+        )
+        .with_stage_after(
+            ROLLBACK_SYSTEMS,
+            CHECKSUM_UPDATE,
+            SystemStage::parallel()
+                .with_system(advance_rollback_frame)
+                .with_system(
+                    checksum_world
+                        .exclusive_system()
+                        .at_end()
+                        .label(CHECKSUM_WORLD),
+                )
+                .with_system(
+                    record_frame_state
+                        .exclusive_system()
+                        .at_end()
+                        .after(CHECKSUM_WORLD),
+                ),
+        )
+}
+
+/// Registers every type that is rolled back.
+/// Every one of them is checksummed, unless it opts out with `#[reflect(SkipChecksum)]`.
+pub fn rollback_types(ggrs_plugin: GGRSPlugin<GGRSConfig>) -> RollbackTypes<GGRSConfig> {
+    RollbackTypes::new(ggrs_plugin)
+        .register_rollback_type::<Attacker>()
+        .register_rollback_type::<Defender>()
+        .register_rollback_type::<RoundEntity>()
+        .register_rollback_type::<AttackerState>()
+        .register_rollback_type::<DefenderState>()
+        .register_rollback_type::<AttackerControls>()
+        .register_rollback_type::<DefenderControls>()
+        .register_rollback_type::<FrameCount>()
+        .register_rollback_type::<Checksum>()
+        .register_rollback_type::<RollbackFrame>()
+        .register_rollback_type::<RoundState>()
+        .register_rollback_type::<RoundData>()
+        .register_rollback_type::<Transform>()
+        .register_rollback_type::<FacingDirection>()
+        .register_rollback_type::<Cake>()
+        .register_rollback_type::<Splat>()
+        .register_rollback_type::<Crosshair>()
+        .register_rollback_type::<ScreenTimer>()
+        .register_physics_types()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{hash_map::Entry, HashMap};
+
+    use bevy_ggrs::SessionType;
+    use ggrs::PlayerHandle;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+    use crate::{
+        desync::DesyncDiagnostics, menu::connect::LocalHandles, menu::main::synctest_session,
+        round::resources::Input, AppState, AttackerAssets, DefenderAssets, FontAssets, MiscAssets,
+        FPS, MAX_PREDICTION, NUM_PLAYERS,
+    };
+
+    const SEED: u64 = 42;
+    /// As far back as a real match can roll back, so state that only breaks on long rollbacks shows up
+    const CHECK_DISTANCE: usize = MAX_PREDICTION;
+    /// Way more than the two rounds and their interludes take
+    const MAX_FRAMES: u32 = 10_000;
+
+    /// Presses random buttons for every player
+    fn random_input(_handle: In<PlayerHandle>, mut rng: Local<Option<ChaCha8Rng>>) -> Input {
+        let rng = rng.get_or_insert_with(|| ChaCha8Rng::seed_from_u64(SEED));
+        // one bit per button
+        Input {
+            inp: rng.gen_range(0..32),
+        }
+    }
+
+    /// The checksum of every frame the first time it was simulated, to compare resimulations against.
+    /// GGRS only logs sync test mismatches, so the test keeps track of them itself.
+    #[derive(Default)]
+    struct SimulatedChecksums {
+        first: HashMap<u32, u64>,
+        resimulated: usize,
+        mismatches: Vec<u32>,
+    }
+
+    fn compare_checksums(
+        frame: Res<RollbackFrame>,
+        checksum: Res<Checksum>,
+        mut simulated: ResMut<SimulatedChecksums>,
+    ) {
+        let simulated = &mut *simulated;
+        match simulated.first.entry(frame.frame) {
+            Entry::Vacant(entry) => {
+                entry.insert(checksum.value);
+            }
+            Entry::Occupied(entry) => {
+                let first = *entry.get();
+                simulated.resimulated += 1;
+                if first != checksum.value {
+                    simulated.mismatches.push(frame.frame);
+                }
+            }
+        }
+    }
+
+    /// Plays a whole local match with random inputs in a sync test, with no window or renderer
+    #[test]
+    fn synctest_random_match() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_plugin(PhysicsPlugin::default())
+            .add_state(AppState::RoundLocal);
+
+        let ggrs_plugin = GGRSPlugin::<GGRSConfig>::new()
+            // there's no point in waiting for real time here
+            .with_update_frequency(100 * FPS)
+            .with_input_system(random_input)
+            .with_rollback_schedule(
+                rollback_schedule().with_system_in_stage(
+                    CHECKSUM_UPDATE,
+                    compare_checksums
+                        .exclusive_system()
+                        .at_end()
+                        .after(CHECKSUM_WORLD),
+                ),
+            );
+        rollback_types(ggrs_plugin).build(&mut app);
+
+        // what the menu and `setup_game` would set up, minus the graphics
+        app.insert_resource(synctest_session(CHECK_DISTANCE))
+            .insert_resource(SessionType::SyncTestSession)
+            .insert_resource(LocalHandles {
+                handles: (0..NUM_PLAYERS).collect(),
+            })
+            // dumps the frames behind a failure
+            .insert_resource(DesyncDiagnostics::sync_test())
+            .init_resource::<SimulatedChecksums>()
+            .insert_resource(RoundState::InterludeStart)
+            .insert_resource(FrameCount::default())
+            .insert_resource(RollbackFrame::default())
+            .insert_resource(RoundData::default())
+            .insert_resource(physics_config())
+            .insert_resource(MiscAssets {
+                game_title: Default::default(),
+                background: Default::default(),
+                cake: Default::default(),
+                splat1: Default::default(),
+                splat2: Default::default(),
+                crosshair: Default::default(),
+            })
+            .insert_resource(FontAssets {
+                default_font: Default::default(),
+            })
+            .insert_resource(AttackerAssets {
+                janitor_idle: Default::default(),
+                janitor_walk: Default::default(),
+                janitor_fall: Default::default(),
+                janitor_jump: Default::default(),
+                janitor_land: Default::default(),
+                janitor_hit: Default::default(),
+            })
+            .insert_resource(DefenderAssets {
+                fortress_idle: Default::default(),
+                fortress_fire: Default::default(),
+            });
+
+        // the last round ends by going to the win screen
+        while app
+            .world
+            .get_resource::<State<AppState>>()
+            .unwrap()
+            .current()
+            != &AppState::Win
+        {
+            app.update();
+
+            let frame = app.world.get_resource::<RollbackFrame>().unwrap().frame;
+            assert!(frame < MAX_FRAMES, "the match did not end");
+            let simulated = app.world.get_resource::<SimulatedChecksums>().unwrap();
+            assert!(
+                simulated.mismatches.is_empty(),
+                "desync in frames {:?}",
+                simulated.mismatches
+            );
+        }
+
+        let simulated = app.world.get_resource::<SimulatedChecksums>().unwrap();
+        assert!(simulated.resimulated > 0, "no frame was resimulated");
+    }
+}